@@ -0,0 +1,47 @@
+-- tables of the entities in src/entity.rs
+-- IF NOT EXISTS keeps databases set up by hand before migrations existed working
+
+CREATE TABLE IF NOT EXISTS "user" (
+    id BIGSERIAL PRIMARY KEY,
+    username TEXT NOT NULL,
+    password TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS user_msg (
+    id BIGSERIAL PRIMARY KEY,
+    from_id BIGINT NOT NULL,
+    to_id BIGINT NOT NULL,
+    date_time TIMESTAMPTZ NOT NULL,
+    content TEXT
+);
+
+CREATE TABLE IF NOT EXISTS "group" (
+    id BIGSERIAL PRIMARY KEY,
+    group_name TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS role (
+    id BIGSERIAL PRIMARY KEY,
+    role_name TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS group_member (
+    id BIGSERIAL PRIMARY KEY,
+    group_id BIGINT NOT NULL,
+    user_id BIGINT NOT NULL,
+    role_id BIGINT
+);
+
+CREATE TABLE IF NOT EXISTS group_msg (
+    id BIGSERIAL PRIMARY KEY,
+    group_id BIGINT NOT NULL,
+    user_id BIGINT NOT NULL,
+    date_time TIMESTAMPTZ NOT NULL,
+    content TEXT
+);
+
+CREATE TABLE IF NOT EXISTS session (
+    user_id BIGINT PRIMARY KEY,
+    address TEXT,
+    login_time TIMESTAMPTZ
+);
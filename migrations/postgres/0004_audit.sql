@@ -0,0 +1,15 @@
+-- audit log of security-relevant events, apart from the chat tables and only ever appended to
+
+CREATE TABLE IF NOT EXISTS audit_event (
+    id BIGSERIAL PRIMARY KEY,
+    date_time TIMESTAMPTZ NOT NULL,
+    kind TEXT NOT NULL,
+    actor_id BIGINT,
+    target_id BIGINT,
+    address TEXT,
+    detail TEXT
+);
+
+CREATE INDEX IF NOT EXISTS audit_event_date_time ON audit_event (date_time);
+
+CREATE INDEX IF NOT EXISTS audit_event_actor ON audit_event (actor_id, date_time);
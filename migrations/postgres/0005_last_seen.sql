@@ -0,0 +1,6 @@
+-- when users were last seen online, one row per user replaced on every logout
+
+CREATE TABLE IF NOT EXISTS last_seen (
+    user_id BIGINT PRIMARY KEY,
+    date_time TIMESTAMPTZ NOT NULL
+);
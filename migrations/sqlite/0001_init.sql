@@ -0,0 +1,47 @@
+-- tables of the entities in src/entity.rs
+-- IF NOT EXISTS keeps databases set up by hand before migrations existed working
+
+CREATE TABLE IF NOT EXISTS "user" (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    username TEXT NOT NULL,
+    password TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS user_msg (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    from_id INTEGER NOT NULL,
+    to_id INTEGER NOT NULL,
+    date_time TEXT NOT NULL,
+    content TEXT
+);
+
+CREATE TABLE IF NOT EXISTS "group" (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    group_name TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS role (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    role_name TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS group_member (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    group_id INTEGER NOT NULL,
+    user_id INTEGER NOT NULL,
+    role_id INTEGER
+);
+
+CREATE TABLE IF NOT EXISTS group_msg (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    group_id INTEGER NOT NULL,
+    user_id INTEGER NOT NULL,
+    date_time TEXT NOT NULL,
+    content TEXT
+);
+
+CREATE TABLE IF NOT EXISTS session (
+    user_id INTEGER PRIMARY KEY,
+    address TEXT,
+    login_time TEXT
+);
@@ -0,0 +1,6 @@
+-- whether a user message reached its recipient, NULL for messages stored before
+-- offline delivery existed, which count as delivered
+
+ALTER TABLE user_msg ADD COLUMN delivered BOOLEAN;
+
+CREATE INDEX IF NOT EXISTS user_msg_undelivered ON user_msg (to_id) WHERE delivered = 0;
@@ -0,0 +1,3 @@
+-- whether a user is refused at login, NULL for users created before, who are enabled
+
+ALTER TABLE "user" ADD COLUMN disabled BOOLEAN;
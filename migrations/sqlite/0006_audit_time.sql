@@ -0,0 +1,12 @@
+-- times of the audit log are compared as text, so they are stored with all nine
+-- fractional digits, e.g. 2021-06-01T12:00:00.500000000Z. earlier events were stored
+-- with as few digits as needed, e.g. 2021-06-01T12:00:00Z or 2021-06-01T12:00:00.5Z.
+-- PostgreSQL stores them as timestamptz and needs no change
+
+UPDATE audit_event
+SET date_time = substr(date_time, 1, 19) || '.' || substr(
+    CASE
+        WHEN substr(date_time, 20, 1) = '.' THEN substr(date_time, 21, length(date_time) - 21)
+        ELSE ''
+    END || '000000000', 1, 9) || 'Z'
+WHERE length(date_time) <> 30
@@ -0,0 +1,781 @@
+//! admin HTTP API of a clush server, on an address of its own
+//!
+//! every request carries `Authorization: Bearer <token>` with the token of `adminConfig`,
+//! bodies are JSON objects with camelCase fields
+//!
+//! - `GET /users`, `POST /users` with `username`, `password` and an optional `id`
+//! - `GET /users/{id}`, `DELETE /users/{id}`
+//! - `POST /users/{id}/disable`, `POST /users/{id}/enable`
+//! - `PUT /users/{id}/password` with `password`
+//! - `GET /groups`, `POST /groups` with `groupName` and an optional `id`
+//! - `GET /groups/{id}`, `DELETE /groups/{id}`
+//! - `GET /groups/{id}/members`, `POST /groups/{id}/members` with `userId` and an optional `roleId`
+//! - `DELETE /groups/{id}/members/{userId}`
+//! - `GET /sessions`, `DELETE /sessions/{userId}` to disconnect a user
+//! - `POST /broadcasts` with `content` and an optional `groupId`
+//! - `GET /audit` with the optional query parameters `from` and `to` in RFC 3339,
+//!   `actorId`, `kind` and `limit`
+//!
+//! passwords are given the way they are stored, as the hex of the hash clients send.
+//! disabling or removing a user disconnects them.
+//! refused requests and the ones which may change something are recorded in the audit log
+
+use crate::audit::{self, AuditKind, AuditQuery};
+use crate::codec::{ClushFrame, MessageType};
+use crate::entity::{AuditEvent, Group, GroupMember, Session, User};
+use crate::outbound::Offer;
+use crate::reload::Settings;
+use crate::server::{ConnectionMap, OutboundMap};
+use crate::storage::{Storage, StorageError};
+use bytes::BytesMut;
+use chrono::{DateTime, Utc};
+use hyper::body::HttpBody;
+use hyper::header::{AUTHORIZATION, CONTENT_TYPE, WWW_AUTHENTICATE};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::watch;
+
+/// largest request body accepted
+static MAX_BODY_SIZE: usize = 64 * 1024;
+
+/// what the API manages
+pub(crate) struct Admin {
+    pub(crate) db: Arc<dyn Storage>,
+    pub(crate) map: Arc<OutboundMap>,
+    /// queues of every connection of the online users
+    pub(crate) connections: Arc<ConnectionMap>,
+    /// settings of the server, the token is read from them on every request
+    pub(crate) settings: watch::Receiver<Arc<Settings>>,
+}
+
+/// an error answered with its status, and a JSON object with the message as body
+#[derive(Debug)]
+struct ApiError {
+    status: StatusCode,
+    message: String,
+}
+
+impl ApiError {
+    fn new<S: Into<String>>(status: StatusCode, message: S) -> ApiError {
+        ApiError {
+            status,
+            message: message.into(),
+        }
+    }
+
+    fn not_found<S: Into<String>>(message: S) -> ApiError {
+        ApiError::new(StatusCode::NOT_FOUND, message)
+    }
+
+    fn bad_request<S: Into<String>>(message: S) -> ApiError {
+        ApiError::new(StatusCode::BAD_REQUEST, message)
+    }
+
+    fn into_response(self) -> Response<Body> {
+        let mut response = json(self.status, &serde_json::json!({ "error": self.message }));
+        if self.status == StatusCode::UNAUTHORIZED {
+            let challenge = "Bearer".parse().unwrap();
+            response.headers_mut().insert(WWW_AUTHENTICATE, challenge);
+        }
+
+        response
+    }
+}
+
+impl From<StorageError> for ApiError {
+    fn from(e: StorageError) -> Self {
+        tracing::error!("admin request failed: {}", e);
+
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "storage error")
+    }
+}
+
+type ApiResult = Result<Response<Body>, ApiError>;
+
+/// a user as answered
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct UserView {
+    id: u64,
+    username: String,
+    disabled: bool,
+    online: bool,
+}
+
+/// a group as answered
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GroupView {
+    id: u64,
+    group_name: String,
+}
+
+/// a member of a group as answered
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct MemberView {
+    user_id: u64,
+    role_id: Option<u64>,
+}
+
+/// an online session as answered
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SessionView {
+    user_id: u64,
+    address: Option<String>,
+    login_time: Option<DateTime<Utc>>,
+}
+
+/// an event of the audit log as answered
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct AuditView {
+    id: u64,
+    date_time: Option<DateTime<Utc>>,
+    kind: String,
+    actor_id: Option<u64>,
+    target_id: Option<u64>,
+    address: Option<String>,
+    detail: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct NewUser {
+    id: Option<u64>,
+    username: String,
+    password: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct NewPassword {
+    password: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct NewGroup {
+    id: Option<u64>,
+    group_name: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct NewMember {
+    user_id: u64,
+    role_id: Option<u64>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Broadcast {
+    content: String,
+    /// every online user if None
+    group_id: Option<u64>,
+}
+
+/// answer a request to the API
+pub(crate) async fn handle(admin: Arc<Admin>, request: Request<Body>) -> Response<Body> {
+    let method = request.method().clone();
+    let path = request.uri().path().to_string();
+    let peer = request.extensions().get::<SocketAddr>().copied();
+
+    let authorized = admin.authorize(&request);
+    let refused = authorized.is_err();
+    let result = match authorized {
+        Ok(()) => admin.route(request).await,
+        Err(e) => Err(e),
+    };
+    let response = result.unwrap_or_else(ApiError::into_response);
+
+    // whoever was refused is unknown
+    let event = if refused {
+        AuditEvent {
+            actor_id: None,
+            ..AuditEvent::new(AuditKind::AdminUnauthorized, 0)
+        }
+    } else if method != Method::GET {
+        AuditEvent::new(AuditKind::AdminAction, 0)
+    } else {
+        return response;
+    };
+    let detail = format!("{} {} {}", method, path, response.status().as_u16());
+    let event = match peer {
+        Some(peer) => event.detail(detail).address(peer),
+        None => event.detail(detail),
+    };
+    audit::record(&*admin.db, event).await;
+
+    response
+}
+
+impl Admin {
+    /// check the bearer token of a request
+    fn authorize(&self, request: &Request<Body>) -> Result<(), ApiError> {
+        let token = self.settings.borrow().admin_token.clone();
+        let given = request
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+
+        match given {
+            Some(given) if !token.is_empty() && same(given.as_bytes(), token.as_bytes()) => Ok(()),
+            _ => Err(ApiError::new(
+                StatusCode::UNAUTHORIZED,
+                "missing or invalid token",
+            )),
+        }
+    }
+
+    /// pass a request to the operation of its method and path
+    async fn route(&self, request: Request<Body>) -> ApiResult {
+        let method = request.method().clone();
+        let path = request.uri().path().to_string();
+        let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+
+        match (&method, segments.as_slice()) {
+            (&Method::GET, ["users"]) => self.list_users().await,
+            (&Method::POST, ["users"]) => self.create_user(body(request).await?).await,
+            (&Method::GET, ["users", id]) => self.get_user(parse_id(id)?).await,
+            (&Method::DELETE, ["users", id]) => self.delete_user(parse_id(id)?).await,
+            (&Method::POST, ["users", id, "disable"]) => {
+                self.set_disabled(parse_id(id)?, true).await
+            }
+            (&Method::POST, ["users", id, "enable"]) => {
+                self.set_disabled(parse_id(id)?, false).await
+            }
+            (&Method::PUT, ["users", id, "password"]) => {
+                let id = parse_id(id)?;
+                self.reset_password(id, body(request).await?).await
+            }
+            (&Method::GET, ["groups"]) => self.list_groups().await,
+            (&Method::POST, ["groups"]) => self.create_group(body(request).await?).await,
+            (&Method::GET, ["groups", id]) => self.get_group(parse_id(id)?).await,
+            (&Method::DELETE, ["groups", id]) => self.delete_group(parse_id(id)?).await,
+            (&Method::GET, ["groups", id, "members"]) => self.list_members(parse_id(id)?).await,
+            (&Method::POST, ["groups", id, "members"]) => {
+                let id = parse_id(id)?;
+                self.add_member(id, body(request).await?).await
+            }
+            (&Method::DELETE, ["groups", id, "members", user_id]) => {
+                self.remove_member(parse_id(id)?, parse_id(user_id)?).await
+            }
+            (&Method::GET, ["sessions"]) => self.list_sessions().await,
+            (&Method::DELETE, ["sessions", user_id]) => {
+                let user_id = parse_id(user_id)?;
+                match self.disconnect(user_id, "disconnected by an administrator") {
+                    true => Ok(no_content()),
+                    false => Err(ApiError::not_found(format!(
+                        "user {} is not online",
+                        user_id
+                    ))),
+                }
+            }
+            (&Method::POST, ["broadcasts"]) => self.broadcast(body(request).await?).await,
+            (&Method::GET, ["audit"]) => {
+                let query = audit_query(request.uri().query().unwrap_or_default())?;
+                self.list_audit(query).await
+            }
+            _ => Err(ApiError::not_found("no such endpoint")),
+        }
+    }
+
+    async fn list_users(&self) -> ApiResult {
+        let users = self.db.fetch_users().await?;
+        let users: Vec<UserView> = users.into_iter().map(|user| self.user_view(user)).collect();
+
+        Ok(json(StatusCode::OK, &users))
+    }
+
+    async fn get_user(&self, id: u64) -> ApiResult {
+        match self.db.fetch_user(id).await? {
+            Some(user) => Ok(json(StatusCode::OK, &self.user_view(user))),
+            None => Err(no_user(id)),
+        }
+    }
+
+    async fn create_user(&self, new_user: NewUser) -> ApiResult {
+        if new_user.username.is_empty() {
+            return Err(ApiError::bad_request("username must not be empty"));
+        }
+        check_password(&new_user.password)?;
+        if let Some(id) = new_user.id {
+            if self.db.fetch_user(id).await?.is_some() {
+                let message = format!("user {} exists", id);
+                return Err(ApiError::new(StatusCode::CONFLICT, message));
+            }
+        }
+
+        let user = User {
+            id: new_user.id,
+            username: Some(new_user.username),
+            password: Some(new_user.password),
+            disabled: Some(false),
+        };
+        let id = self.db.save_user(&user).await?;
+        let user = User {
+            id: Some(id),
+            ..user
+        };
+
+        Ok(json(StatusCode::CREATED, &self.user_view(user)))
+    }
+
+    async fn delete_user(&self, id: u64) -> ApiResult {
+        if !self.db.remove_user(id).await? {
+            return Err(no_user(id));
+        }
+        self.disconnect(id, "user removed");
+
+        Ok(no_content())
+    }
+
+    async fn set_disabled(&self, id: u64, disabled: bool) -> ApiResult {
+        let user = User {
+            id: Some(id),
+            username: None,
+            password: None,
+            disabled: Some(disabled),
+        };
+        if !self.db.update_user(&user).await? {
+            return Err(no_user(id));
+        }
+        if disabled {
+            self.disconnect(id, "user disabled");
+        }
+
+        Ok(no_content())
+    }
+
+    async fn reset_password(&self, id: u64, new_password: NewPassword) -> ApiResult {
+        check_password(&new_password.password)?;
+        let user = User {
+            id: Some(id),
+            username: None,
+            password: Some(new_password.password),
+            disabled: None,
+        };
+        if !self.db.update_user(&user).await? {
+            return Err(no_user(id));
+        }
+        let event = AuditEvent::new(AuditKind::PasswordChanged, 0).target(id);
+        audit::record(&*self.db, event).await;
+
+        Ok(no_content())
+    }
+
+    async fn list_groups(&self) -> ApiResult {
+        let groups = self.db.fetch_groups().await?;
+        let groups: Vec<GroupView> = groups.into_iter().map(group_view).collect();
+
+        Ok(json(StatusCode::OK, &groups))
+    }
+
+    async fn get_group(&self, id: u64) -> ApiResult {
+        match self.db.fetch_group(id).await? {
+            Some(group) => Ok(json(StatusCode::OK, &group_view(group))),
+            None => Err(no_group(id)),
+        }
+    }
+
+    async fn create_group(&self, new_group: NewGroup) -> ApiResult {
+        if new_group.group_name.is_empty() {
+            return Err(ApiError::bad_request("groupName must not be empty"));
+        }
+        if let Some(id) = new_group.id {
+            if self.db.fetch_group(id).await?.is_some() {
+                let message = format!("group {} exists", id);
+                return Err(ApiError::new(StatusCode::CONFLICT, message));
+            }
+        }
+
+        let group = Group {
+            id: new_group.id,
+            group_name: Some(new_group.group_name),
+        };
+        let id = self.db.save_group(&group).await?;
+        let group = Group {
+            id: Some(id),
+            ..group
+        };
+
+        Ok(json(StatusCode::CREATED, &group_view(group)))
+    }
+
+    async fn delete_group(&self, id: u64) -> ApiResult {
+        match self.db.remove_group(id).await? {
+            true => Ok(no_content()),
+            false => Err(no_group(id)),
+        }
+    }
+
+    async fn list_members(&self, group_id: u64) -> ApiResult {
+        if self.db.fetch_group(group_id).await?.is_none() {
+            return Err(no_group(group_id));
+        }
+
+        let members = self.db.fetch_members(group_id).await?;
+        let mut members: Vec<MemberView> = members
+            .into_iter()
+            .filter_map(|member| {
+                Some(MemberView {
+                    user_id: member.user_id?,
+                    role_id: member.role_id,
+                })
+            })
+            .collect();
+        members.sort_by_key(|member| member.user_id);
+
+        Ok(json(StatusCode::OK, &members))
+    }
+
+    async fn add_member(&self, group_id: u64, new_member: NewMember) -> ApiResult {
+        let user_id = new_member.user_id;
+        if self.db.fetch_group(group_id).await?.is_none() {
+            return Err(no_group(group_id));
+        }
+        if self.db.fetch_user(user_id).await?.is_none() {
+            return Err(no_user(user_id));
+        }
+        if let Some(role_id) = new_member.role_id {
+            if self.db.fetch_role(role_id).await?.is_none() {
+                return Err(ApiError::not_found(format!("no role {}", role_id)));
+            }
+        }
+        let members = self.db.fetch_members(group_id).await?;
+        if members.iter().any(|member| member.user_id == Some(user_id)) {
+            let message = format!("user {} is a member of group {}", user_id, group_id);
+            return Err(ApiError::new(StatusCode::CONFLICT, message));
+        }
+
+        let member = GroupMember {
+            id: None,
+            group_id: Some(group_id),
+            user_id: Some(user_id),
+            role_id: new_member.role_id,
+        };
+        self.db.save_member(&member).await?;
+        let detail = match new_member.role_id {
+            Some(role_id) => format!("user {} with role {}", user_id, role_id),
+            None => format!("user {}", user_id),
+        };
+        let event = AuditEvent::new(AuditKind::MemberAdded, 0)
+            .target(group_id)
+            .detail(detail);
+        audit::record(&*self.db, event).await;
+        let member = MemberView {
+            user_id,
+            role_id: new_member.role_id,
+        };
+
+        Ok(json(StatusCode::CREATED, &member))
+    }
+
+    async fn remove_member(&self, group_id: u64, user_id: u64) -> ApiResult {
+        if !self.db.remove_member(group_id, user_id).await? {
+            return Err(ApiError::not_found(format!(
+                "user {} is not a member of group {}",
+                user_id, group_id
+            )));
+        }
+        let event = AuditEvent::new(AuditKind::MemberRemoved, 0)
+            .target(group_id)
+            .detail(format!("user {}", user_id));
+        audit::record(&*self.db, event).await;
+
+        Ok(no_content())
+    }
+
+    async fn list_sessions(&self) -> ApiResult {
+        let sessions = self.db.fetch_sessions().await?;
+        let mut sessions: Vec<SessionView> =
+            sessions.into_iter().filter_map(session_view).collect();
+        sessions.sort_by_key(|session| session.user_id);
+
+        Ok(json(StatusCode::OK, &sessions))
+    }
+
+    async fn list_audit(&self, query: AuditQuery) -> ApiResult {
+        let events = self.db.fetch_audit(&query).await?;
+        let events: Vec<AuditView> = events.into_iter().filter_map(audit_view).collect();
+
+        Ok(json(StatusCode::OK, &events))
+    }
+
+    /// send a message from the server to every online user, or to the online members of a group,
+    /// answer how many got it
+    async fn broadcast(&self, broadcast: Broadcast) -> ApiResult {
+        if broadcast.content.is_empty() {
+            return Err(ApiError::bad_request("content must not be empty"));
+        }
+        let limits = self.settings.borrow().frame_limits;
+        let size = broadcast.content.len() as u64;
+        if let Err(e) = limits.check(&MessageType::UserMessage, size) {
+            return Err(ApiError::bad_request(e.to_string()));
+        }
+
+        let user_ids: Vec<u64> = match broadcast.group_id {
+            Some(group_id) => {
+                if self.db.fetch_group(group_id).await?.is_none() {
+                    return Err(no_group(group_id));
+                }
+                let members = self.db.fetch_members(group_id).await?;
+                members.iter().filter_map(|member| member.user_id).collect()
+            }
+            None => self.map.iter().map(|pair| *pair.key()).collect(),
+        };
+
+        let mut recipients = 0;
+        for user_id in user_ids {
+            let outbound = match self.map.get(&user_id) {
+                Some(outbound) => outbound.clone(),
+                None => continue,
+            };
+            let frame = server_frame(user_id, &broadcast.content);
+            if let Offer::Queued = outbound.offer(frame) {
+                recipients += 1;
+            }
+        }
+
+        let body = serde_json::json!({ "recipients": recipients });
+        Ok(json(StatusCode::OK, &body))
+    }
+
+    /// tell an online user why, then close all their connections, return whether they were online
+    fn disconnect(&self, user_id: u64, reason: &'static str) -> bool {
+        let queues = match self.connections.get(&user_id) {
+            Some(queues) => queues.clone(),
+            None => return false,
+        };
+        for outbound in &queues {
+            outbound.offer(server_frame(user_id, reason));
+            outbound.close(reason);
+        }
+
+        !queues.is_empty()
+    }
+
+    fn user_view(&self, user: User) -> UserView {
+        let id = user.id.unwrap_or_default();
+
+        UserView {
+            id,
+            username: user.username.unwrap_or_default(),
+            disabled: user.disabled.unwrap_or(false),
+            online: self.connections.contains_key(&id),
+        }
+    }
+}
+
+fn group_view(group: Group) -> GroupView {
+    GroupView {
+        id: group.id.unwrap_or_default(),
+        group_name: group.group_name.unwrap_or_default(),
+    }
+}
+
+fn session_view(session: Session) -> Option<SessionView> {
+    Some(SessionView {
+        user_id: session.user_id?,
+        address: session.address,
+        login_time: session.login_time,
+    })
+}
+
+fn audit_view(event: AuditEvent) -> Option<AuditView> {
+    Some(AuditView {
+        id: event.id?,
+        date_time: event.date_time,
+        kind: event.kind?,
+        actor_id: event.actor_id,
+        target_id: event.target_id,
+        address: event.address,
+        detail: event.detail,
+    })
+}
+
+/// a user message from the server, like the errors sent to clients
+fn server_frame(to_id: u64, content: &str) -> ClushFrame {
+    let mut frame = ClushFrame::new(
+        MessageType::UserMessage,
+        0,
+        to_id,
+        0,
+        BytesMut::from(content),
+    );
+    frame.update_size();
+
+    frame
+}
+
+fn no_user(id: u64) -> ApiError {
+    ApiError::not_found(format!("no user {}", id))
+}
+
+fn no_group(id: u64) -> ApiError {
+    ApiError::not_found(format!("no group {}", id))
+}
+
+/// parse an id in a path
+fn parse_id(segment: &str) -> Result<u64, ApiError> {
+    segment
+        .parse()
+        .map_err(|_| ApiError::bad_request(format!("`{}` is not an id", segment)))
+}
+
+/// parse the query parameters of `GET /audit`
+fn audit_query(query: &str) -> Result<AuditQuery, ApiError> {
+    let mut audit_query = AuditQuery::default();
+    for (key, value) in form_urlencoded::parse(query.as_bytes()) {
+        let invalid =
+            |e: &dyn std::fmt::Display| ApiError::bad_request(format!("invalid `{}`: {}", key, e));
+        match key.as_ref() {
+            "from" | "to" => {
+                let time = DateTime::parse_from_rfc3339(&value)
+                    .map_err(|e| invalid(&e))?
+                    .with_timezone(&Utc);
+                match key.as_ref() {
+                    "from" => audit_query.from = Some(time),
+                    _ => audit_query.to = Some(time),
+                }
+            }
+            "actorId" => audit_query.actor_id = Some(value.parse().map_err(|e| invalid(&e))?),
+            "kind" => audit_query.kind = Some(value.parse().map_err(|e| invalid(&e))?),
+            "limit" => audit_query.limit = Some(value.parse().map_err(|e| invalid(&e))?),
+            _ => {
+                let message = format!("unknown query parameter `{}`", key);
+                return Err(ApiError::bad_request(message));
+            }
+        }
+    }
+
+    Ok(audit_query)
+}
+
+/// check that a password is a hex string, the way it is stored
+fn check_password(password: &str) -> Result<(), ApiError> {
+    let is_hex = password.chars().all(|c| c.is_ascii_hexdigit());
+    if password.is_empty() || !password.len().is_multiple_of(2) || !is_hex {
+        return Err(ApiError::bad_request(
+            "password must be a non-empty hex string of whole bytes",
+        ));
+    }
+
+    Ok(())
+}
+
+/// read a JSON body, up to `MAX_BODY_SIZE` bytes
+async fn body<T: DeserializeOwned>(request: Request<Body>) -> Result<T, ApiError> {
+    let mut body = request.into_body();
+    let mut bytes = vec![];
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|e| ApiError::bad_request(e.to_string()))?;
+        if bytes.len() + chunk.len() > MAX_BODY_SIZE {
+            let message = format!("body exceeds {} bytes", MAX_BODY_SIZE);
+            return Err(ApiError::new(StatusCode::PAYLOAD_TOO_LARGE, message));
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+
+    serde_json::from_slice(&bytes)
+        .map_err(|e| ApiError::bad_request(format!("invalid body: {}", e)))
+}
+
+/// compare two byte strings in a time independent of where they differ
+fn same(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// a JSON response of the given status
+fn json<T: Serialize>(status: StatusCode, value: &T) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(serde_json::to_vec(value).unwrap()))
+        .unwrap()
+}
+
+/// an empty response of an operation which succeeded
+fn no_content() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .body(Body::empty())
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reload::{self, Settings};
+    use crate::storage::MemoryStorage;
+    use crate::ClushConfig;
+
+    fn request(token: Option<&str>, path: &str) -> Request<Body> {
+        let mut builder = Request::builder().uri(path);
+        if let Some(token) = token {
+            builder = builder.header(AUTHORIZATION, format!("Bearer {}", token));
+        }
+
+        builder.body(Body::empty()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn authorize_test() {
+        let mut config = ClushConfig::default();
+        config.admin_config.token = "secret".to_string();
+        let settings = Settings::new(&config).unwrap();
+        let (reload_handle, settings) = reload::channel(config.clone(), settings);
+        let admin = Arc::new(Admin {
+            db: Arc::new(MemoryStorage::new()),
+            map: Arc::new(OutboundMap::new()),
+            connections: Arc::new(ConnectionMap::new()),
+            settings,
+        });
+
+        for token in &[None, Some("wrong"), Some("secre")] {
+            let response = handle(admin.clone(), request(*token, "/users")).await;
+            assert_eq!(StatusCode::UNAUTHORIZED, response.status(), "{:?}", token);
+            assert!(response.headers().contains_key(WWW_AUTHENTICATE));
+        }
+        let response = handle(admin.clone(), request(Some("secret"), "/users")).await;
+        assert_eq!(StatusCode::OK, response.status());
+        let response = handle(admin.clone(), request(Some("secret"), "/missing")).await;
+        assert_eq!(StatusCode::NOT_FOUND, response.status());
+
+        // a reloaded token applies at once
+        config.admin_config.token = "rotated".to_string();
+        reload_handle.reload(config).unwrap();
+        let response = handle(admin.clone(), request(Some("secret"), "/users")).await;
+        assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+        let response = handle(admin, request(Some("rotated"), "/users")).await;
+        assert_eq!(StatusCode::OK, response.status());
+    }
+
+    #[test]
+    fn audit_query_test() {
+        assert_eq!(AuditQuery::default(), audit_query("").unwrap());
+
+        let query = "from=2021-07-01T00:00:00Z&to=2021-07-02T00:00:00%2B08:00\
+                     &actorId=1&kind=loginFailed&limit=10";
+        let query = audit_query(query).unwrap();
+        assert_eq!(
+            "2021-07-01T00:00:00+00:00",
+            query.from.unwrap().to_rfc3339()
+        );
+        assert_eq!("2021-07-01T16:00:00+00:00", query.to.unwrap().to_rfc3339());
+        assert_eq!(Some(1), query.actor_id);
+        assert_eq!(Some(AuditKind::LoginFailed), query.kind);
+        assert_eq!(Some(10), query.limit);
+
+        for query in &["from=yesterday", "actorId=alice", "kind=logout", "user=1"] {
+            let e = audit_query(query).unwrap_err();
+            assert_eq!(StatusCode::BAD_REQUEST, e.status, "{}", query);
+        }
+    }
+}
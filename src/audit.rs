@@ -0,0 +1,198 @@
+//! audit log of security-relevant events, kept apart from the chat tables
+//!
+//! events are only ever appended, and queried by time range, actor and kind.
+//! the actor `0` is the server itself or an administrator,
+//! like the sender of the messages from the server
+
+use crate::entity::AuditEvent;
+use crate::storage::Storage;
+use chrono::{DateTime, Utc};
+use std::fmt;
+use std::str::FromStr;
+
+/// kind of an audited event
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AuditKind {
+    /// a user logged in
+    Login,
+    /// a login was refused, the detail tells why
+    LoginFailed,
+    /// the password of a user was set
+    PasswordChanged,
+    /// a session was ended by the server, e.g. on request of an administrator
+    SessionTerminated,
+    /// a user was added to a group, the detail tells with which role
+    MemberAdded,
+    /// a user was removed from a group
+    MemberRemoved,
+    /// a request to the admin API which changes something was answered
+    AdminAction,
+    /// a request to the admin API was refused for a missing or invalid token
+    AdminUnauthorized,
+    /// a user sent a frame in the name of another one
+    Spoofing,
+}
+
+static KINDS: &[(AuditKind, &str)] = &[
+    (AuditKind::Login, "login"),
+    (AuditKind::LoginFailed, "loginFailed"),
+    (AuditKind::PasswordChanged, "passwordChanged"),
+    (AuditKind::SessionTerminated, "sessionTerminated"),
+    (AuditKind::MemberAdded, "memberAdded"),
+    (AuditKind::MemberRemoved, "memberRemoved"),
+    (AuditKind::AdminAction, "adminAction"),
+    (AuditKind::AdminUnauthorized, "adminUnauthorized"),
+    (AuditKind::Spoofing, "spoofing"),
+];
+
+impl AuditKind {
+    /// name of the kind, as stored
+    pub fn as_str(&self) -> &'static str {
+        KINDS
+            .iter()
+            .find(|(kind, _)| kind == self)
+            .map(|(_, name)| *name)
+            .unwrap()
+    }
+}
+
+impl fmt::Display for AuditKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for AuditKind {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        KINDS
+            .iter()
+            .find(|(_, known)| *known == name)
+            .map(|(kind, _)| *kind)
+            .ok_or_else(|| format!("unknown audit event kind `{}`", name))
+    }
+}
+
+impl AuditEvent {
+    /// create an event of the given kind by the given actor, happening now
+    pub fn new(kind: AuditKind, actor_id: u64) -> AuditEvent {
+        AuditEvent {
+            id: None,
+            date_time: Some(Utc::now()),
+            kind: Some(kind.to_string()),
+            actor_id: Some(actor_id),
+            target_id: None,
+            address: None,
+            detail: None,
+        }
+    }
+
+    /// set the user or group acted on
+    pub fn target(mut self, target_id: u64) -> AuditEvent {
+        self.target_id = Some(target_id);
+        self
+    }
+
+    /// set the address the actor came from
+    pub fn address<A: ToString>(mut self, address: A) -> AuditEvent {
+        self.address = Some(address.to_string());
+        self
+    }
+
+    /// set what else is worth knowing about the event
+    pub fn detail<S: Into<String>>(mut self, detail: S) -> AuditEvent {
+        self.detail = Some(detail.into());
+        self
+    }
+}
+
+/// which events to fetch from the audit log, every field set narrows it down
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AuditQuery {
+    /// events at or after this time
+    pub from: Option<DateTime<Utc>>,
+    /// events before this time
+    pub to: Option<DateTime<Utc>>,
+    pub actor_id: Option<u64>,
+    pub kind: Option<AuditKind>,
+    /// at most this many events, the earliest ones
+    pub limit: Option<u64>,
+}
+
+impl AuditQuery {
+    /// check whether an event is asked for, regardless of the limit
+    pub fn matches(&self, event: &AuditEvent) -> bool {
+        let at = event.date_time;
+        self.from.is_none_or(|from| at.is_some_and(|at| at >= from))
+            && self.to.is_none_or(|to| at.is_some_and(|at| at < to))
+            && self.actor_id.is_none_or(|id| event.actor_id == Some(id))
+            && self
+                .kind
+                .is_none_or(|kind| event.kind.as_deref() == Some(kind.as_str()))
+    }
+}
+
+/// append an event to the audit log of a storage,
+/// a failure is logged and does not fail what is audited
+pub async fn record(db: &dyn Storage, event: AuditEvent) {
+    if let Err(e) = db.append_audit(&event).await {
+        tracing::error!(
+            kind = event.kind.as_deref().unwrap_or_default(),
+            "failed to record an audit event: {}",
+            e
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn kind_test() {
+        for (kind, name) in KINDS {
+            assert_eq!(*name, kind.to_string());
+            assert_eq!(Ok(*kind), name.parse());
+        }
+        assert!("logout".parse::<AuditKind>().is_err());
+    }
+
+    #[test]
+    fn matches_test() {
+        let event = AuditEvent::new(AuditKind::Login, 1).address("127.0.0.1:1");
+        let at = event.date_time.unwrap();
+        let second = chrono::Duration::seconds(1);
+
+        assert!(AuditQuery::default().matches(&event));
+        let query = AuditQuery {
+            from: Some(at),
+            to: Some(at + second),
+            actor_id: Some(1),
+            kind: Some(AuditKind::Login),
+            limit: Some(1),
+        };
+        assert!(query.matches(&event));
+        for query in &[
+            AuditQuery {
+                to: Some(at),
+                ..query.clone()
+            },
+            AuditQuery {
+                from: Some(at + second),
+                to: None,
+                ..query.clone()
+            },
+            AuditQuery {
+                actor_id: Some(2),
+                ..query.clone()
+            },
+            AuditQuery {
+                kind: Some(AuditKind::LoginFailed),
+                ..query.clone()
+            },
+        ] {
+            assert!(!query.matches(&event), "{:?}", query);
+        }
+    }
+}
@@ -0,0 +1,477 @@
+//! # clush-admin
+//!
+//! provisioning and maintenance of clush, working directly on the configured database
+//!
+//! MIT License
+//! Copyright (c) 2021 Bruce Kang
+
+use chrono::{DateTime, Utc};
+use clush_server::audit::{self, AuditKind, AuditQuery};
+use clush_server::config::{ConfigLoader, StorageBackend};
+use clush_server::entity::{AuditEvent, Group, GroupMember, Role, User};
+use clush_server::util::hash_password;
+use clush_server::{storage, ClushConfig, RbatisStorage, Storage};
+use std::io::{self, BufRead};
+
+static USAGE: &str = "usage: clush-admin [options] <command>
+
+options:
+  -c, --config <file>        configuration file, found the way the server does by default
+      --set <key>=<value>    override a field of the configuration
+  -p, --password <password>  password of create-user and reset-password, read from stdin if missing
+      --id <id>              id of the created user, group or role, generated by default
+      --role <id>            role of the member added by add-member
+      --from <time>          audit events at or after this time, in RFC 3339
+      --to <time>            audit events before this time, in RFC 3339
+      --actor <id>           audit events of this actor, 0 for administrators
+      --kind <kind>          audit events of this kind, e.g. loginFailed
+      --limit <n>            at most this many audit events
+
+commands:
+  create-user <username>             create a user, print its id
+  reset-password <user-id>           set the password of a user
+  create-group <name>                create a group, print its id
+  create-role <name>                 create a role, print its id
+  add-member <group-id> <user-id>    add a user to a group
+  migrate                            apply pending database migrations
+  purge-messages <days>              remove messages older than the given days
+  audit                              print events of the audit log, one per line";
+
+/// what to do on the database
+#[derive(Debug, PartialEq)]
+enum Command {
+    CreateUser { username: String },
+    ResetPassword { user_id: u64 },
+    CreateGroup { name: String },
+    CreateRole { name: String },
+    AddMember { group_id: u64, user_id: u64 },
+    Migrate,
+    PurgeMessages { days: u64 },
+    Audit,
+}
+
+/// parsed command line
+struct Options {
+    loader: ConfigLoader,
+    password: Option<String>,
+    id: Option<u64>,
+    role: Option<u64>,
+    /// events printed by `audit`
+    query: AuditQuery,
+    command: Command,
+}
+
+#[tokio::main]
+async fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let options = match parse_args(&args) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            std::process::exit(2);
+        }
+    };
+    let config = match options.loader.clone().load().await {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("invalid configuration: {}", e);
+            std::process::exit(2);
+        }
+    };
+    if let Err(errors) = config.validate() {
+        eprintln!("{}", errors);
+        std::process::exit(2);
+    }
+
+    if let Err(e) = run(options, config).await {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+/// run a command on the database of the given configuration
+async fn run(options: Options, config: ClushConfig) -> io::Result<()> {
+    if config.storage_config.backend != StorageBackend::Rbatis {
+        return Err(io::Error::other(
+            "the storage backend keeps nothing, set storageConfig.backend to rbatis",
+        ));
+    }
+    if options.command == Command::Migrate {
+        return migrate(&config).await;
+    }
+
+    let db = storage::open(&config).await?;
+    match options.command {
+        Command::CreateUser { username } => {
+            let password = read_password(options.password)?;
+            create_user(&*db, options.id, username, &password).await
+        }
+        Command::ResetPassword { user_id } => {
+            let password = read_password(options.password)?;
+            reset_password(&*db, user_id, &password).await
+        }
+        Command::CreateGroup { name } => create_group(&*db, options.id, name).await,
+        Command::CreateRole { name } => create_role(&*db, options.id, name).await,
+        Command::AddMember { group_id, user_id } => {
+            add_member(&*db, group_id, user_id, options.role).await
+        }
+        Command::PurgeMessages { days } => purge_messages(&*db, days).await,
+        Command::Audit => print_audit(&*db, &options.query).await,
+        Command::Migrate => unreachable!("migrated above"),
+    }
+}
+
+/// parse the arguments after the program name
+fn parse_args(args: &[String]) -> Result<Options, String> {
+    let mut loader = ConfigLoader::new();
+    let mut password = None;
+    let mut id = None;
+    let mut role = None;
+    let mut query = AuditQuery::default();
+    let mut rest = vec![];
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| {
+            args.next()
+                .cloned()
+                .ok_or_else(|| format!("missing value of {}", name))
+        };
+        match arg.as_str() {
+            "-c" | "--config" => loader = loader.path(value(arg)?),
+            "--set" => loader = loader.set(value(arg)?),
+            "-p" | "--password" => password = Some(value(arg)?),
+            "--id" => id = Some(parse_id(&value(arg)?)?),
+            "--role" => role = Some(parse_id(&value(arg)?)?),
+            "--from" => query.from = Some(parse_time(&value(arg)?)?),
+            "--to" => query.to = Some(parse_time(&value(arg)?)?),
+            "--actor" => query.actor_id = Some(parse_id(&value(arg)?)?),
+            "--kind" => query.kind = Some(value(arg)?.parse()?),
+            "--limit" => {
+                let limit = value(arg)?;
+                let limit = limit
+                    .parse()
+                    .map_err(|_| format!("invalid limit `{}`", limit))?;
+                query.limit = Some(limit);
+            }
+            "-h" | "--help" => {
+                return Err("clush-admin, provisioning and maintenance of clush".to_string())
+            }
+            _ if arg.starts_with('-') => return Err(format!("unknown option `{}`", arg)),
+            _ => rest.push(arg.as_str()),
+        }
+    }
+
+    let command = match rest.as_slice() {
+        ["create-user", username] => Command::CreateUser {
+            username: username.to_string(),
+        },
+        ["reset-password", user_id] => Command::ResetPassword {
+            user_id: parse_id(user_id)?,
+        },
+        ["create-group", name] => Command::CreateGroup {
+            name: name.to_string(),
+        },
+        ["create-role", name] => Command::CreateRole {
+            name: name.to_string(),
+        },
+        ["add-member", group_id, user_id] => Command::AddMember {
+            group_id: parse_id(group_id)?,
+            user_id: parse_id(user_id)?,
+        },
+        ["migrate"] => Command::Migrate,
+        ["purge-messages", days] => Command::PurgeMessages {
+            days: days
+                .parse()
+                .map_err(|_| format!("invalid number of days `{}`", days))?,
+        },
+        ["audit"] => Command::Audit,
+        [] => return Err("missing command".to_string()),
+        _ => return Err(format!("invalid command `{}`", rest.join(" "))),
+    };
+
+    Ok(Options {
+        loader,
+        password,
+        id,
+        role,
+        query,
+        command,
+    })
+}
+
+fn parse_id(id: &str) -> Result<u64, String> {
+    id.parse().map_err(|_| format!("invalid id `{}`", id))
+}
+
+fn parse_time(time: &str) -> Result<DateTime<Utc>, String> {
+    DateTime::parse_from_rfc3339(time)
+        .map(|time| time.with_timezone(&Utc))
+        .map_err(|_| format!("invalid time `{}`, expected RFC 3339", time))
+}
+
+/// get the password given with `--password`, or the first line of stdin
+fn read_password(password: Option<String>) -> io::Result<String> {
+    let password = match password {
+        Some(password) => password,
+        None => {
+            let mut line = String::new();
+            io::stdin().lock().read_line(&mut line)?;
+            line.trim_end_matches(&['\r', '\n'][..]).to_string()
+        }
+    };
+    if password.is_empty() {
+        return Err(io::Error::other("the password must not be empty"));
+    }
+
+    Ok(password)
+}
+
+/// apply pending database migrations
+async fn migrate(config: &ClushConfig) -> io::Result<()> {
+    let db = RbatisStorage::connect(&config.rbatis_config).await?;
+    let applied = db.migrate().await?;
+    if applied.is_empty() {
+        println!("schema is up to date");
+    }
+    for version in applied {
+        println!("applied migration {}", version);
+    }
+
+    Ok(())
+}
+
+async fn create_user(
+    db: &dyn Storage,
+    id: Option<u64>,
+    username: String,
+    password: &str,
+) -> io::Result<()> {
+    if let Some(id) = id {
+        if db.fetch_user(id).await?.is_some() {
+            return Err(io::Error::other(format!("user {} exists", id)));
+        }
+    }
+
+    let user = User {
+        id,
+        username: Some(username),
+        password: Some(hash_password(password)),
+        disabled: Some(false),
+    };
+    println!("{}", db.save_user(&user).await?);
+
+    Ok(())
+}
+
+async fn reset_password(db: &dyn Storage, user_id: u64, password: &str) -> io::Result<()> {
+    let user = User {
+        id: Some(user_id),
+        username: None,
+        password: Some(hash_password(password)),
+        disabled: None,
+    };
+    if !db.update_user(&user).await? {
+        return Err(io::Error::other(format!("no user {}", user_id)));
+    }
+    let event = AuditEvent::new(AuditKind::PasswordChanged, 0).target(user_id);
+    audit::record(db, event).await;
+
+    Ok(())
+}
+
+async fn create_group(db: &dyn Storage, id: Option<u64>, name: String) -> io::Result<()> {
+    if let Some(id) = id {
+        if db.fetch_group(id).await?.is_some() {
+            return Err(io::Error::other(format!("group {} exists", id)));
+        }
+    }
+
+    let group = Group {
+        id,
+        group_name: Some(name),
+    };
+    println!("{}", db.save_group(&group).await?);
+
+    Ok(())
+}
+
+async fn create_role(db: &dyn Storage, id: Option<u64>, name: String) -> io::Result<()> {
+    if let Some(id) = id {
+        if db.fetch_role(id).await?.is_some() {
+            return Err(io::Error::other(format!("role {} exists", id)));
+        }
+    }
+
+    let role = Role {
+        id,
+        role_name: Some(name),
+    };
+    println!("{}", db.save_role(&role).await?);
+
+    Ok(())
+}
+
+async fn add_member(
+    db: &dyn Storage,
+    group_id: u64,
+    user_id: u64,
+    role_id: Option<u64>,
+) -> io::Result<()> {
+    if db.fetch_group(group_id).await?.is_none() {
+        return Err(io::Error::other(format!("no group {}", group_id)));
+    }
+    if db.fetch_user(user_id).await?.is_none() {
+        return Err(io::Error::other(format!("no user {}", user_id)));
+    }
+    if let Some(role_id) = role_id {
+        if db.fetch_role(role_id).await?.is_none() {
+            return Err(io::Error::other(format!("no role {}", role_id)));
+        }
+    }
+    let members = db.fetch_members(group_id).await?;
+    if members.iter().any(|member| member.user_id == Some(user_id)) {
+        let message = format!("user {} is a member of group {}", user_id, group_id);
+        return Err(io::Error::other(message));
+    }
+
+    let member = GroupMember {
+        id: None,
+        group_id: Some(group_id),
+        user_id: Some(user_id),
+        role_id,
+    };
+    db.save_member(&member).await?;
+    let detail = match role_id {
+        Some(role_id) => format!("user {} with role {}", user_id, role_id),
+        None => format!("user {}", user_id),
+    };
+    let event = AuditEvent::new(AuditKind::MemberAdded, 0)
+        .target(group_id)
+        .detail(detail);
+    audit::record(db, event).await;
+
+    Ok(())
+}
+
+/// remove the messages older than the given days, except those not delivered yet
+async fn purge_messages(db: &dyn Storage, days: u64) -> io::Result<()> {
+    let before = days_ago(Utc::now(), days).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} days ago is out of range", days),
+        )
+    })?;
+    let removed = db.purge_msgs(before).await?;
+    println!(
+        "removed {} messages sent before {}",
+        removed,
+        before.to_rfc3339()
+    );
+
+    Ok(())
+}
+
+/// get the time the given days before `now`, None if it cannot be represented
+fn days_ago(now: DateTime<Utc>, days: u64) -> Option<DateTime<Utc>> {
+    let secs = days.checked_mul(24 * 60 * 60)?;
+    let duration = chrono::Duration::from_std(std::time::Duration::from_secs(secs)).ok()?;
+
+    now.checked_sub_signed(duration)
+}
+
+/// print the events asked for, tab separated, `-` for what is unknown
+async fn print_audit(db: &dyn Storage, query: &AuditQuery) -> io::Result<()> {
+    let or_dash = |value: Option<String>| value.unwrap_or_else(|| "-".to_string());
+    for event in db.fetch_audit(query).await? {
+        println!(
+            "{}\t{}\t{}\t{}\t{}\t{}",
+            or_dash(event.date_time.map(|time| time.to_rfc3339())),
+            or_dash(event.kind),
+            or_dash(event.actor_id.map(|id| id.to_string())),
+            or_dash(event.target_id.map(|id| id.to_string())),
+            or_dash(event.address),
+            or_dash(event.detail),
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn parse_args_test() {
+        let options = parse_args(&args("-p secret --id 7 create-user alice")).unwrap();
+        assert_eq!(Some("secret".to_string()), options.password);
+        assert_eq!(Some(7), options.id);
+        assert_eq!(
+            Command::CreateUser {
+                username: "alice".to_string()
+            },
+            options.command
+        );
+
+        let line = "--config clush.toml --set storageConfig.backend=rbatis add-member 1 2 --role 3";
+        let options = parse_args(&args(line)).unwrap();
+        assert_eq!(Some(3), options.role);
+        assert_eq!(
+            Command::AddMember {
+                group_id: 1,
+                user_id: 2
+            },
+            options.command
+        );
+
+        let line = "--from 2021-07-01T00:00:00Z --actor 0 --kind passwordChanged --limit 5 audit";
+        let options = parse_args(&args(line)).unwrap();
+        assert_eq!(Command::Audit, options.command);
+        assert_eq!(
+            "2021-07-01T00:00:00+00:00",
+            options.query.from.unwrap().to_rfc3339()
+        );
+        assert_eq!(Some(0), options.query.actor_id);
+        assert_eq!(Some(AuditKind::PasswordChanged), options.query.kind);
+        assert_eq!(Some(5), options.query.limit);
+
+        let options = parse_args(&args("purge-messages 30")).unwrap();
+        assert_eq!(Command::PurgeMessages { days: 30 }, options.command);
+        assert_eq!(None, options.password);
+    }
+
+    #[test]
+    fn days_ago_test() {
+        let now = chrono::Utc::now();
+        assert_eq!(Some(now), days_ago(now, 0));
+        assert_eq!(Some(now - chrono::Duration::days(30)), days_ago(now, 30));
+        // far too many days are an error, not a panic
+        assert_eq!(None, days_ago(now, 1_000_000_000));
+        assert_eq!(None, days_ago(now, u64::MAX));
+    }
+
+    #[test]
+    fn invalid_args_test() {
+        for line in &[
+            "",
+            "create-user",
+            "create-user alice bob",
+            "reset-password alice",
+            "add-member 1",
+            "purge-messages -1",
+            "purge-messages soon",
+            "--id x create-group clush",
+            "--bogus migrate",
+            "--config",
+            "--from yesterday audit",
+            "--kind logout audit",
+        ] {
+            assert!(parse_args(&args(line)).is_err(), "{}", line);
+        }
+    }
+}
@@ -0,0 +1,415 @@
+//! # clush-cli
+//!
+//! a command-line client of clush, for scripting and debugging
+//!
+//! MIT License
+//! Copyright (c) 2021 Bruce Kang
+
+use clush_server::codec::HEADER_SIZE;
+use clush_server::{tls, ClushClient, ClushFrame, MessageType};
+use std::io::{self, Write};
+use std::time::Duration;
+use tokio::time;
+
+static USAGE: &str = "usage: clush-cli [options] <command>
+
+options:
+  -s, --server <addr>     server address, default 127.0.0.1:9527
+  -u, --user <id>         id of the user to log in as
+  -p, --password <hex>    password in hex, default $CLUSH_PASSWORD
+      --tls               connect over TLS
+      --ca <path>         PEM certificates to trust with --tls
+      --domain <name>     name in the server certificate, default localhost
+  -f, --format <format>   output of tail: text, json or hex, default text
+
+commands:
+  send <user|group> <id> <text>        send a text message
+  send-file <user|group> <id> <path>   send a file
+  tail                                 print incoming frames until the server closes";
+
+/// how long `send` waits for the server to acknowledge a message
+static ACK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// output format of incoming frames
+#[derive(Debug, PartialEq)]
+enum Format {
+    Text,
+    Json,
+    Hex,
+}
+
+/// what to do once logged in
+#[derive(Debug, PartialEq)]
+enum Command {
+    Send {
+        msg_type: MessageType,
+        to_id: u64,
+        content: Vec<u8>,
+    },
+    SendFile {
+        msg_type: MessageType,
+        to_id: u64,
+        path: String,
+    },
+    Tail,
+}
+
+/// parsed command line
+#[derive(Debug, PartialEq)]
+struct Options {
+    server: String,
+    user: u64,
+    password: Vec<u8>,
+    tls: bool,
+    ca: Option<String>,
+    domain: String,
+    format: Format,
+    command: Command,
+}
+
+#[tokio::main]
+async fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let password = std::env::var("CLUSH_PASSWORD").ok();
+    let options = match parse_args(&args, password) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    if let Err(e) = run(options).await {
+        eprintln!("error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+/// connect, log in and run the command
+async fn run(options: Options) -> io::Result<()> {
+    let mut client = if options.tls {
+        let ca = options.ca.as_deref().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "--tls needs --ca <path>")
+        })?;
+        let config = tls::client_config(ca)?;
+        ClushClient::connect_tls(&options.server, &options.domain, config).await?
+    } else {
+        ClushClient::connect(&options.server).await?
+    };
+    client.login(options.user, &options.password).await?;
+
+    match options.command {
+        Command::Send {
+            msg_type,
+            to_id,
+            content,
+        } => send(client, msg_type, to_id, content).await,
+        Command::SendFile {
+            msg_type,
+            to_id,
+            path,
+        } => {
+            let content = tokio::fs::read(&path).await?;
+            send(client, msg_type, to_id, content).await
+        }
+        Command::Tail => tail(client, &options.format).await,
+    }
+}
+
+/// send a message, then wait for its ack if the server acknowledges its type
+async fn send(
+    client: ClushClient,
+    msg_type: MessageType,
+    to_id: u64,
+    content: Vec<u8>,
+) -> io::Result<()> {
+    let expects_ack = matches!(
+        msg_type,
+        MessageType::UserMessage | MessageType::GroupMessage
+    );
+    let (mut sender, mut receiver) = client.split();
+    let mut frame = ClushFrame::new(msg_type, sender.uid(), to_id, 0, Default::default());
+    frame.append(&content).update_size();
+    sender.send_frame(&frame).await?;
+
+    if expects_ack {
+        let acked = time::timeout(ACK_TIMEOUT, async {
+            while let Some(frame) = receiver.recv().await? {
+                if let MessageType::AckMessage = frame.msg_type {
+                    return Ok(true);
+                }
+            }
+            Ok::<_, io::Error>(false)
+        })
+        .await;
+        match acked {
+            Ok(Ok(true)) => (),
+            Ok(Ok(false)) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "connection closed before the message was acknowledged",
+                ))
+            }
+            Ok(Err(e)) => return Err(e),
+            Err(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "the message was not acknowledged in time",
+                ))
+            }
+        }
+    }
+
+    sender.close().await
+}
+
+/// print incoming frames until the server closes the connection
+async fn tail(mut client: ClushClient, format: &Format) -> io::Result<()> {
+    let stdout = io::stdout();
+    while let Some(frame) = client.recv().await? {
+        let mut out = stdout.lock();
+        writeln!(out, "{}", format_frame(&frame, format))?;
+        out.flush()?;
+    }
+
+    Ok(())
+}
+
+/// parse the arguments after the program name
+fn parse_args(args: &[String], password: Option<String>) -> Result<Options, String> {
+    let mut server = "127.0.0.1:9527".to_string();
+    let mut user = None;
+    let mut password = password;
+    let mut tls = false;
+    let mut ca = None;
+    let mut domain = "localhost".to_string();
+    let mut format = Format::Text;
+    let mut rest = vec![];
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| {
+            args.next()
+                .cloned()
+                .ok_or_else(|| format!("missing value of {}", name))
+        };
+        match arg.as_str() {
+            "-s" | "--server" => server = value(arg)?,
+            "-u" | "--user" => user = Some(parse_id(&value(arg)?)?),
+            "-p" | "--password" => password = Some(value(arg)?),
+            "--tls" => tls = true,
+            "--ca" => ca = Some(value(arg)?),
+            "--domain" => domain = value(arg)?,
+            "-f" | "--format" => {
+                format = match value(arg)?.as_str() {
+                    "text" => Format::Text,
+                    "json" => Format::Json,
+                    "hex" => Format::Hex,
+                    other => return Err(format!("unknown format `{}`", other)),
+                }
+            }
+            "-h" | "--help" => return Err("clush-cli, a command-line client of clush".to_string()),
+            _ if arg.starts_with('-') => return Err(format!("unknown option `{}`", arg)),
+            _ => rest.push(arg.as_str()),
+        }
+    }
+
+    let user = user.ok_or("missing --user")?;
+    let password = password.ok_or("missing --password")?;
+    let password = parse_hex(&password).ok_or("the password must be hex")?;
+    let command = match rest.as_slice() {
+        ["send", kind, id, text] => Command::Send {
+            msg_type: parse_kind(kind, false)?,
+            to_id: parse_id(id)?,
+            content: text.as_bytes().to_vec(),
+        },
+        ["send-file", kind, id, path] => Command::SendFile {
+            msg_type: parse_kind(kind, true)?,
+            to_id: parse_id(id)?,
+            path: path.to_string(),
+        },
+        ["tail"] => Command::Tail,
+        [] => return Err("missing command".to_string()),
+        _ => return Err(format!("invalid command `{}`", rest.join(" "))),
+    };
+
+    Ok(Options {
+        server,
+        user,
+        password,
+        tls,
+        ca,
+        domain,
+        format,
+        command,
+    })
+}
+
+/// get the message type sent to a user or a group
+fn parse_kind(kind: &str, file: bool) -> Result<MessageType, String> {
+    match (kind, file) {
+        ("user", false) => Ok(MessageType::UserMessage),
+        ("group", false) => Ok(MessageType::GroupMessage),
+        ("user", true) => Ok(MessageType::UserFileMessage),
+        ("group", true) => Ok(MessageType::GroupFileMessage),
+        _ => Err(format!("expected `user` or `group`, got `{}`", kind)),
+    }
+}
+
+fn parse_id(id: &str) -> Result<u64, String> {
+    id.parse().map_err(|_| format!("invalid id `{}`", id))
+}
+
+/// decode a string of hex, None if it is not valid hex
+fn parse_hex(hex: &str) -> Option<Vec<u8>> {
+    hex.as_bytes()
+        .chunks(2)
+        .map(|pair| match pair {
+            [high, low] => Some((hex_digit(*high)? << 4) | hex_digit(*low)?),
+            _ => None,
+        })
+        .collect()
+}
+
+fn hex_digit(digit: u8) -> Option<u8> {
+    (digit as char).to_digit(16).map(|digit| digit as u8)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// name of a message type in the output
+fn type_name(msg_type: &MessageType) -> &'static str {
+    match msg_type {
+        MessageType::Undefined => "undefined",
+        MessageType::LoginMessage => "login",
+        MessageType::UserMessage => "user",
+        MessageType::GroupMessage => "group",
+        MessageType::UserFileMessage => "userFile",
+        MessageType::GroupFileMessage => "groupFile",
+        MessageType::AckMessage => "ack",
+        MessageType::ShutdownMessage => "shutdown",
+        MessageType::HelloMessage => "hello",
+        MessageType::PresenceMessage => "presence",
+    }
+}
+
+/// format a frame as a line of output,
+/// the content of files is written in hex, the one of other frames as text
+fn format_frame(frame: &ClushFrame, format: &Format) -> String {
+    let is_file = matches!(
+        frame.msg_type,
+        MessageType::UserFileMessage | MessageType::GroupFileMessage
+    );
+    let content = if is_file {
+        to_hex(&frame.content)
+    } else {
+        String::from_utf8_lossy(&frame.content).into_owned()
+    };
+
+    match format {
+        Format::Text => format!(
+            "[{}] {} -> {}: {}",
+            type_name(&frame.msg_type),
+            frame.from_id,
+            frame.to_id,
+            content
+        ),
+        Format::Json => serde_json::json!({
+            "type": type_name(&frame.msg_type),
+            "from": frame.from_id,
+            "to": frame.to_id,
+            "size": frame.size,
+            "content": content,
+        })
+        .to_string(),
+        Format::Hex => {
+            let bytes = frame.to_bytes();
+            format!(
+                "{} {}",
+                to_hex(&bytes[..HEADER_SIZE]),
+                to_hex(&bytes[HEADER_SIZE..])
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn parse_args_test() {
+        let options = parse_args(&args("-u 1 -p 1c8a send user 2 hi"), None).unwrap();
+        assert_eq!("127.0.0.1:9527", options.server);
+        assert_eq!(vec![0x1c, 0x8a], options.password);
+        assert_eq!(
+            Command::Send {
+                msg_type: MessageType::UserMessage,
+                to_id: 2,
+                content: b"hi".to_vec(),
+            },
+            options.command
+        );
+
+        let line = "--tls --ca ca.pem -s example.com:9527 -u 1 -f json tail";
+        let options = parse_args(&args(line), Some("00".to_string())).unwrap();
+        assert!(options.tls);
+        assert_eq!(Some("ca.pem".to_string()), options.ca);
+        assert_eq!(Format::Json, options.format);
+        assert_eq!(Command::Tail, options.command);
+
+        let line = "-u 1 send-file group 3 a.txt";
+        let options = parse_args(&args(line), Some("00".to_string())).unwrap();
+        assert!(matches!(
+            options.command,
+            Command::SendFile {
+                msg_type: MessageType::GroupFileMessage,
+                to_id: 3,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn invalid_args_test() {
+        for line in &[
+            "tail",
+            "-u 1 tail",
+            "-u 1 -p xyz tail",
+            "-u 1 -p 00",
+            "-u 1 -p 00 send team 2 hi",
+            "-u 1 -p 00 -f yaml tail",
+            "-u 1 -p 00 --bogus tail",
+        ] {
+            assert!(parse_args(&args(line), None).is_err(), "{}", line);
+        }
+    }
+
+    #[test]
+    fn format_frame_test() {
+        let mut frame = ClushFrame::new(MessageType::UserMessage, 1, 2, 0, Default::default());
+        frame.append(b"hi").update_size();
+
+        assert_eq!("[user] 1 -> 2: hi", format_frame(&frame, &Format::Text));
+        let json: serde_json::Value =
+            serde_json::from_str(&format_frame(&frame, &Format::Json)).unwrap();
+        assert_eq!("user", json["type"]);
+        assert_eq!("hi", json["content"]);
+        assert_eq!(
+            "00000001000000000000000100000000000000020000000000000002 6869",
+            format_frame(&frame, &Format::Hex)
+        );
+
+        frame.set_msg_type(MessageType::UserFileMessage);
+        assert_eq!(
+            "[userFile] 1 -> 2: 6869",
+            format_frame(&frame, &Format::Text)
+        );
+    }
+}
@@ -0,0 +1,438 @@
+//! a client speaking the clush protocol, for bots, load tests and integration tests
+//!
+//! frames are encoded by `codec`, the same way the server does
+
+use crate::codec::{self, ClushFrame, Connection, MessageType};
+use crate::compression::Compression;
+use crate::presence::Status;
+use crate::protocol::{Capabilities, Hello};
+use crate::tls::{rustls, webpki, TlsConnector};
+use bytes::BytesMut;
+use futures::stream::{self, Stream};
+use std::io;
+use std::sync::Arc;
+use tokio::io::{AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::net::{TcpStream, ToSocketAddrs};
+
+/// a clush client
+///
+/// # Example
+///
+/// ```no_run
+/// # use clush_server::client::ClushClient;
+/// # async fn run() -> std::io::Result<()> {
+/// let mut client = ClushClient::connect("127.0.0.1:9527").await?;
+/// client.login(1, &[0x1c, 0x8a]).await?;
+/// client.send_user_msg(2, "hello").await?;
+/// while let Some(frame) = client.recv().await? {
+///     println!("{:?}", frame);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub struct ClushClient {
+    sender: ClientSender,
+    receiver: ClientReceiver,
+    offered: Capabilities,
+    agreed: Hello,
+}
+
+/// the sending half of a client
+pub struct ClientSender {
+    writer: WriteHalf<Box<dyn Connection>>,
+    uid: u64,
+    compression: Option<Compression>,
+}
+
+/// the receiving half of a client
+pub struct ClientReceiver {
+    reader: ReadHalf<Box<dyn Connection>>,
+    compression: Option<Compression>,
+}
+
+impl ClushClient {
+    /// connect to a server over plain TCP
+    pub async fn connect<A: ToSocketAddrs>(addr: A) -> io::Result<ClushClient> {
+        let stream = TcpStream::connect(addr).await?;
+
+        Ok(ClushClient::new(Box::new(stream)))
+    }
+
+    /// connect to a server over TLS, verifying its certificate for `domain`
+    pub async fn connect_tls<A: ToSocketAddrs>(
+        addr: A,
+        domain: &str,
+        config: rustls::ClientConfig,
+    ) -> io::Result<ClushClient> {
+        let domain = webpki::DNSNameRef::try_from_ascii_str(domain)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let stream = TcpStream::connect(addr).await?;
+        let stream = TlsConnector::from(Arc::new(config))
+            .connect(domain, stream)
+            .await?;
+
+        Ok(ClushClient::new(Box::new(stream)))
+    }
+
+    /// create a client on an established connection
+    pub fn new(stream: Box<dyn Connection>) -> ClushClient {
+        let (reader, writer) = tokio::io::split(stream);
+
+        ClushClient {
+            sender: ClientSender {
+                writer,
+                uid: 0,
+                compression: None,
+            },
+            receiver: ClientReceiver {
+                reader,
+                compression: None,
+            },
+            offered: Capabilities::ACKS | Capabilities::COMPRESSION,
+            agreed: Hello::new(Capabilities::NONE),
+        }
+    }
+
+    /// set the capabilities offered to the server on login, acks and compression by default
+    pub fn set_capabilities(&mut self, capabilities: Capabilities) {
+        self.offered = capabilities;
+    }
+
+    /// get the capabilities agreed with the server, none before login
+    pub fn capabilities(&self) -> Capabilities {
+        self.agreed.capabilities
+    }
+
+    /// get the protocol version agreed with the server
+    pub fn protocol_version(&self) -> u32 {
+        self.agreed.version
+    }
+
+    /// negotiate the protocol and log in as the given user,
+    /// fail with `Unsupported` if the server refuses the protocol version
+    /// or with `PermissionDenied` if it refuses the user
+    pub async fn login(&mut self, uid: u64, password: &[u8]) -> io::Result<()> {
+        self.sender
+            .send_frame(&Hello::new(self.offered).to_frame())
+            .await?;
+        let reply = self.recv_reply().await?;
+        self.agreed = Hello::from_frame(&reply).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::Unsupported,
+                String::from_utf8_lossy(&reply.content).into_owned(),
+            )
+        })?;
+        if self.agreed.capabilities.contains(Capabilities::COMPRESSION) {
+            self.sender.compression = Some(Compression::default());
+            self.receiver.compression = Some(Compression::default());
+        }
+
+        let mut frame = ClushFrame::new(MessageType::LoginMessage, uid, 0, 0, BytesMut::new());
+        frame.append(password).update_size();
+        self.sender.send_frame(&frame).await?;
+
+        let reply = self.recv_reply().await?;
+        match reply.msg_type {
+            MessageType::LoginMessage if &reply.content[..] == b"success" => {
+                self.sender.uid = uid;
+                Ok(())
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                String::from_utf8_lossy(&reply.content).into_owned(),
+            )),
+        }
+    }
+
+    /// get the id of the logged in user, 0 before login
+    pub fn uid(&self) -> u64 {
+        self.sender.uid
+    }
+
+    /// receive the reply to a login step
+    async fn recv_reply(&mut self) -> io::Result<ClushFrame> {
+        self.receiver.recv().await?.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "connection closed during login",
+            )
+        })
+    }
+
+    /// send a frame as is
+    pub async fn send_frame(&mut self, frame: &ClushFrame) -> io::Result<()> {
+        self.sender.send_frame(frame).await
+    }
+
+    /// send a text message to a user
+    pub async fn send_user_msg(&mut self, to_id: u64, content: &str) -> io::Result<()> {
+        self.sender.send_user_msg(to_id, content).await
+    }
+
+    /// send a text message to a group
+    pub async fn send_group_msg(&mut self, group_id: u64, content: &str) -> io::Result<()> {
+        self.sender.send_group_msg(group_id, content).await
+    }
+
+    /// send a file to a user
+    pub async fn send_user_file(&mut self, to_id: u64, content: &[u8]) -> io::Result<()> {
+        self.sender.send_user_file(to_id, content).await
+    }
+
+    /// send a file to a group
+    pub async fn send_group_file(&mut self, group_id: u64, content: &[u8]) -> io::Result<()> {
+        self.sender.send_group_file(group_id, content).await
+    }
+
+    /// set the status of the logged in user, told to those watching them
+    pub async fn set_status(&mut self, status: Status) -> io::Result<()> {
+        self.sender.set_status(status).await
+    }
+
+    /// watch the presence of a user, the server answers with their current presence
+    pub async fn subscribe(&mut self, user_id: u64) -> io::Result<()> {
+        self.sender.subscribe(user_id).await
+    }
+
+    /// stop watching the presence of a user
+    pub async fn unsubscribe(&mut self, user_id: u64) -> io::Result<()> {
+        self.sender.unsubscribe(user_id).await
+    }
+
+    /// receive the next frame, None if the server closed the connection
+    pub async fn recv(&mut self) -> io::Result<Option<ClushFrame>> {
+        self.receiver.recv().await
+    }
+
+    /// split the client to send and receive from different tasks
+    pub fn split(self) -> (ClientSender, ClientReceiver) {
+        (self.sender, self.receiver)
+    }
+}
+
+impl ClientSender {
+    /// get the id of the logged in user, 0 before login
+    pub fn uid(&self) -> u64 {
+        self.uid
+    }
+
+    /// send a frame as is
+    pub async fn send_frame(&mut self, frame: &ClushFrame) -> io::Result<()> {
+        codec::write_frame(&mut self.writer, frame).await
+    }
+
+    /// send a text message to a user
+    pub async fn send_user_msg(&mut self, to_id: u64, content: &str) -> io::Result<()> {
+        self.send(MessageType::UserMessage, to_id, content.as_bytes())
+            .await
+    }
+
+    /// send a text message to a group
+    pub async fn send_group_msg(&mut self, group_id: u64, content: &str) -> io::Result<()> {
+        self.send(MessageType::GroupMessage, group_id, content.as_bytes())
+            .await
+    }
+
+    /// send a file to a user
+    pub async fn send_user_file(&mut self, to_id: u64, content: &[u8]) -> io::Result<()> {
+        self.send(MessageType::UserFileMessage, to_id, content)
+            .await
+    }
+
+    /// send a file to a group
+    pub async fn send_group_file(&mut self, group_id: u64, content: &[u8]) -> io::Result<()> {
+        self.send(MessageType::GroupFileMessage, group_id, content)
+            .await
+    }
+
+    /// set the status of the logged in user, told to those watching them
+    pub async fn set_status(&mut self, status: Status) -> io::Result<()> {
+        self.send(MessageType::PresenceMessage, 0, status.as_str().as_bytes())
+            .await
+    }
+
+    /// watch the presence of a user, the server answers with their current presence
+    pub async fn subscribe(&mut self, user_id: u64) -> io::Result<()> {
+        self.send(MessageType::PresenceMessage, user_id, b"subscribe")
+            .await
+    }
+
+    /// stop watching the presence of a user
+    pub async fn unsubscribe(&mut self, user_id: u64) -> io::Result<()> {
+        self.send(MessageType::PresenceMessage, user_id, b"unsubscribe")
+            .await
+    }
+
+    /// close the sending side of the connection
+    pub async fn close(&mut self) -> io::Result<()> {
+        self.writer.shutdown().await
+    }
+
+    /// send a frame of the given type from the logged in user,
+    /// compressed if negotiated and large enough
+    async fn send(&mut self, msg_type: MessageType, to_id: u64, content: &[u8]) -> io::Result<()> {
+        let mut frame = ClushFrame::new(msg_type, self.uid, to_id, 0, BytesMut::new());
+        frame.append(content).update_size();
+        if let Some(compression) = self.compression {
+            compression.compress(&mut frame)?;
+        }
+
+        self.send_frame(&frame).await
+    }
+}
+
+impl ClientReceiver {
+    /// receive the next frame, None if the server closed the connection,
+    /// compressed content is decompressed
+    pub async fn recv(&mut self) -> io::Result<Option<ClushFrame>> {
+        let mut frame = match codec::read_frame(&mut self.reader).await? {
+            Some(frame) => frame,
+            None => return Ok(None),
+        };
+
+        if frame.compressed {
+            let compression = self.compression.ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, "compression was not negotiated")
+            })?;
+            compression.decompress(&mut frame)?;
+        }
+
+        Ok(Some(frame))
+    }
+
+    /// turn into a stream of incoming frames, which ends after the connection is closed
+    /// or after the first error
+    pub fn into_stream(self) -> impl Stream<Item = io::Result<ClushFrame>> {
+        stream::unfold(Some(self), |receiver| async move {
+            let mut receiver = receiver?;
+            match receiver.recv().await {
+                Ok(Some(frame)) => Some((Ok(frame), Some(receiver))),
+                Ok(None) => None,
+                Err(e) => Some((Err(e), None)),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ClushConfig;
+    use crate::entity::User;
+    use crate::server::ClushServer;
+    use crate::storage::{MemoryStorage, UserRepository};
+    use crate::tls;
+    use futures::StreamExt;
+    use std::net::SocketAddr;
+    use tokio::net::TcpListener;
+
+    /// start a server on a random port with the users 1 and 2, both of password `1c8a`
+    async fn start_server(mut config: ClushConfig) -> SocketAddr {
+        // every server logs to its own write-ahead log
+        let wal_path = std::env::temp_dir().join(format!(
+            "clush-wal-{}",
+            chrono::Utc::now().timestamp_nanos()
+        ));
+        config.wal_config.path = wal_path.to_str().unwrap().to_string();
+        let db = Arc::new(MemoryStorage::new());
+        for id in 1..=2 {
+            let user = User {
+                id: Some(id),
+                username: Some(format!("user{}", id)),
+                password: Some("1c8a".to_string()),
+                disabled: None,
+            };
+            db.save_user(&user).await.unwrap();
+        }
+
+        let server = ClushServer::builder()
+            .config(config)
+            .listener(TcpListener::bind("127.0.0.1:0").await.unwrap())
+            .storage(db)
+            .build()
+            .await
+            .unwrap();
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(async move { server.start().await });
+
+        addr
+    }
+
+    #[tokio::test]
+    async fn user_msg_test() {
+        let addr = start_server(ClushConfig::default()).await;
+        let mut alice = ClushClient::connect(addr).await.unwrap();
+        alice.login(1, &[0x1c, 0x8a]).await.unwrap();
+        assert_eq!(1, alice.uid());
+        let mut bob = ClushClient::connect(addr).await.unwrap();
+        bob.login(2, &[0x1c, 0x8a]).await.unwrap();
+
+        alice.send_user_msg(2, "hello").await.unwrap();
+
+        let (_, receiver) = bob.split();
+        let mut frames = Box::pin(receiver.into_stream());
+        let frame = frames.next().await.unwrap().unwrap();
+        assert!(matches!(frame.msg_type, MessageType::UserMessage));
+        assert_eq!((1, 2), (frame.from_id, frame.to_id));
+        assert_eq!(&b"hello"[..], &frame.content[..]);
+
+        let ack = alice.recv().await.unwrap().unwrap();
+        assert!(matches!(ack.msg_type, MessageType::AckMessage));
+    }
+
+    #[tokio::test]
+    async fn negotiate_test() {
+        let addr = start_server(ClushConfig::default()).await;
+
+        let mut client = ClushClient::connect(addr).await.unwrap();
+        client.set_capabilities(Capabilities::ACKS | Capabilities::FILE_RESUME);
+        client.login(1, &[0x1c, 0x8a]).await.unwrap();
+        assert_eq!(crate::protocol::PROTOCOL_VERSION, client.protocol_version());
+        assert_eq!(Capabilities::ACKS, client.capabilities());
+
+        let mut config = ClushConfig::default();
+        config.server_config.min_protocol_version = crate::protocol::PROTOCOL_VERSION;
+        let addr = start_server(config).await;
+
+        // an older client is refused
+        let mut client = ClushClient::connect(addr).await.unwrap();
+        let hello = Hello {
+            version: crate::protocol::LEGACY_VERSION,
+            capabilities: Capabilities::NONE,
+        };
+        client.send_frame(&hello.to_frame()).await.unwrap();
+        let reply = client.recv().await.unwrap().unwrap();
+        assert_eq!(MessageType::UserMessage, reply.msg_type);
+        assert!(Hello::from_frame(&reply).is_none());
+    }
+
+    #[tokio::test]
+    async fn login_failure_test() {
+        let addr = start_server(ClushConfig::default()).await;
+
+        let mut client = ClushClient::connect(addr).await.unwrap();
+        let e = client.login(1, b"wrong").await.unwrap_err();
+        assert_eq!(io::ErrorKind::PermissionDenied, e.kind());
+        assert_eq!(0, client.uid());
+    }
+
+    #[tokio::test]
+    async fn tls_test() {
+        let (cert_path, key_path) = tls::tests::self_signed();
+        let mut config = ClushConfig::default();
+        config.server_config.enable_tls = true;
+        config.server_config.cert_path = cert_path.clone();
+        config.server_config.key_path = key_path;
+        let addr = start_server(config).await;
+
+        let client_config = tls::client_config(&cert_path).unwrap();
+        let mut client = ClushClient::connect_tls(addr, "localhost", client_config)
+            .await
+            .unwrap();
+        client.login(1, &[0x1c, 0x8a]).await.unwrap();
+
+        // a plain client cannot talk to a TLS server
+        let mut client = ClushClient::connect(addr).await.unwrap();
+        assert!(client.login(1, &[0x1c, 0x8a]).await.is_err());
+    }
+}
@@ -0,0 +1,355 @@
+//! framing of the clush protocol
+//!
+//! every frame is a header of `HEADER_SIZE` bytes, holding the message type,
+//! the sender, the recipient and the size of the content, all big-endian,
+//! followed by the content
+//!
+//! the highest bit of the message type is set if the content is compressed
+//!
+//! the size announced in a header is checked against `FrameLimits` before the content
+//! is read, and the content is buffered as it arrives, not allocated up front
+
+use crate::config::FrameConfig;
+use crate::util::*;
+use bytes::{Bytes, BytesMut};
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+pub use crate::util::MessageType;
+
+/// length of msg_type + from_id + to_id + size
+pub const HEADER_SIZE: usize = 28;
+/// bit of the message type set on frames whose content is compressed
+pub const COMPRESSED_FLAG: u32 = 1 << 31;
+/// how much content buffer grows at most per read
+const READ_CHUNK: u64 = 64 * 1024;
+
+/// a duplex byte stream carrying frames, e.g. TCP or TLS over TCP
+pub trait Connection: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Connection for T {}
+
+/// a frame used to communicate with clush client and server
+#[derive(Clone, Debug)]
+pub struct ClushFrame {
+    pub msg_type: MessageType,
+    pub from_id: u64,
+    pub to_id: u64,
+    pub size: u64,
+    pub content: BytesMut,
+    /// whether the content is compressed, see `compression`
+    pub compressed: bool,
+}
+
+impl ClushFrame {
+    pub fn new(
+        msg_type: MessageType,
+        from_id: u64,
+        to_id: u64,
+        size: u64,
+        content: BytesMut,
+    ) -> ClushFrame {
+        ClushFrame {
+            msg_type,
+            from_id,
+            to_id,
+            size,
+            content,
+            compressed: false,
+        }
+    }
+
+    /// set the message type of ClushFrame
+    pub fn set_msg_type(&mut self, msg_type: MessageType) -> &mut Self {
+        self.msg_type = msg_type;
+
+        self
+    }
+
+    /// append the given content to ClushFrame's content
+    pub fn append(&mut self, content: &[u8]) -> &mut Self {
+        self.content.extend_from_slice(content);
+
+        self
+    }
+
+    /// update the size of frame
+    pub fn update_size(&mut self) {
+        self.size = self.content.len() as u64;
+    }
+
+    /// convert ClushFrame to Bytes
+    /// convenience for writing ClushFrame into byte stream
+    pub fn to_bytes(&self) -> Bytes {
+        let mut bytes_mut = BytesMut::with_capacity(0);
+
+        // convert MessageType to [u8; 4]
+        let code = match self.msg_type {
+            MessageType::UserMessage => 1,
+            MessageType::GroupMessage => 2,
+            MessageType::UserFileMessage => 3,
+            MessageType::GroupFileMessage => 4,
+            MessageType::AckMessage => 5,
+            MessageType::ShutdownMessage => 6,
+            MessageType::HelloMessage => 7,
+            MessageType::PresenceMessage => 8,
+            _ => 0,
+        };
+        let flags = if self.compressed { COMPRESSED_FLAG } else { 0 };
+        bytes_mut.extend_from_slice(&u32_to_bytes(code | flags)[..]);
+
+        bytes_mut.extend_from_slice(&u64_to_bytes(self.from_id)[..]);
+        bytes_mut.extend_from_slice(&u64_to_bytes(self.to_id)[..]);
+        bytes_mut.extend_from_slice(&u64_to_bytes(self.size)[..]);
+        bytes_mut.extend_from_slice(&self.content[..]);
+
+        bytes_mut.freeze()
+    }
+}
+
+/// maximum sizes in bytes of the content of frames, by message type
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FrameLimits {
+    /// user and group messages
+    pub message: u64,
+    /// user and group files
+    pub file: u64,
+    /// login, ack, shutdown, hello and presence frames
+    pub control: u64,
+}
+
+impl Default for FrameLimits {
+    fn default() -> Self {
+        FrameLimits::new(&FrameConfig::default())
+    }
+}
+
+impl FrameLimits {
+    /// create limits from the configuration
+    pub fn new(config: &FrameConfig) -> FrameLimits {
+        FrameLimits {
+            message: config.max_message_size,
+            file: config.max_file_size,
+            control: config.max_control_size,
+        }
+    }
+
+    /// get the maximum content size of a message type
+    pub fn max_size(&self, msg_type: &MessageType) -> u64 {
+        match msg_type {
+            MessageType::UserMessage | MessageType::GroupMessage => self.message,
+            MessageType::UserFileMessage | MessageType::GroupFileMessage => self.file,
+            _ => self.control,
+        }
+    }
+
+    /// fail with `InvalidData` if the content size exceeds the limit of its message type
+    pub fn check(&self, msg_type: &MessageType, size: u64) -> io::Result<()> {
+        let max_size = self.max_size(msg_type);
+        if size > max_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "frame of {} bytes exceeds the limit of {} bytes",
+                    size, max_size
+                ),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// read a frame from the given reader within the default limits,
+/// return None if the reader is closed between two frames
+pub async fn read_frame<R>(reader: &mut R) -> io::Result<Option<ClushFrame>>
+where
+    R: AsyncRead + Unpin,
+{
+    read_frame_with_limits(reader, &FrameLimits::default()).await
+}
+
+/// read a frame from the given reader,
+/// fail with `InvalidData` before reading the content if it exceeds the limits
+pub async fn read_frame_with_limits<R>(
+    reader: &mut R,
+    limits: &FrameLimits,
+) -> io::Result<Option<ClushFrame>>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut header = [0u8; HEADER_SIZE];
+
+    // a closed reader is only fine before the first byte of a frame
+    let n = reader.read(&mut header).await?;
+    if n == 0 {
+        return Ok(None);
+    }
+    reader.read_exact(&mut header[n..]).await?;
+
+    let code = u32_from_bytes(&header[0..4]).unwrap();
+    let msg_type = match code & !COMPRESSED_FLAG {
+        0 => MessageType::LoginMessage,
+        1 => MessageType::UserMessage,
+        2 => MessageType::GroupMessage,
+        3 => MessageType::UserFileMessage,
+        4 => MessageType::GroupFileMessage,
+        5 => MessageType::AckMessage,
+        6 => MessageType::ShutdownMessage,
+        7 => MessageType::HelloMessage,
+        8 => MessageType::PresenceMessage,
+        code => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown message type {}", code),
+            ))
+        }
+    };
+    let from_id = u64_from_bytes(&header[4..12]).unwrap();
+    let to_id = u64_from_bytes(&header[12..20]).unwrap();
+    let size = u64_from_bytes(&header[20..28]).unwrap();
+
+    limits.check(&msg_type, size)?;
+
+    // read exactly the content announced in the header, growing the buffer as it arrives
+    let mut body = (&mut *reader).take(size);
+    let mut content = BytesMut::with_capacity(size.min(READ_CHUNK) as usize);
+    while body.limit() > 0 {
+        content.reserve(body.limit().min(READ_CHUNK) as usize);
+        if body.read_buf(&mut content).await? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+    }
+
+    let mut frame = ClushFrame::new(msg_type, from_id, to_id, size, content);
+    frame.compressed = code & COMPRESSED_FLAG != 0;
+
+    Ok(Some(frame))
+}
+
+/// write a frame to the given writer
+pub async fn write_frame<W>(writer: &mut W, frame: &ClushFrame) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    writer.write_all(&frame.to_bytes()[..]).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clush_frame_test() {
+        let frame = ClushFrame::new(MessageType::UserMessage, 0, 0, 5, BytesMut::from("hello"));
+        assert_eq!(0, frame.from_id);
+        assert_eq!(0, frame.to_id);
+        assert_eq!(5, frame.size);
+        assert_eq!(BytesMut::from("hello"), frame.content);
+        assert_eq!(
+            Bytes::from(
+                &[
+                    0u8, 0u8, 0u8, 1u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8,
+                    0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 5u8, b'h', b'e', b'l',
+                    b'l', b'o'
+                ][..]
+            ),
+            frame.to_bytes()
+        );
+    }
+
+    #[test]
+    fn update_size_test() {
+        let mut frame = ClushFrame::new(MessageType::UserMessage, 0, 0, 0, BytesMut::from("hello"));
+        frame.update_size();
+        assert_eq!(5, frame.size);
+    }
+
+    #[tokio::test]
+    async fn read_frame_test() {
+        let (mut client, mut server) = tokio::io::duplex(16);
+        let mut frame = ClushFrame::new(MessageType::UserMessage, 1, 2, 0, BytesMut::new());
+        // content larger than the buffer of the pipe, it arrives in pieces
+        frame.append(&[7u8; 1000]).update_size();
+
+        let writing = {
+            let frame = frame.clone();
+            tokio::spawn(async move {
+                write_frame(&mut client, &frame).await.unwrap();
+                write_frame(&mut client, &frame).await.unwrap();
+            })
+        };
+
+        for _ in 0..2 {
+            let read = read_frame(&mut server).await.unwrap().unwrap();
+            assert_eq!(frame.to_bytes(), read.to_bytes());
+        }
+        writing.await.unwrap();
+        // the writer is gone between two frames
+        assert!(read_frame(&mut server).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn read_incomplete_frame_test() {
+        let frame = ClushFrame::new(MessageType::UserMessage, 1, 2, 5, BytesMut::from("hello"));
+        let bytes = frame.to_bytes();
+
+        // closed within the header, or within the content
+        for len in &[10, HEADER_SIZE + 2] {
+            let mut reader = &bytes[..*len];
+            let e = read_frame(&mut reader).await.unwrap_err();
+            assert_eq!(io::ErrorKind::UnexpectedEof, e.kind());
+        }
+    }
+
+    #[tokio::test]
+    async fn read_oversized_frame_test() {
+        let limits = FrameLimits {
+            message: 4,
+            file: 8,
+            control: 0,
+        };
+        let mut frame = ClushFrame::new(MessageType::UserMessage, 1, 2, 0, BytesMut::new());
+        frame.append(b"hello").update_size();
+        let e = read_frame_with_limits(&mut &frame.to_bytes()[..], &limits)
+            .await
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, e.kind());
+
+        frame.set_msg_type(MessageType::UserFileMessage);
+        let read = read_frame_with_limits(&mut &frame.to_bytes()[..], &limits)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&b"hello"[..], &read.content[..]);
+
+        // a huge size is refused from the header alone
+        let mut header = frame.to_bytes()[..HEADER_SIZE].to_vec();
+        header[20..28].copy_from_slice(&u64::MAX.to_be_bytes());
+        let e = read_frame(&mut &header[..]).await.unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, e.kind());
+    }
+
+    #[tokio::test]
+    async fn read_compressed_frame_test() {
+        let mut frame = ClushFrame::new(MessageType::GroupMessage, 1, 2, 0, BytesMut::new());
+        frame.compressed = true;
+        let bytes = frame.to_bytes();
+        assert_eq!(&[0x80, 0, 0, 2], &bytes[0..4]);
+
+        let read = read_frame(&mut &bytes[..]).await.unwrap().unwrap();
+        assert_eq!(MessageType::GroupMessage, read.msg_type);
+        assert!(read.compressed);
+    }
+
+    #[tokio::test]
+    async fn read_unknown_type_test() {
+        let mut bytes = ClushFrame::new(MessageType::UserMessage, 1, 2, 0, BytesMut::new())
+            .to_bytes()
+            .to_vec();
+        bytes[3] = 0xff;
+
+        let e = read_frame(&mut &bytes[..]).await.unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, e.kind());
+    }
+}
@@ -0,0 +1,145 @@
+//! deflate compression of frame content, used once both sides negotiated
+//! the `COMPRESSION` capability
+//!
+//! only content of at least `threshold` bytes is compressed, and only if it gets smaller,
+//! so small frames are sent as they are.
+//! decompressed content is limited to `max_size` bytes, a small frame cannot expand
+//! into a huge one
+
+use crate::codec::ClushFrame;
+use crate::config::CompressionConfig;
+use bytes::BytesMut;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use std::io::{self, Read, Write};
+
+/// compression settings of a connection
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Compression {
+    threshold: usize,
+    max_size: usize,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Compression::new(&CompressionConfig::default())
+    }
+}
+
+impl Compression {
+    /// create compression settings from the configuration
+    pub fn new(config: &CompressionConfig) -> Compression {
+        Compression {
+            threshold: config.threshold,
+            max_size: config.max_size,
+        }
+    }
+
+    /// compress the content of a frame if it is large enough and gets smaller
+    pub fn compress(&self, frame: &mut ClushFrame) -> io::Result<()> {
+        if frame.compressed || frame.content.len() < self.threshold {
+            return Ok(());
+        }
+
+        let mut encoder = DeflateEncoder::new(vec![], flate2::Compression::default());
+        encoder.write_all(&frame.content)?;
+        let content = encoder.finish()?;
+        if content.len() < frame.content.len() {
+            frame.content = BytesMut::from(&content[..]);
+            frame.compressed = true;
+            frame.update_size();
+        }
+
+        Ok(())
+    }
+
+    /// decompress the content of a compressed frame,
+    /// fail with `InvalidData` if it is corrupt or larger than `max_size`
+    pub fn decompress(&self, frame: &mut ClushFrame) -> io::Result<()> {
+        if !frame.compressed {
+            return Ok(());
+        }
+
+        // never inflate more than one byte past the limit
+        let mut content = vec![];
+        DeflateDecoder::new(&frame.content[..])
+            .take(self.max_size as u64 + 1)
+            .read_to_end(&mut content)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        if content.len() > self.max_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("decompressed content exceeds {} bytes", self.max_size),
+            ));
+        }
+
+        frame.content = BytesMut::from(&content[..]);
+        frame.compressed = false;
+        frame.update_size();
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::MessageType;
+
+    fn frame(content: &[u8]) -> ClushFrame {
+        let mut frame = ClushFrame::new(MessageType::UserMessage, 1, 2, 0, BytesMut::new());
+        frame.append(content).update_size();
+
+        frame
+    }
+
+    #[test]
+    fn compress_test() {
+        let compression = Compression::default();
+        let content = "hello clush ".repeat(200);
+
+        let mut large = frame(content.as_bytes());
+        compression.compress(&mut large).unwrap();
+        assert!(large.compressed);
+        assert!(large.size < content.len() as u64);
+        compression.decompress(&mut large).unwrap();
+        assert!(!large.compressed);
+        assert_eq!(content.as_bytes(), &large.content[..]);
+        assert_eq!(content.len() as u64, large.size);
+
+        // small frames are left alone
+        let mut small = frame(b"hello");
+        compression.compress(&mut small).unwrap();
+        assert!(!small.compressed);
+        assert_eq!(&b"hello"[..], &small.content[..]);
+    }
+
+    #[test]
+    fn decompression_bomb_test() {
+        let config = CompressionConfig {
+            enable: true,
+            threshold: 0,
+            max_size: 1024,
+        };
+        let compression = Compression::new(&config);
+
+        // a megabyte of zeros deflates to about a kilobyte
+        let mut bomb = frame(&vec![0u8; 1024 * 1024]);
+        Compression::default().compress(&mut bomb).unwrap();
+        assert!(bomb.size < 2048);
+        let e = compression.decompress(&mut bomb).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, e.kind());
+
+        // up to the limit is fine
+        let mut fits = frame(&[0u8; 1024]);
+        compression.compress(&mut fits).unwrap();
+        assert!(fits.compressed);
+        compression.decompress(&mut fits).unwrap();
+        assert_eq!(1024, fits.content.len());
+
+        let mut corrupt = frame(&[0xff; 16]);
+        corrupt.compressed = true;
+        let e = compression.decompress(&mut corrupt).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, e.kind());
+    }
+}
@@ -0,0 +1,323 @@
+//! validation of a configuration, reporting every bad field at once
+
+use super::{ClushConfig, StorageBackend};
+use crate::logging;
+use crate::protocol;
+use crate::tls;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::Path;
+
+/// schemes of the database URLs the storage can migrate
+const DB_SCHEMES: &[&str] = &["postgres://", "postgresql://", "sqlite:"];
+
+/// a field of the configuration with an invalid value
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConfigError {
+    /// camelCase path of the field, e.g. `serverConfig.url`
+    pub path: String,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+/// all problems found in a configuration
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ConfigErrors(pub Vec<ConfigError>);
+
+impl fmt::Display for ConfigErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} invalid configuration field(s)", self.0.len())?;
+        for error in &self.0 {
+            write!(f, "\n  {}", error)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigErrors {}
+
+impl From<ConfigErrors> for std::io::Error {
+    fn from(errors: ConfigErrors) -> Self {
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, errors)
+    }
+}
+
+impl ConfigErrors {
+    fn check(&mut self, valid: bool, path: &str, message: &str) {
+        if !valid {
+            self.push(path, message.to_string());
+        }
+    }
+
+    pub(super) fn push(&mut self, path: &str, message: String) {
+        self.0.push(ConfigError {
+            path: path.to_string(),
+            message,
+        });
+    }
+}
+
+impl ClushConfig {
+    /// check every field, listing all the invalid ones
+    ///
+    /// TLS files are read when `enableTls` is set,
+    /// the database URL is checked when the storage backend is `rbatis`
+    pub fn validate(&self) -> Result<(), ConfigErrors> {
+        let mut errors = ConfigErrors::default();
+
+        let server = &self.server_config;
+        if !is_address(&server.url) {
+            errors.push(
+                "serverConfig.url",
+                format!("`{}` is not an address of the form host:port", server.url),
+            );
+        }
+        if server.enable_tls {
+            validate_tls(&server.cert_path, &server.key_path, &mut errors);
+        }
+        errors.check(
+            server.shutdown_timeout > 0,
+            "serverConfig.shutdownTimeout",
+            "must be positive",
+        );
+        if !(protocol::LEGACY_VERSION..=protocol::PROTOCOL_VERSION)
+            .contains(&server.min_protocol_version)
+        {
+            errors.push(
+                "serverConfig.minProtocolVersion",
+                format!(
+                    "must be from {} to {}",
+                    protocol::LEGACY_VERSION,
+                    protocol::PROTOCOL_VERSION
+                ),
+            );
+        }
+
+        let rbatis = &self.rbatis_config;
+        if self.storage_config.backend == StorageBackend::Rbatis
+            && !DB_SCHEMES
+                .iter()
+                .any(|scheme| rbatis.db_url.starts_with(scheme))
+        {
+            errors.push(
+                "rbatisConfig.dbUrl",
+                format!(
+                    "`{}` is neither a postgres:// nor a sqlite: URL",
+                    rbatis.db_url
+                ),
+            );
+        }
+
+        errors.check(
+            self.pipeline_config.batch_size > 0,
+            "pipelineConfig.batchSize",
+            "must be positive",
+        );
+
+        let wal = &self.wal_config;
+        if wal.enable {
+            errors.check(!wal.path.is_empty(), "walConfig.path", "must not be empty");
+            errors.check(
+                wal.segment_size > 0,
+                "walConfig.segmentSize",
+                "must be positive",
+            );
+        }
+
+        errors.check(
+            self.compression_config.max_size > 0,
+            "compressionConfig.maxSize",
+            "must be positive",
+        );
+
+        let frame = &self.frame_config;
+        errors.check(
+            frame.max_message_size > 0,
+            "frameConfig.maxMessageSize",
+            "must be positive",
+        );
+        errors.check(
+            frame.max_file_size > 0,
+            "frameConfig.maxFileSize",
+            "must be positive",
+        );
+        errors.check(
+            frame.max_control_size > 0,
+            "frameConfig.maxControlSize",
+            "must be positive",
+        );
+        errors.check(
+            frame.max_inflight_bytes > 0,
+            "frameConfig.maxInflightBytes",
+            "must be positive",
+        );
+
+        errors.check(
+            self.outbound_config.queue_size > 0,
+            "outboundConfig.queueSize",
+            "must be positive",
+        );
+
+        if let Err(e) = logging::parse_level(&self.logging_config.level) {
+            errors.push("loggingConfig.level", e.to_string());
+        }
+
+        let metrics = &self.metrics_config;
+        if metrics.enable && !is_address(&metrics.url) {
+            errors.push(
+                "metricsConfig.url",
+                format!("`{}` is not an address of the form host:port", metrics.url),
+            );
+        }
+
+        let admin = &self.admin_config;
+        if admin.enable {
+            if !is_address(&admin.url) {
+                errors.push(
+                    "adminConfig.url",
+                    format!("`{}` is not an address of the form host:port", admin.url),
+                );
+            }
+            errors.check(
+                !admin.token.is_empty(),
+                "adminConfig.token",
+                "must not be empty when the admin API is enabled",
+            );
+        }
+
+        if errors.0.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+/// check that an address is `ip:port` or `host:port`
+fn is_address(address: &str) -> bool {
+    if address.parse::<SocketAddr>().is_ok() {
+        return true;
+    }
+
+    match address.rsplit_once(':') {
+        Some((host, port)) => {
+            !host.is_empty()
+                && host
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+                && port.parse::<u16>().is_ok()
+        }
+        None => false,
+    }
+}
+
+/// check that the certificate and the key exist and make a server configuration
+fn validate_tls(cert_path: &str, key_path: &str, errors: &mut ConfigErrors) {
+    let mut found = true;
+    for (path, field) in &[
+        (cert_path, "serverConfig.certPath"),
+        (key_path, "serverConfig.keyPath"),
+    ] {
+        if !Path::new(path).is_file() {
+            errors.push(field, format!("{} does not exist", path));
+            found = false;
+        }
+    }
+
+    if found {
+        if let Err(e) = tls::server_config(cert_path, key_path) {
+            let field = match e.to_string().contains(key_path) {
+                true => "serverConfig.keyPath",
+                false => "serverConfig.certPath",
+            };
+            errors.push(field, e.to_string());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn paths(errors: &ConfigErrors) -> Vec<&str> {
+        errors.0.iter().map(|error| error.path.as_str()).collect()
+    }
+
+    #[test]
+    fn default_test() {
+        assert_eq!(Ok(()), ClushConfig::default().validate());
+    }
+
+    #[test]
+    fn aggregate_test() {
+        let mut config = ClushConfig::default();
+        config.server_config.url = "9527".to_string();
+        config.server_config.enable_tls = true;
+        config.server_config.cert_path = "missing.pem".to_string();
+        config.rbatis_config.db_url = "mongodb://127.0.0.1".to_string();
+        config.pipeline_config.batch_size = 0;
+        config.outbound_config.queue_size = 0;
+        config.logging_config.level = "info,clush_server=verbose".to_string();
+        config.admin_config.enable = true;
+
+        let errors = config.validate().unwrap_err();
+        assert_eq!(
+            vec![
+                "serverConfig.url",
+                "serverConfig.certPath",
+                "serverConfig.keyPath",
+                "rbatisConfig.dbUrl",
+                "pipelineConfig.batchSize",
+                "outboundConfig.queueSize",
+                "loggingConfig.level",
+                "adminConfig.token",
+            ],
+            paths(&errors)
+        );
+        let report = errors.to_string();
+        assert!(report.starts_with("8 invalid configuration field(s)"));
+        assert!(report.contains("\n  loggingConfig.level: invalid log level"));
+
+        // the database URL does not matter without a database
+        config.storage_config.backend = StorageBackend::Memory;
+        assert!(!paths(&config.validate().unwrap_err()).contains(&"rbatisConfig.dbUrl"));
+    }
+
+    #[test]
+    fn address_test() {
+        for address in &[
+            "0.0.0.0:9527",
+            "[::1]:9527",
+            "localhost:9527",
+            "clush.example.com:1",
+        ] {
+            assert!(is_address(address), "{}", address);
+        }
+        for address in &["9527", ":9527", "localhost:", "localhost:70000", "a b:1"] {
+            assert!(!is_address(address), "{}", address);
+        }
+    }
+
+    #[test]
+    fn tls_test() {
+        let (cert_path, key_path) = tls::tests::self_signed();
+        let mut config = ClushConfig::default();
+        config.server_config.enable_tls = true;
+        config.server_config.cert_path = cert_path.clone();
+        config.server_config.key_path = key_path;
+        assert_eq!(Ok(()), config.validate());
+
+        // a certificate is no key
+        config.server_config.key_path = cert_path;
+        assert_eq!(
+            vec!["serverConfig.keyPath"],
+            paths(&config.validate().unwrap_err())
+        );
+    }
+}
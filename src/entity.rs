@@ -1,14 +1,42 @@
 use chrono::{DateTime, Utc};
+use rbatis::core::db::DriverType;
+use rbatis::crud::CRUDTable;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::HashMap;
 
-#[crud_enable(table_name: public."user")]
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct User {
     pub id: Option<u64>,
     pub username: Option<String>,
     pub password: Option<String>, // sha256
+    /// whether the user is refused at login, None counts as enabled
+    #[serde(default, deserialize_with = "bool_or_int")]
+    pub disabled: Option<bool>,
 }
 
-#[crud_enable]
+// "user" is a keyword of SQL, quote it to work on every database
+impl CRUDTable for User {
+    type IdType = u64;
+
+    fn get_id(&self) -> Option<&u64> {
+        self.id.as_ref()
+    }
+
+    fn table_name() -> String {
+        "\"user\"".to_string()
+    }
+
+    fn formats(driver_type: &DriverType) -> HashMap<String, fn(arg: &str) -> String> {
+        let mut formats: HashMap<String, fn(arg: &str) -> String> = HashMap::new();
+        if *driver_type == DriverType::Postgres {
+            formats.insert("disabled".to_string(), |arg| format!("{}::boolean", arg));
+        }
+
+        formats
+    }
+}
+
+#[crud_enable(formats_pg: "date_time:{}::timestamptz,delivered:{}::boolean")]
 #[derive(Clone, Debug)]
 pub struct UserMsg {
     pub id: Option<u64>,
@@ -16,15 +44,47 @@ pub struct UserMsg {
     pub to_id: Option<u64>,
     pub date_time: Option<DateTime<Utc>>,
     pub content: Option<String>,
+    /// whether the message reached its recipient, None counts as delivered
+    #[serde(default, deserialize_with = "bool_or_int")]
+    pub delivered: Option<bool>,
 }
 
-#[crud_enable]
-#[derive(Clone, Debug)]
+/// deserialize a boolean which SQLite returns as an integer
+fn bool_or_int<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<bool>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum BoolOrInt {
+        Bool(bool),
+        Int(i64),
+    }
+
+    Ok(
+        Option::<BoolOrInt>::deserialize(deserializer)?.map(|value| match value {
+            BoolOrInt::Bool(value) => value,
+            BoolOrInt::Int(value) => value != 0,
+        }),
+    )
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Group {
     pub id: Option<u64>,
     pub group_name: Option<String>,
 }
 
+// "group" is a keyword of SQL, quote it to work on every database
+impl CRUDTable for Group {
+    type IdType = u64;
+
+    fn get_id(&self) -> Option<&u64> {
+        self.id.as_ref()
+    }
+
+    fn table_name() -> String {
+        "\"group\"".to_string()
+    }
+}
+
 #[crud_enable]
 #[derive(Clone, Debug)]
 pub struct GroupMember {
@@ -34,7 +94,7 @@ pub struct GroupMember {
     pub role_id: Option<u64>,
 }
 
-#[crud_enable]
+#[crud_enable(formats_pg: "date_time:{}::timestamptz")]
 #[derive(Clone, Debug)]
 pub struct GroupMsg {
     pub id: Option<u64>,
@@ -50,3 +110,36 @@ pub struct Role {
     pub id: Option<u64>,
     pub role_name: Option<String>,
 }
+
+#[crud_enable(id_name: user_id | formats_pg: "login_time:{}::timestamptz")]
+#[derive(Clone, Debug)]
+pub struct Session {
+    pub user_id: Option<u64>,
+    pub address: Option<String>,
+    pub login_time: Option<DateTime<Utc>>,
+}
+
+/// an event of the audit log, see `audit`
+#[crud_enable(formats_pg: "date_time:{}::timestamptz")]
+#[derive(Clone, Debug)]
+pub struct AuditEvent {
+    pub id: Option<u64>,
+    pub date_time: Option<DateTime<Utc>>,
+    /// name of an `AuditKind`
+    pub kind: Option<String>,
+    /// the user who acted, `0` for the server or an administrator, None if unknown
+    pub actor_id: Option<u64>,
+    /// the user or group acted on, if any
+    pub target_id: Option<u64>,
+    /// the address the actor came from, if known
+    pub address: Option<String>,
+    pub detail: Option<String>,
+}
+
+/// when a user was last seen online, see `presence`
+#[crud_enable(id_name: user_id | formats_pg: "date_time:{}::timestamptz")]
+#[derive(Clone, Debug)]
+pub struct LastSeen {
+    pub user_id: Option<u64>,
+    pub date_time: Option<DateTime<Utc>>,
+}
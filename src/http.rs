@@ -0,0 +1,161 @@
+//! HTTP endpoints of a clush server, on an address apart from the one of clients,
+//! the admin API is served the same way on an address of its own
+//!
+//! - `GET /metrics` renders the metrics in the Prometheus text format
+//! - `GET /healthz` answers `200` as long as the process runs
+//! - `GET /readyz` answers `200` while the server accepts connections and reaches its storage,
+//!   `503` with the reason otherwise
+
+use crate::metrics::Metrics;
+use crate::shutdown::Shutdown;
+use crate::storage::Storage;
+use hyper::header::CONTENT_TYPE;
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use std::convert::Infallible;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::time;
+
+/// content type of the Prometheus text format
+static METRICS_CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+/// how long the storage may take to answer a readiness check
+static PING_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// what the endpoints report on
+pub(crate) struct Endpoints {
+    pub(crate) metrics: Arc<Metrics>,
+    pub(crate) db: Arc<dyn Storage>,
+    /// whether the server accepts connections
+    pub(crate) accepting: Arc<AtomicBool>,
+    pub(crate) shutdown: Shutdown,
+}
+
+/// answer the requests of a listener with the given handler until the task is aborted,
+/// which is after the server has drained, so probes see the shutdown
+pub(crate) async fn serve<H, F>(listener: Arc<TcpListener>, handler: H)
+where
+    H: Fn(Request<Body>) -> F + Send + Sync + 'static,
+    F: Future<Output = Response<Body>> + Send + 'static,
+{
+    let handler = Arc::new(handler);
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                tracing::warn!("failed to accept an HTTP connection: {}", e);
+                continue;
+            }
+        };
+
+        let handler = handler.clone();
+        tokio::spawn(async move {
+            let service = service_fn(move |mut request: Request<Body>| {
+                // handlers may want to know who asks
+                request.extensions_mut().insert(addr);
+                let response = handler(request);
+                async move { Ok::<_, Infallible>(response.await) }
+            });
+            if let Err(e) = Http::new()
+                .http1_only(true)
+                .serve_connection(stream, service)
+                .await
+            {
+                tracing::debug!("HTTP connection from {} failed: {}", addr, e);
+            }
+        });
+    }
+}
+
+/// answer a request to one of the endpoints
+pub(crate) async fn handle(endpoints: Arc<Endpoints>, request: Request<Body>) -> Response<Body> {
+    match (request.method(), request.uri().path()) {
+        (&Method::GET, "/metrics") => metrics(&endpoints).await,
+        (&Method::GET, "/healthz") => text(StatusCode::OK, "ok".to_string()),
+        (&Method::GET, "/readyz") => match readiness(&endpoints).await {
+            Ok(()) => text(StatusCode::OK, "ready".to_string()),
+            Err(reason) => text(StatusCode::SERVICE_UNAVAILABLE, reason),
+        },
+        _ => status(StatusCode::NOT_FOUND),
+    }
+}
+
+/// render the metrics, updating those read from the storage
+async fn metrics(endpoints: &Endpoints) -> Response<Body> {
+    match endpoints.db.count_undelivered_user_msgs().await {
+        Ok(backlog) => endpoints.metrics.offline_backlog.set(backlog as i64),
+        Err(e) => tracing::warn!("failed to count undelivered messages: {}", e),
+    }
+
+    Response::builder()
+        .header(CONTENT_TYPE, METRICS_CONTENT_TYPE)
+        .body(Body::from(endpoints.metrics.encode()))
+        .unwrap()
+}
+
+/// check whether the server can serve clients, return why not otherwise
+async fn readiness(endpoints: &Endpoints) -> Result<(), String> {
+    if endpoints.shutdown.is_shutdown() {
+        return Err("shutting down".to_string());
+    }
+    if !endpoints.accepting.load(Ordering::Relaxed) {
+        return Err("not accepting connections".to_string());
+    }
+
+    match time::timeout(PING_TIMEOUT, endpoints.db.ping()).await {
+        Ok(Ok(())) => Ok(()),
+        Ok(Err(e)) => Err(format!("storage unreachable: {}", e)),
+        Err(_) => Err(format!("storage did not answer in {:?}", PING_TIMEOUT)),
+    }
+}
+
+/// a plain text response of the given status
+fn text(status: StatusCode, body: String) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::from(body))
+        .unwrap()
+}
+
+/// an empty response of the given status
+fn status(status: StatusCode) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::empty())
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shutdown;
+    use crate::storage::MemoryStorage;
+
+    #[tokio::test]
+    async fn readiness_test() {
+        let (handle, shutdown) = shutdown::channel();
+        let endpoints = Endpoints {
+            metrics: Arc::new(Metrics::new()),
+            db: Arc::new(MemoryStorage::new()),
+            accepting: Arc::new(AtomicBool::new(false)),
+            shutdown,
+        };
+        assert_eq!(
+            Err("not accepting connections".to_string()),
+            readiness(&endpoints).await
+        );
+
+        endpoints.accepting.store(true, Ordering::Relaxed);
+        assert_eq!(Ok(()), readiness(&endpoints).await);
+
+        handle.shutdown();
+        assert_eq!(
+            Err("shutting down".to_string()),
+            readiness(&endpoints).await
+        );
+    }
+}
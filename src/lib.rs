@@ -0,0 +1,40 @@
+//! # clush-server
+//!
+//! the server of clush, a cross-platform IM software,
+//! as a library to embed the server or to talk its protocol
+//!
+//! MIT License
+//! Copyright (c) 2021 Bruce Kang
+
+#[macro_use]
+extern crate rbatis;
+
+mod admin;
+pub mod audit;
+pub mod client;
+pub mod codec;
+pub mod compression;
+pub mod config;
+pub mod entity;
+mod http;
+pub mod logging;
+pub mod metrics;
+pub mod outbound;
+pub mod pipeline;
+pub mod presence;
+pub mod protocol;
+pub mod reload;
+pub mod server;
+pub mod shutdown;
+pub mod storage;
+pub mod tls;
+pub mod util;
+pub mod wal;
+
+pub use crate::client::ClushClient;
+pub use crate::codec::{ClushFrame, MessageType};
+pub use crate::config::ClushConfig;
+pub use crate::reload::ReloadHandle;
+pub use crate::server::{ClushServer, ServerBuilder};
+pub use crate::shutdown::ShutdownHandle;
+pub use crate::storage::{MemoryStorage, RbatisStorage, Storage};
@@ -0,0 +1,93 @@
+//! logs of the server, as text or JSON lines, filtered by module
+//!
+//! records of the `log` crate, e.g. those of the ORM, are logged too.
+//! every connection has a `connection` span with its address, session id and user,
+//! and every frame a `frame` span with its type, ids and size
+
+use crate::config::{LogFormat, LoggingConfig};
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, reload, EnvFilter, Registry};
+
+/// handle to change the levels of the installed logger
+pub struct LogHandle {
+    filter: reload::Handle<EnvFilter, Registry>,
+}
+
+impl LogHandle {
+    /// replace the levels, e.g. with `info,clush_server::server=debug`
+    pub fn set_level(&self, level: &str) -> io::Result<()> {
+        let filter = parse_level(level)?;
+
+        self.filter.reload(filter).map_err(io::Error::other)
+    }
+}
+
+/// install the logger of the given configuration for the whole process,
+/// fail if a logger is already installed
+pub fn init(config: &LoggingConfig) -> io::Result<LogHandle> {
+    let (filter, handle) = reload::Layer::new(parse_level(&config.level)?);
+    let registry = tracing_subscriber::registry().with(filter);
+
+    // standard output, or a file appended to
+    let writer = match config.path.is_empty() {
+        true => fmt::writer::BoxMakeWriter::new(io::stdout),
+        false => {
+            if let Some(dir) = Path::new(&config.path).parent() {
+                fs::create_dir_all(dir)?;
+            }
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&config.path)?;
+            fmt::writer::BoxMakeWriter::new(Mutex::new(file))
+        }
+    };
+    let ansi = config.path.is_empty();
+
+    let installed = match config.format {
+        LogFormat::Text => registry
+            .with(fmt::layer().with_writer(writer).with_ansi(ansi))
+            .try_init(),
+        LogFormat::Json => registry
+            .with(
+                fmt::layer()
+                    .json()
+                    .with_current_span(true)
+                    .with_span_list(true)
+                    .with_writer(writer),
+            )
+            .try_init(),
+    };
+    installed.map_err(io::Error::other)?;
+
+    Ok(LogHandle { filter: handle })
+}
+
+/// parse levels in the syntax of `level`
+pub(crate) fn parse_level(level: &str) -> io::Result<EnvFilter> {
+    EnvFilter::try_new(level).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid log level `{}`: {}", level, e),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_level_test() {
+        for level in &["info", "warn,clush_server::server=debug", "rbatis=off"] {
+            assert!(parse_level(level).is_ok(), "{}", level);
+        }
+        let e = parse_level("info,clush_server=loud").unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, e.kind());
+    }
+}
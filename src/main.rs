@@ -5,22 +5,155 @@
 //! MIT License
 //! Copyright (c) 2021 Bruce Kang
 
-#[macro_use]
-extern crate rbatis;
-
-pub mod config;
-pub mod entity;
-pub mod util;
-
-mod core;
-
-use crate::config::ClushConfig;
-use crate::core::ClushServer;
+use clush_server::config::{ConfigLoader, StorageBackend};
+use clush_server::logging::{self, LogHandle};
+use clush_server::reload::ReloadHandle;
+use clush_server::{reload, shutdown, ClushConfig, ClushServer, RbatisStorage};
 use tokio::io::Result;
 
+static USAGE: &str =
+    "usage: clush-server [--config <file>] [--set <key>=<value>]... [migrate | check-config]";
+
+/// usage: `clush-server [--config <file>] [--set <key>=<value>]... [migrate | check-config]`
+///
+/// without a subcommand the server is started, and reloads the configuration on SIGHUP,
+/// `migrate` applies pending database migrations and exits,
+/// `check-config` validates the configuration and exits.
+///
+/// the configuration is read from `--config`, `CLUSH_CONFIG` or the first of
+/// `config/clush.{json,toml,yaml,yml}`, then overridden by `CLUSH_` environment variables
+/// and by `--set`
 #[tokio::main]
 async fn main() -> Result<()> {
-    let config = ClushConfig::from_json("config/clush.json").await;
-    let server = ClushServer::init_with_config(config).await?;
-    server.start().await
+    let (loader, command) = match parse_args(std::env::args().skip(1)) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            eprintln!("{}\n{}", e, USAGE);
+            std::process::exit(2);
+        }
+    };
+    let (config, warnings) = match loader.clone().load_with_warnings().await {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            eprintln!("invalid configuration: {}", e);
+            std::process::exit(2);
+        }
+    };
+    // deprecated fields are logged once there is a log, or told at once by the subcommands
+    if command.is_some() {
+        for warning in &warnings {
+            eprintln!("warning: {}", warning);
+        }
+    }
+
+    // every problem is reported before anything starts
+    let validated = config.validate();
+    if command.as_deref() == Some("check-config") {
+        match validated {
+            Ok(()) => println!("configuration is valid"),
+            Err(errors) => {
+                eprintln!("{}", errors);
+                std::process::exit(1);
+            }
+        }
+        return Ok(());
+    }
+    if let Err(errors) = validated {
+        eprintln!("{}", errors);
+        std::process::exit(2);
+    }
+
+    match command.as_deref() {
+        None => {
+            let log = logging::init(&config.logging_config)?;
+            for warning in warnings {
+                tracing::warn!("configuration {}", warning);
+            }
+            let server = ClushServer::init_with_config(config).await?;
+            // shut down gracefully on SIGINT or SIGTERM
+            let handle = server.shutdown_handle();
+            tokio::spawn(async move {
+                shutdown::signal().await;
+                tracing::info!("shutting down");
+                handle.shutdown();
+            });
+            // apply what can change live on SIGHUP
+            let handle = server.reload_handle();
+            tokio::spawn(async move {
+                loop {
+                    reload::signal().await;
+                    tracing::info!("reloading the configuration");
+                    if let Err(e) = reload_config(&loader, &handle, &log).await {
+                        tracing::error!("configuration not reloaded: {}", e);
+                    }
+                }
+            });
+            server.start().await
+        }
+        Some("migrate") => migrate(config).await,
+        Some(command) => {
+            eprintln!("unknown subcommand `{}`\n{}", command, USAGE);
+            std::process::exit(2);
+        }
+    }
+}
+
+/// parse the options into a configuration loader, and the subcommand if any
+fn parse_args<I: Iterator<Item = String>>(
+    mut args: I,
+) -> std::result::Result<(ConfigLoader, Option<String>), String> {
+    let mut loader = ConfigLoader::new();
+    let mut command = None;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--config" | "-c" => {
+                let path = args.next().ok_or("--config expects a file")?;
+                loader = loader.path(path);
+            }
+            "--set" => {
+                let assignment = args.next().ok_or("--set expects key=value")?;
+                loader = loader.set(assignment);
+            }
+            option if option.starts_with('-') => {
+                return Err(format!("unknown option `{}`", option));
+            }
+            _ if command.is_none() => command = Some(arg),
+            _ => return Err(format!("unexpected argument `{}`", arg)),
+        }
+    }
+
+    Ok((loader, command))
+}
+
+/// apply pending database migrations
+async fn migrate(config: ClushConfig) -> Result<()> {
+    if config.storage_config.backend != StorageBackend::Rbatis {
+        println!("storage backend has no schema, nothing to migrate");
+        return Ok(());
+    }
+
+    let db = RbatisStorage::connect(&config.rbatis_config).await?;
+    let applied = db.migrate().await?;
+    if applied.is_empty() {
+        println!("schema is up to date");
+    }
+    for version in applied {
+        println!("applied migration {}", version);
+    }
+
+    Ok(())
+}
+
+/// load the configuration again and apply it to the running server and the logger
+async fn reload_config(
+    loader: &ConfigLoader,
+    handle: &ReloadHandle,
+    log: &LogHandle,
+) -> Result<()> {
+    let config = loader.clone().load().await?;
+    for field in handle.reload(config.clone())? {
+        tracing::warn!("{} changed, restart the server to apply it", field);
+    }
+    log.set_level(&config.logging_config.level)
 }
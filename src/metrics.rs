@@ -0,0 +1,291 @@
+//! metrics of a clush server in the Prometheus text format
+
+use crate::codec::{self, ClushFrame, MessageType};
+use crate::outbound::OutboundStats;
+use crate::server::OutboundMap;
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::MetricFamily;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder,
+};
+use std::sync::Arc;
+
+/// metrics of a server, each server has a registry of its own
+pub struct Metrics {
+    registry: Registry,
+    /// connections accepted, whether they log in or not
+    pub(crate) connections: IntCounter,
+    /// users logged in
+    pub(crate) sessions: IntGauge,
+    /// login attempts by outcome
+    pub(crate) logins: IntCounterVec,
+    pub(crate) frames_received: IntCounterVec,
+    pub(crate) frames_sent: IntCounterVec,
+    pub(crate) bytes_received: IntCounter,
+    pub(crate) bytes_sent: IntCounter,
+    /// latency of storage operations by operation
+    pub(crate) storage_latency: HistogramVec,
+    /// frames waiting in the channel of the message handler
+    pub(crate) handler_queue: IntGauge,
+    /// user messages waiting for their recipient to log in, as of the last scrape
+    pub(crate) offline_backlog: IntGauge,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics::new()
+    }
+}
+
+impl Metrics {
+    /// create the metrics of a server, all zero
+    pub fn new() -> Metrics {
+        let connections =
+            IntCounter::new("clush_connections_accepted_total", "connections accepted").unwrap();
+        let sessions = IntGauge::new("clush_sessions_active", "users logged in").unwrap();
+        let logins = IntCounterVec::new(
+            Opts::new("clush_logins_total", "login attempts by outcome"),
+            &["outcome"],
+        )
+        .unwrap();
+        let frames_received = IntCounterVec::new(
+            Opts::new("clush_frames_received_total", "frames read from clients"),
+            &["msg_type"],
+        )
+        .unwrap();
+        let frames_sent = IntCounterVec::new(
+            Opts::new("clush_frames_sent_total", "frames written to clients"),
+            &["msg_type"],
+        )
+        .unwrap();
+        let bytes_received = IntCounter::new(
+            "clush_received_bytes_total",
+            "bytes of frames read from clients, as sent",
+        )
+        .unwrap();
+        let bytes_sent = IntCounter::new(
+            "clush_sent_bytes_total",
+            "bytes of frames written to clients, as sent",
+        )
+        .unwrap();
+        let storage_latency = HistogramVec::new(
+            HistogramOpts::new(
+                "clush_storage_duration_seconds",
+                "latency of storage operations",
+            ),
+            &["operation"],
+        )
+        .unwrap();
+        let handler_queue =
+            IntGauge::new("clush_handler_queue_depth", "frames waiting to be routed").unwrap();
+        let offline_backlog = IntGauge::new(
+            "clush_offline_backlog",
+            "user messages waiting for their recipient to log in",
+        )
+        .unwrap();
+
+        let registry = Registry::new();
+        let collectors: Vec<Box<dyn Collector>> = vec![
+            Box::new(connections.clone()),
+            Box::new(sessions.clone()),
+            Box::new(logins.clone()),
+            Box::new(frames_received.clone()),
+            Box::new(frames_sent.clone()),
+            Box::new(bytes_received.clone()),
+            Box::new(bytes_sent.clone()),
+            Box::new(storage_latency.clone()),
+            Box::new(handler_queue.clone()),
+            Box::new(offline_backlog.clone()),
+        ];
+        for collector in collectors {
+            registry.register(collector).unwrap();
+        }
+
+        Metrics {
+            registry,
+            connections,
+            sessions,
+            logins,
+            frames_received,
+            frames_sent,
+            bytes_received,
+            bytes_sent,
+            storage_latency,
+            handler_queue,
+            offline_backlog,
+        }
+    }
+
+    /// get the registry, e.g. to register metrics of the embedding application
+    pub fn registry(&self) -> &Registry {
+        &self.registry
+    }
+
+    /// render all metrics in the Prometheus text format
+    pub fn encode(&self) -> String {
+        let mut buffer = vec![];
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .unwrap();
+
+        String::from_utf8(buffer).unwrap()
+    }
+
+    /// count a frame read from a client, with its content as sent
+    pub(crate) fn received(&self, frame: &ClushFrame) {
+        self.frames_received
+            .with_label_values(&[msg_type_label(&frame.msg_type)])
+            .inc();
+        self.bytes_received
+            .inc_by((codec::HEADER_SIZE + frame.content.len()) as u64);
+    }
+
+    /// count a frame written to a client, with its content as sent
+    pub(crate) fn sent(&self, frame: &ClushFrame) {
+        self.frames_sent
+            .with_label_values(&[msg_type_label(&frame.msg_type)])
+            .inc();
+        self.bytes_sent
+            .inc_by((codec::HEADER_SIZE + frame.content.len()) as u64);
+    }
+
+    /// count a login attempt
+    pub(crate) fn login(&self, outcome: &str) {
+        self.logins.with_label_values(&[outcome]).inc();
+    }
+
+    /// export the depths and counters of the outbound queues
+    pub(crate) fn register_outbound(&self, map: Arc<OutboundMap>, stats: Arc<OutboundStats>) {
+        self.registry
+            .register(Box::new(OutboundCollector::new(map, stats)))
+            .unwrap();
+    }
+}
+
+/// label of a message type
+fn msg_type_label(msg_type: &MessageType) -> &'static str {
+    match msg_type {
+        MessageType::Undefined => "undefined",
+        MessageType::LoginMessage => "login",
+        MessageType::UserMessage => "user",
+        MessageType::GroupMessage => "group",
+        MessageType::UserFileMessage => "user_file",
+        MessageType::GroupFileMessage => "group_file",
+        MessageType::AckMessage => "ack",
+        MessageType::ShutdownMessage => "shutdown",
+        MessageType::HelloMessage => "hello",
+        MessageType::PresenceMessage => "presence",
+    }
+}
+
+/// collector reading the outbound queues when scraped
+struct OutboundCollector {
+    map: Arc<OutboundMap>,
+    stats: Arc<OutboundStats>,
+    dropped: IntCounter,
+    spilled: IntCounter,
+    disconnected: IntCounter,
+    depth: IntGauge,
+    max_depth: IntGauge,
+}
+
+impl OutboundCollector {
+    fn new(map: Arc<OutboundMap>, stats: Arc<OutboundStats>) -> OutboundCollector {
+        let counter = |name, help| IntCounter::new(name, help).unwrap();
+        let gauge = |name, help| IntGauge::new(name, help).unwrap();
+
+        OutboundCollector {
+            map,
+            stats,
+            dropped: counter(
+                "clush_outbound_dropped_frames_total",
+                "frames dropped from full queues",
+            ),
+            spilled: counter(
+                "clush_outbound_spilled_frames_total",
+                "user messages left for the next login by full queues",
+            ),
+            disconnected: counter(
+                "clush_outbound_disconnects_total",
+                "connections closed for overflowing their queue",
+            ),
+            depth: gauge(
+                "clush_outbound_queue_depth",
+                "frames waiting in all outbound queues",
+            ),
+            max_depth: gauge(
+                "clush_outbound_queue_depth_max",
+                "frames waiting in the deepest outbound queue",
+            ),
+        }
+    }
+}
+
+impl Collector for OutboundCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        let mut descs = vec![];
+        descs.extend(self.dropped.desc());
+        descs.extend(self.spilled.desc());
+        descs.extend(self.disconnected.desc());
+        descs.extend(self.depth.desc());
+        descs.extend(self.max_depth.desc());
+
+        descs
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        // counters only go up, catch up with the stats
+        let catch_up = |counter: &IntCounter, value: u64| {
+            counter.inc_by(value.saturating_sub(counter.get()));
+        };
+        catch_up(&self.dropped, self.stats.dropped());
+        catch_up(&self.spilled, self.stats.spilled());
+        catch_up(&self.disconnected, self.stats.disconnected());
+
+        let max_depth = self.map.iter().map(|pair| pair.value().len()).max();
+        self.depth.set(self.stats.queued() as i64);
+        self.max_depth.set(max_depth.unwrap_or_default() as i64);
+
+        let mut families = vec![];
+        families.extend(self.dropped.collect());
+        families.extend(self.spilled.collect());
+        families.extend(self.disconnected.collect());
+        families.extend(self.depth.collect());
+        families.extend(self.max_depth.collect());
+
+        families
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::BytesMut;
+
+    #[test]
+    fn encode_test() {
+        let metrics = Metrics::new();
+        let frame = ClushFrame::new(MessageType::UserMessage, 1, 2, 5, BytesMut::from("hello"));
+        metrics.received(&frame);
+        metrics.sent(&frame);
+        metrics.sent(&frame);
+        metrics.login("success");
+
+        let stats = Arc::new(OutboundStats::default());
+        stats.add_dropped();
+        metrics.register_outbound(Arc::new(OutboundMap::new()), stats);
+
+        let text = metrics.encode();
+        for line in &[
+            "clush_frames_received_total{msg_type=\"user\"} 1",
+            "clush_frames_sent_total{msg_type=\"user\"} 2",
+            "clush_received_bytes_total 33",
+            "clush_logins_total{outcome=\"success\"} 1",
+            "clush_outbound_dropped_frames_total 1",
+            "clush_outbound_queue_depth_max 0",
+        ] {
+            assert!(text.contains(line), "{} in\n{}", line, text);
+        }
+    }
+}
@@ -0,0 +1,454 @@
+//! bounded outbound queues of connections
+//!
+//! frames to a connection wait in its queue until its writer task sends them.
+//! a queue holds at most `capacity` frames, and frames routed from other users never
+//! wait for room: once the queue is full they are handled by the `OverflowPolicy`,
+//! so one slow consumer cannot hold up delivery to everyone else.
+//! frames a connection answers to its own client wait for room instead,
+//! which stops reading from a client that does not read its replies
+//!
+//! like an mpsc channel, the queue is closed once every `Outbound` is dropped,
+//! the receiver still gets the frames left in it.
+//! a queue can also be closed on purpose, e.g. to disconnect a user
+//!
+//! frames dropped after they were queued, by the overflow policy or because the
+//! connection is gone, are given back to the `DroppedFrames` of the queue if any,
+//! e.g. for user messages to be stored for the next login
+
+use crate::codec::ClushFrame;
+use crate::config::OverflowPolicy;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{mpsc, watch, Notify};
+
+/// where the frames dropped from outbound queues after they were queued are given back
+pub type DroppedFrames = mpsc::UnboundedSender<ClushFrame>;
+
+/// counters shared by the outbound queues of a server
+#[derive(Debug, Default)]
+pub struct OutboundStats {
+    queued: AtomicUsize,
+    dropped: AtomicU64,
+    spilled: AtomicU64,
+    disconnected: AtomicU64,
+}
+
+impl OutboundStats {
+    /// get the number of frames waiting in all queues
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    /// get the number of frames dropped because a queue was full
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// get the number of messages left for delivery on the next login
+    /// because a queue was full
+    pub fn spilled(&self) -> u64 {
+        self.spilled.load(Ordering::Relaxed)
+    }
+
+    /// get the number of connections closed because their queue was full
+    pub fn disconnected(&self) -> u64 {
+        self.disconnected.load(Ordering::Relaxed)
+    }
+
+    /// count a frame dropped by the caller of `Outbound::offer`
+    pub(crate) fn add_dropped(&self) {
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// count a message spilled by the caller of `Outbound::offer`
+    pub(crate) fn add_spilled(&self) {
+        self.spilled.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// what became of a frame offered to a queue
+#[derive(Debug)]
+pub enum Offer {
+    /// the frame is queued
+    Queued,
+    /// the queue is full or closed, the frame is given back
+    Refused(ClushFrame),
+}
+
+/// sending side of an outbound queue, cheap to clone
+pub struct Outbound {
+    shared: Arc<Shared>,
+}
+
+/// receiving side of an outbound queue
+pub struct OutboundReceiver {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    capacity: usize,
+    policy: OverflowPolicy,
+    stats: Arc<OutboundStats>,
+    dropped: Option<DroppedFrames>,
+    /// notified when a frame is queued or the queue is closed
+    readable: Notify,
+    /// notified when a frame is taken from the queue
+    writable: Notify,
+    /// set to the reason once the connection is to be closed
+    disconnected: watch::Sender<Option<&'static str>>,
+    disconnected_rx: watch::Receiver<Option<&'static str>>,
+}
+
+struct State {
+    frames: VecDeque<ClushFrame>,
+    senders: usize,
+    closed: bool,
+}
+
+/// create an outbound queue of the given capacity,
+/// giving the frames it drops back to `dropped` if any
+pub fn channel(
+    capacity: usize,
+    policy: OverflowPolicy,
+    stats: Arc<OutboundStats>,
+    dropped: Option<DroppedFrames>,
+) -> (Outbound, OutboundReceiver) {
+    let state = State {
+        frames: VecDeque::with_capacity(capacity.min(64)),
+        senders: 1,
+        closed: false,
+    };
+    let (disconnected, disconnected_rx) = watch::channel(None);
+    let shared = Arc::new(Shared {
+        state: Mutex::new(state),
+        capacity: capacity.max(1),
+        policy,
+        stats,
+        dropped,
+        readable: Notify::new(),
+        writable: Notify::new(),
+        disconnected,
+        disconnected_rx,
+    });
+
+    (
+        Outbound {
+            shared: shared.clone(),
+        },
+        OutboundReceiver { shared },
+    )
+}
+
+impl Outbound {
+    /// queue a frame, waiting for room, give it back if the queue is closed
+    pub async fn send(&self, frame: ClushFrame) -> Result<(), ClushFrame> {
+        loop {
+            {
+                let mut state = self.shared.state.lock().unwrap();
+                if state.closed {
+                    return Err(frame);
+                }
+                if state.frames.len() < self.shared.capacity {
+                    self.shared.push(&mut state, frame);
+                    return Ok(());
+                }
+            }
+            self.shared.writable.notified().await;
+        }
+    }
+
+    /// queue a frame without waiting, applying the overflow policy if the queue is full
+    ///
+    /// - `DropOldest` makes room by dropping the oldest frame
+    /// - `Disconnect` closes the queue and the connection, dropping the frames in it
+    /// - `Spill` refuses the frame, for the caller to store or drop it
+    pub fn offer(&self, frame: ClushFrame) -> Offer {
+        let mut state = self.shared.state.lock().unwrap();
+        if state.closed {
+            return Offer::Refused(frame);
+        }
+        if state.frames.len() < self.shared.capacity {
+            self.shared.push(&mut state, frame);
+            return Offer::Queued;
+        }
+
+        match self.shared.policy {
+            OverflowPolicy::DropOldest => {
+                if let Some(oldest) = state.frames.pop_front() {
+                    self.shared.give_back(oldest);
+                }
+                self.shared.stats.add_dropped();
+                state.frames.push_back(frame);
+                Offer::Queued
+            }
+            OverflowPolicy::Disconnect => {
+                self.shared.disconnect(&mut state);
+                Offer::Refused(frame)
+            }
+            OverflowPolicy::Spill => Offer::Refused(frame),
+        }
+    }
+
+    /// get the number of frames waiting in the queue
+    pub fn len(&self) -> usize {
+        self.shared.state.lock().unwrap().frames.len()
+    }
+
+    /// check whether the queue is empty
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// close the queue, the frames in it are still written before the connection is closed
+    pub fn close(&self, reason: &'static str) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.closed = true;
+        self.shared.readable.notify_one();
+        self.shared.writable.notify_one();
+        let _ = self.shared.disconnected.send(Some(reason));
+    }
+
+    /// check whether both ends send to the same queue
+    pub fn same_queue(&self, other: &Outbound) -> bool {
+        Arc::ptr_eq(&self.shared, &other.shared)
+    }
+
+    /// wait until the connection is to be closed, e.g. for being too slow, return why
+    pub async fn disconnected(&self) -> &'static str {
+        let mut rx = self.shared.disconnected_rx.clone();
+        loop {
+            if let Some(reason) = *rx.borrow() {
+                return reason;
+            }
+            if rx.changed().await.is_err() {
+                std::future::pending::<()>().await;
+            }
+        }
+    }
+}
+
+impl Clone for Outbound {
+    fn clone(&self) -> Self {
+        self.shared.state.lock().unwrap().senders += 1;
+
+        Outbound {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl Drop for Outbound {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.senders -= 1;
+        if state.senders == 0 {
+            state.closed = true;
+            self.shared.readable.notify_one();
+        }
+    }
+}
+
+impl OutboundReceiver {
+    /// take the next frame, None once the queue is closed and empty
+    pub async fn recv(&mut self) -> Option<ClushFrame> {
+        loop {
+            {
+                let mut state = self.shared.state.lock().unwrap();
+                if let Some(frame) = state.frames.pop_front() {
+                    self.shared.stats.queued.fetch_sub(1, Ordering::Relaxed);
+                    self.shared.writable.notify_one();
+                    return Some(frame);
+                }
+                if state.closed {
+                    return None;
+                }
+            }
+            self.shared.readable.notified().await;
+        }
+    }
+
+    /// give back a frame taken from the queue but not written
+    pub fn give_back(&self, frame: ClushFrame) {
+        self.shared.give_back(frame);
+    }
+}
+
+impl Drop for OutboundReceiver {
+    fn drop(&mut self) {
+        // nothing will be written anymore
+        let mut state = self.shared.state.lock().unwrap();
+        state.closed = true;
+        let left = state.frames.len();
+        for frame in state.frames.drain(..) {
+            self.shared.give_back(frame);
+        }
+        self.shared.stats.queued.fetch_sub(left, Ordering::Relaxed);
+        self.shared.writable.notify_one();
+    }
+}
+
+impl Shared {
+    /// give a dropped frame back, if anyone takes it
+    fn give_back(&self, frame: ClushFrame) {
+        if let Some(dropped) = &self.dropped {
+            let _ = dropped.send(frame);
+        }
+    }
+
+    fn push(&self, state: &mut State, frame: ClushFrame) {
+        state.frames.push_back(frame);
+        self.stats.queued.fetch_add(1, Ordering::Relaxed);
+        self.readable.notify_one();
+    }
+
+    /// close the queue, dropping the frames left in it
+    fn disconnect(&self, state: &mut State) {
+        state.closed = true;
+        let left = state.frames.len();
+        for frame in state.frames.drain(..) {
+            self.give_back(frame);
+        }
+        self.stats.queued.fetch_sub(left, Ordering::Relaxed);
+        self.stats.dropped.fetch_add(left as u64, Ordering::Relaxed);
+        self.stats.disconnected.fetch_add(1, Ordering::Relaxed);
+        self.readable.notify_one();
+        self.writable.notify_one();
+        let _ = self.disconnected.send(Some("outbound queue overflowed"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::MessageType;
+    use bytes::BytesMut;
+    use std::time::Duration;
+    use tokio::time;
+
+    fn frame(to_id: u64) -> ClushFrame {
+        ClushFrame::new(MessageType::UserMessage, 1, to_id, 0, BytesMut::new())
+    }
+
+    #[tokio::test]
+    async fn send_test() {
+        let stats = Arc::new(OutboundStats::default());
+        let (tx, mut rx) = channel(2, OverflowPolicy::Spill, stats.clone(), None);
+
+        tx.send(frame(1)).await.unwrap();
+        tx.send(frame(2)).await.unwrap();
+        assert_eq!(2, stats.queued());
+
+        // send waits for room
+        let waiting = time::timeout(Duration::from_millis(50), tx.send(frame(3))).await;
+        assert!(waiting.is_err());
+        let waiting = {
+            let tx = tx.clone();
+            tokio::spawn(async move { tx.send(frame(3)).await })
+        };
+        assert_eq!(1, rx.recv().await.unwrap().to_id);
+        waiting.await.unwrap().unwrap();
+
+        // the frames left are received after the last sender is gone
+        drop(tx);
+        assert_eq!(2, rx.recv().await.unwrap().to_id);
+        assert_eq!(3, rx.recv().await.unwrap().to_id);
+        assert!(rx.recv().await.is_none());
+        assert_eq!(0, stats.queued());
+    }
+
+    #[tokio::test]
+    async fn overflow_test() {
+        // the oldest frame makes room
+        let stats = Arc::new(OutboundStats::default());
+        let (tx, mut rx) = channel(2, OverflowPolicy::DropOldest, stats.clone(), None);
+        for to_id in 1..=3 {
+            assert!(matches!(tx.offer(frame(to_id)), Offer::Queued));
+        }
+        assert_eq!(2, tx.len());
+        assert_eq!(1, stats.dropped());
+        assert_eq!(2, rx.recv().await.unwrap().to_id);
+
+        // the frame is given back
+        let stats = Arc::new(OutboundStats::default());
+        let (tx, _rx) = channel(1, OverflowPolicy::Spill, stats.clone(), None);
+        assert!(matches!(tx.offer(frame(1)), Offer::Queued));
+        assert!(matches!(tx.offer(frame(2)), Offer::Refused(frame) if frame.to_id == 2));
+        assert_eq!(1, tx.len());
+
+        // the queue is closed
+        let stats = Arc::new(OutboundStats::default());
+        let (tx, mut rx) = channel(1, OverflowPolicy::Disconnect, stats.clone(), None);
+        assert!(matches!(tx.offer(frame(1)), Offer::Queued));
+        assert!(matches!(tx.offer(frame(2)), Offer::Refused(_)));
+        time::timeout(Duration::from_secs(1), tx.disconnected())
+            .await
+            .unwrap();
+        assert!(rx.recv().await.is_none());
+        assert!(tx.send(frame(3)).await.is_err());
+        assert_eq!(
+            (1, 1, 0),
+            (stats.dropped(), stats.disconnected(), stats.queued())
+        );
+    }
+
+    #[tokio::test]
+    async fn give_back_test() {
+        let stats = Arc::new(OutboundStats::default());
+        let (dropped, mut given_back) = mpsc::unbounded_channel();
+
+        // the oldest frame, evicted
+        let (tx, rx) = channel(
+            1,
+            OverflowPolicy::DropOldest,
+            stats.clone(),
+            Some(dropped.clone()),
+        );
+        tx.offer(frame(1));
+        tx.offer(frame(2));
+        assert_eq!(1, given_back.recv().await.unwrap().to_id);
+
+        // the frames left once the connection is gone
+        drop(rx);
+        assert_eq!(2, given_back.recv().await.unwrap().to_id);
+
+        // the frames of a queue closed for overflowing
+        let (tx, _rx) = channel(
+            1,
+            OverflowPolicy::Disconnect,
+            stats.clone(),
+            Some(dropped.clone()),
+        );
+        tx.offer(frame(3));
+        tx.offer(frame(4));
+        assert_eq!(3, given_back.recv().await.unwrap().to_id);
+
+        // a frame taken but not written
+        let (tx, mut rx) = channel(1, OverflowPolicy::Spill, stats.clone(), Some(dropped));
+        tx.offer(frame(5));
+        let frame = rx.recv().await.unwrap();
+        rx.give_back(frame);
+        assert_eq!(5, given_back.recv().await.unwrap().to_id);
+
+        // frames refused are the caller's
+        let more = time::timeout(Duration::from_millis(10), given_back.recv()).await;
+        assert!(more.is_err());
+        assert_eq!(0, stats.queued());
+    }
+
+    #[tokio::test]
+    async fn close_test() {
+        let stats = Arc::new(OutboundStats::default());
+        let (tx, mut rx) = channel(2, OverflowPolicy::Spill, stats.clone(), None);
+        assert!(matches!(tx.offer(frame(1)), Offer::Queued));
+        tx.close("kicked");
+
+        // the frames queued before are still received
+        assert_eq!("kicked", tx.disconnected().await);
+        assert!(matches!(tx.offer(frame(2)), Offer::Refused(_)));
+        assert_eq!(1, rx.recv().await.unwrap().to_id);
+        assert!(rx.recv().await.is_none());
+        assert_eq!((0, 0), (stats.dropped(), stats.disconnected()));
+    }
+}
@@ -0,0 +1,367 @@
+//! batched, asynchronous persistence of messages
+//!
+//! user and group messages from every connection are queued to one writer task,
+//! which saves them with multi-row inserts of up to `batch_size` rows.
+//! a message waits at most `flush_interval` for its batch to fill,
+//! and its sender is notified once the batch is written.
+//!
+//! with the write-ahead log enabled, the batches are appended to the log instead,
+//! with one sync each, a message is durable once its batch is in the log,
+//! and the replayer of the log writes them to the storage.
+
+use crate::config::{PipelineConfig, WalConfig};
+use crate::entity::{GroupMsg, UserMsg};
+use crate::storage::{Result, Storage, StorageError};
+use crate::wal::WriteAheadLog;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{self, Instant};
+
+/// a message to persist
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Record {
+    User(UserMsg),
+    Group(GroupMsg),
+}
+
+impl Record {
+    /// save the record alone
+    pub(crate) async fn save(&self, db: &dyn Storage) -> Result<()> {
+        match self {
+            Record::User(msg) => db.save_user_msg(msg).await,
+            Record::Group(msg) => db.save_group_msg(msg).await,
+        }
+    }
+}
+
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Record::User(msg) => write!(
+                f,
+                "message from {} to user {}",
+                msg.from_id.unwrap_or_default(),
+                msg.to_id.unwrap_or_default()
+            ),
+            Record::Group(msg) => write!(
+                f,
+                "message from {} to group {}",
+                msg.user_id.unwrap_or_default(),
+                msg.group_id.unwrap_or_default()
+            ),
+        }
+    }
+}
+
+/// save records with one multi-row insert for each kind of message
+pub(crate) async fn save_records(db: &dyn Storage, records: &[Record]) -> Result<()> {
+    let mut user_msgs = vec![];
+    let mut group_msgs = vec![];
+    for record in records {
+        match record {
+            Record::User(msg) => user_msgs.push(msg.clone()),
+            Record::Group(msg) => group_msgs.push(msg.clone()),
+        }
+    }
+
+    if !user_msgs.is_empty() {
+        db.save_user_msgs(&user_msgs).await?;
+    }
+    if !group_msgs.is_empty() {
+        db.save_group_msgs(&group_msgs).await?;
+    }
+
+    Ok(())
+}
+
+/// a request to the writer task
+enum Request {
+    /// a message to persist, with the channel to notify its sender
+    Persist(Record, oneshot::Sender<Result<()>>),
+    /// write the messages queued before at once
+    Flush(oneshot::Sender<()>),
+}
+
+/// where the writer task writes the batches to
+enum Sink {
+    /// the storage itself
+    Storage(Arc<dyn Storage>),
+    /// the write-ahead log, replayed to the storage
+    Log(Arc<WriteAheadLog>),
+}
+
+impl Sink {
+    /// write a batch of records at once
+    async fn write(&self, records: &[Record]) -> Result<()> {
+        match self {
+            Sink::Storage(db) => save_records(db.as_ref(), records).await,
+            Sink::Log(wal) => wal
+                .append_all(records)
+                .await
+                .map_err(|e| StorageError::Backend(e.to_string())),
+        }
+    }
+}
+
+/// handle of the persistence pipeline, cheap to clone
+#[derive(Clone)]
+pub struct Pipeline {
+    tx: mpsc::Sender<Request>,
+    /// the write-ahead log the batches go to, if enabled
+    wal: Option<Arc<WriteAheadLog>>,
+}
+
+impl Pipeline {
+    /// spawn the writer task of a pipeline saving to the given storage
+    pub fn spawn(db: Arc<dyn Storage>, config: &PipelineConfig) -> Pipeline {
+        Pipeline::spawn_writer(Sink::Storage(db), config, None)
+    }
+
+    /// spawn a pipeline going through the write-ahead log if it is enabled,
+    /// messages left in the log by a former run are replayed first
+    pub async fn spawn_with_wal(
+        db: Arc<dyn Storage>,
+        config: &PipelineConfig,
+        wal_config: &WalConfig,
+    ) -> io::Result<Pipeline> {
+        if !wal_config.enable {
+            return Ok(Pipeline::spawn(db, config));
+        }
+
+        let wal = Arc::new(WriteAheadLog::open(wal_config).await?);
+        tokio::spawn(wal.clone().replay(db, config.batch_size.max(1)));
+
+        Ok(Pipeline::spawn_writer(
+            Sink::Log(wal.clone()),
+            config,
+            Some(wal),
+        ))
+    }
+
+    /// spawn the writer task writing batches to the given sink
+    fn spawn_writer(
+        sink: Sink,
+        config: &PipelineConfig,
+        wal: Option<Arc<WriteAheadLog>>,
+    ) -> Pipeline {
+        let batch_size = config.batch_size.max(1);
+        let flush_interval = Duration::from_millis(config.flush_interval);
+        let (tx, rx) = mpsc::channel(batch_size * 4);
+
+        tokio::spawn(write_batches(sink, rx, batch_size, flush_interval));
+
+        Pipeline { tx, wal }
+    }
+
+    /// queue a message, return once it is durable
+    pub async fn persist(&self, record: Record) -> Result<()> {
+        let (done_tx, done_rx) = oneshot::channel();
+        if self
+            .tx
+            .send(Request::Persist(record, done_tx))
+            .await
+            .is_err()
+        {
+            return Err(StorageError::Backend("pipeline is closed".to_string()));
+        }
+
+        match done_rx.await {
+            Ok(result) => result,
+            Err(_) => Err(StorageError::Backend("pipeline is closed".to_string())),
+        }
+    }
+
+    /// return once every message queued before is written,
+    /// to the write-ahead log if enabled
+    pub async fn flush(&self) {
+        let (done_tx, done_rx) = oneshot::channel();
+        if self.tx.send(Request::Flush(done_tx)).await.is_ok() {
+            let _ = done_rx.await;
+        }
+    }
+
+    /// return once every message queued before is saved to the storage,
+    /// including those still to be replayed from the write-ahead log
+    pub async fn wait_saved(&self) {
+        self.flush().await;
+        if let Some(wal) = &self.wal {
+            wal.wait_replayed().await;
+        }
+    }
+}
+
+/// collect messages into batches and write them until all handles are dropped
+async fn write_batches(
+    sink: Sink,
+    mut rx: mpsc::Receiver<Request>,
+    batch_size: usize,
+    flush_interval: Duration,
+) {
+    // wait for the first request of a batch
+    while let Some(first) = rx.recv().await {
+        let mut batch = vec![];
+        let mut flushes = vec![];
+        let mut next = Some(first);
+        let deadline = Instant::now() + flush_interval;
+
+        // fill the batch until it is full, flushed or the deadline is reached
+        while let Some(request) = next.take() {
+            match request {
+                Request::Persist(record, sender) => batch.push((record, sender)),
+                Request::Flush(sender) => {
+                    flushes.push(sender);
+                    break;
+                }
+            }
+            if batch.len() >= batch_size {
+                break;
+            }
+            if let Ok(request) = time::timeout_at(deadline, rx.recv()).await {
+                next = request;
+            }
+        }
+
+        if !batch.is_empty() {
+            write_batch(&sink, batch).await;
+        }
+        for sender in flushes {
+            let _ = sender.send(());
+        }
+    }
+}
+
+/// write a batch and notify the sender of every message
+async fn write_batch(sink: &Sink, batch: Vec<(Record, oneshot::Sender<Result<()>>)>) {
+    let (records, senders): (Vec<_>, Vec<_>) = batch.into_iter().unzip();
+    let result = sink.write(&records).await;
+
+    // notify every sender, they may have gone away meanwhile
+    for sender in senders {
+        let result = match &result {
+            Ok(()) => Ok(()),
+            Err(e) => Err(StorageError::Backend(e.to_string())),
+        };
+        let _ = sender.send(result);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{MemoryStorage, MessageRepository};
+
+    fn user_msg(from_id: u64) -> Record {
+        Record::User(UserMsg {
+            id: None,
+            from_id: Some(from_id),
+            to_id: Some(0),
+            date_time: Some(chrono::Utc::now()),
+            content: Some("hello".to_string()),
+            delivered: Some(true),
+        })
+    }
+
+    #[tokio::test]
+    async fn persist_test() {
+        let config = PipelineConfig {
+            batch_size: 4,
+            flush_interval: 1000,
+        };
+        let pipeline = Pipeline::spawn(Arc::new(MemoryStorage::new()), &config);
+
+        // a full batch is written without waiting for the interval
+        let started = Instant::now();
+        let tasks: Vec<_> = (0..4)
+            .map(|i| {
+                let pipeline = pipeline.clone();
+                tokio::spawn(async move { pipeline.persist(user_msg(i)).await })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap().unwrap();
+        }
+        assert!(started.elapsed() < Duration::from_millis(1000));
+    }
+
+    #[tokio::test]
+    async fn flush_test() {
+        let config = PipelineConfig {
+            batch_size: 64,
+            flush_interval: 60_000,
+        };
+        let pipeline = Pipeline::spawn(Arc::new(MemoryStorage::new()), &config);
+
+        // a flush writes the pending batch at once
+        let started = Instant::now();
+        let persisting = {
+            let pipeline = pipeline.clone();
+            tokio::spawn(async move { pipeline.persist(user_msg(1)).await })
+        };
+        time::sleep(Duration::from_millis(10)).await;
+        pipeline.flush().await;
+        persisting.await.unwrap().unwrap();
+        assert!(started.elapsed() < Duration::from_secs(60));
+    }
+
+    #[tokio::test]
+    async fn wal_batch_test() {
+        let config = PipelineConfig {
+            batch_size: 4,
+            flush_interval: 60_000,
+        };
+        let dir = std::env::temp_dir().join(format!(
+            "clush-pipeline-{}",
+            chrono::Utc::now().timestamp_nanos()
+        ));
+        let wal_config = WalConfig {
+            enable: true,
+            path: dir.to_str().unwrap().to_string(),
+            ..WalConfig::default()
+        };
+        let db = Arc::new(MemoryStorage::new());
+        let pipeline = Pipeline::spawn_with_wal(db.clone(), &config, &wal_config)
+            .await
+            .unwrap();
+
+        // messages wait for their batch, then are logged together
+        let persist = |i| {
+            let pipeline = pipeline.clone();
+            tokio::spawn(async move { pipeline.persist(user_msg(i)).await })
+        };
+        let mut first: Vec<_> = (0..3).map(persist).collect();
+        let waiting = time::timeout(Duration::from_millis(50), &mut first[0]).await;
+        assert!(waiting.is_err());
+        let last = persist(3);
+        for task in first.into_iter().chain(Some(last)) {
+            task.await.unwrap().unwrap();
+        }
+
+        // and replayed to the storage
+        time::timeout(Duration::from_secs(5), pipeline.wait_saved())
+            .await
+            .unwrap();
+        let later = chrono::Utc::now() + chrono::Duration::hours(1);
+        assert_eq!(4, db.purge_msgs(later).await.unwrap());
+
+        tokio::fs::remove_dir_all(dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn flush_interval_test() {
+        let config = PipelineConfig {
+            batch_size: 64,
+            flush_interval: 20,
+        };
+        let pipeline = Pipeline::spawn(Arc::new(MemoryStorage::new()), &config);
+
+        // a lone message is written once the interval elapses
+        let started = Instant::now();
+        pipeline.persist(user_msg(1)).await.unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(20));
+    }
+}
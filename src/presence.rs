@@ -0,0 +1,323 @@
+//! presence of users: online, away, do not disturb or offline since a time
+//!
+//! the status of online users is kept in memory, the time a user was last seen
+//! is stored once they log out.
+//! presence is told with presence frames, from the user it is about to the user told,
+//! and only to clients which negotiated `Capabilities::PRESENCE`.
+//! the content of a frame is the name of the status, followed by the RFC 3339 time
+//! the user was last seen if offline, e.g. `offline 2021-06-01T12:00:00+00:00`.
+//!
+//! a client sets its own status with a presence frame of `online`, `away` or `dnd`,
+//! and watches a group peer with `subscribe` to the id of the user,
+//! answered by the current presence of the user. `unsubscribe` stops watching.
+//! members of a common group are told about each other without subscribing,
+//! users who share no group cannot watch each other.
+//!
+//! a user may have several connections, they are online until the last one closes.
+
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::str::FromStr;
+
+/// status of a user
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Status {
+    Online,
+    Away,
+    DoNotDisturb,
+    Offline,
+}
+
+static STATUSES: &[(Status, &str)] = &[
+    (Status::Online, "online"),
+    (Status::Away, "away"),
+    (Status::DoNotDisturb, "dnd"),
+    (Status::Offline, "offline"),
+];
+
+impl Status {
+    /// name of the status, as sent in presence frames
+    pub fn as_str(&self) -> &'static str {
+        STATUSES
+            .iter()
+            .find(|(status, _)| status == self)
+            .map(|(_, name)| *name)
+            .unwrap()
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Status {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        STATUSES
+            .iter()
+            .find(|(_, known)| *known == name)
+            .map(|(status, _)| *status)
+            .ok_or_else(|| format!("unknown status `{}`", name))
+    }
+}
+
+/// presence of a user, as told in a presence frame
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Presence {
+    pub status: Status,
+    /// when an offline user was last seen, None if online or never seen
+    pub last_seen: Option<DateTime<Utc>>,
+}
+
+impl Presence {
+    /// presence of an online user
+    pub fn online(status: Status) -> Presence {
+        Presence {
+            status,
+            last_seen: None,
+        }
+    }
+
+    /// presence of an offline user, last seen at the given time if ever
+    pub fn offline(last_seen: Option<DateTime<Utc>>) -> Presence {
+        Presence {
+            status: Status::Offline,
+            last_seen,
+        }
+    }
+
+    /// content of a presence frame
+    pub fn to_content(&self) -> String {
+        match self.last_seen {
+            Some(last_seen) => format!("{} {}", self.status, last_seen.to_rfc3339()),
+            None => self.status.to_string(),
+        }
+    }
+
+    /// read the content of a presence frame
+    pub fn from_content(content: &str) -> Result<Presence, String> {
+        let mut parts = content.splitn(2, ' ');
+        let status = parts.next().unwrap_or_default().parse()?;
+        let last_seen = parts
+            .next()
+            .map(|time| DateTime::parse_from_rfc3339(time).map(|time| time.with_timezone(&Utc)))
+            .transpose()
+            .map_err(|e| format!("invalid last seen time: {}", e))?;
+
+        Ok(Presence { status, last_seen })
+    }
+}
+
+/// status of an online user
+struct Online {
+    status: Status,
+    /// open connections of the user
+    connections: usize,
+    /// connections which negotiated presence, and are told about others
+    listening: usize,
+}
+
+/// presence of the online users and who subscribed to whom, shared by all connections
+#[derive(Default)]
+pub struct PresenceHub {
+    online: DashMap<u64, Online>,
+    /// subscribers of each user, with the number of their connections subscribed
+    subscribers: DashMap<u64, HashMap<u64, usize>>,
+}
+
+impl PresenceHub {
+    /// create a hub without any user online
+    pub fn new() -> PresenceHub {
+        PresenceHub::default()
+    }
+
+    /// add a connection of a user, marking them online unless they already are,
+    /// return true if it is their first connection
+    pub fn login(&self, user_id: u64, listening: bool) -> bool {
+        let mut online = self.online.entry(user_id).or_insert(Online {
+            status: Status::Online,
+            connections: 0,
+            listening: 0,
+        });
+        online.connections += 1;
+        if listening {
+            online.listening += 1;
+        }
+
+        online.connections == 1
+    }
+
+    /// remove a connection of a user, dropping its subscriptions to the given users,
+    /// the user is marked offline with their last connection, return true if it was
+    pub fn logout(&self, user_id: u64, listening: bool, subscriptions: &HashSet<u64>) -> bool {
+        for watched in subscriptions {
+            self.unsubscribe(user_id, *watched);
+        }
+        if let Some(mut online) = self.online.get_mut(&user_id) {
+            online.connections -= 1;
+            if listening {
+                online.listening -= 1;
+            }
+        }
+
+        self.online
+            .remove_if(&user_id, |_, online| online.connections == 0)
+            .is_some()
+    }
+
+    /// set the status of an online user, return false if the user is offline
+    pub fn set_status(&self, user_id: u64, status: Status) -> bool {
+        match self.online.get_mut(&user_id) {
+            Some(mut online) => {
+                online.status = status;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// get the status of a user, None if offline
+    pub fn status(&self, user_id: u64) -> Option<Status> {
+        self.online.get(&user_id).map(|online| online.status)
+    }
+
+    /// check whether a user is online and told about others
+    pub fn is_listening(&self, user_id: u64) -> bool {
+        self.online
+            .get(&user_id)
+            .is_some_and(|online| online.listening > 0)
+    }
+
+    /// let a connection of `subscriber` watch `user_id`
+    pub fn subscribe(&self, subscriber: u64, user_id: u64) {
+        *self
+            .subscribers
+            .entry(user_id)
+            .or_default()
+            .entry(subscriber)
+            .or_default() += 1;
+    }
+
+    /// stop a connection of `subscriber` watching `user_id`,
+    /// `subscriber` watches until none of their connections does
+    pub fn unsubscribe(&self, subscriber: u64, user_id: u64) {
+        let empty = match self.subscribers.get_mut(&user_id) {
+            Some(mut subscribers) => {
+                if let Some(count) = subscribers.get_mut(&subscriber) {
+                    *count -= 1;
+                    if *count == 0 {
+                        subscribers.remove(&subscriber);
+                    }
+                }
+                subscribers.is_empty()
+            }
+            None => false,
+        };
+        if empty {
+            self.subscribers
+                .remove_if(&user_id, |_, subscribers| subscribers.is_empty());
+        }
+    }
+
+    /// get the subscribers of a user
+    pub fn subscribers(&self, user_id: u64) -> Vec<u64> {
+        self.subscribers
+            .get(&user_id)
+            .map(|subscribers| subscribers.keys().copied().collect())
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn content_test() {
+        for (status, name) in STATUSES {
+            assert_eq!(*name, status.to_string());
+            assert_eq!(Ok(*status), name.parse());
+        }
+        assert!("busy".parse::<Status>().is_err());
+
+        let away = Presence::online(Status::Away);
+        assert_eq!("away", away.to_content());
+        assert_eq!(Ok(away), Presence::from_content("away"));
+
+        let last_seen = DateTime::parse_from_rfc3339("2021-06-01T12:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let offline = Presence::offline(Some(last_seen));
+        assert_eq!("offline 2021-06-01T12:00:00+00:00", offline.to_content());
+        assert_eq!(Ok(offline), Presence::from_content(&offline.to_content()));
+        assert_eq!("offline", Presence::offline(None).to_content());
+
+        assert!(Presence::from_content("offline yesterday").is_err());
+        assert!(Presence::from_content("").is_err());
+    }
+
+    #[test]
+    fn hub_test() {
+        let hub = PresenceHub::new();
+        assert!(hub.login(1, true));
+        assert!(hub.login(2, false));
+        assert_eq!(Some(Status::Online), hub.status(1));
+        assert_eq!(None, hub.status(3));
+        assert!(hub.is_listening(1));
+        assert!(!hub.is_listening(2));
+
+        assert!(hub.set_status(2, Status::DoNotDisturb));
+        assert_eq!(Some(Status::DoNotDisturb), hub.status(2));
+        assert!(!hub.set_status(3, Status::Away));
+
+        hub.subscribe(1, 3);
+        hub.subscribe(2, 3);
+        let mut subscribers = hub.subscribers(3);
+        subscribers.sort_unstable();
+        assert_eq!(vec![1, 2], subscribers);
+        hub.unsubscribe(2, 3);
+        assert_eq!(vec![1], hub.subscribers(3));
+
+        // subscriptions end with the session
+        assert!(hub.logout(1, true, &[3].iter().copied().collect()));
+        assert_eq!(None, hub.status(1));
+        assert!(!hub.is_listening(1));
+        assert!(hub.subscribers(3).is_empty());
+    }
+
+    #[test]
+    fn connections_test() {
+        let hub = PresenceHub::new();
+        assert!(hub.login(1, false));
+        assert!(hub.set_status(1, Status::Away));
+        // a second connection keeps the status, and may listen on its own
+        assert!(!hub.login(1, true));
+        assert_eq!(Some(Status::Away), hub.status(1));
+        assert!(hub.is_listening(1));
+
+        // both connections watch user 3, the second one user 4 too
+        hub.subscribe(1, 3);
+        hub.subscribe(1, 3);
+        hub.subscribe(1, 4);
+
+        // the user stays online while a connection is open
+        assert!(!hub.logout(1, true, &[3, 4].iter().copied().collect()));
+        assert_eq!(Some(Status::Away), hub.status(1));
+        assert!(!hub.is_listening(1));
+        assert_eq!(vec![1], hub.subscribers(3));
+        assert!(hub.subscribers(4).is_empty());
+
+        assert!(hub.logout(1, false, &[3].iter().copied().collect()));
+        assert_eq!(None, hub.status(1));
+        assert!(hub.subscribers(3).is_empty());
+
+        // a new connection is online again
+        assert!(hub.login(1, false));
+        assert_eq!(Some(Status::Online), hub.status(1));
+    }
+}
@@ -0,0 +1,176 @@
+//! version and capability negotiation of the clush protocol
+//!
+//! a client opens the connection with a hello frame stating its protocol version
+//! in `from_id` and the capabilities it supports as bit flags in `to_id`.
+//! the server answers with a hello of the version both speak and the capabilities
+//! both support, or with an error if the version is not supported.
+//!
+//! clients which log in without a hello speak `LEGACY_VERSION` and get no capabilities
+
+use crate::codec::{ClushFrame, MessageType};
+use bytes::BytesMut;
+use std::ops::{BitAnd, BitOr};
+
+/// version of clients sending no hello
+pub const LEGACY_VERSION: u32 = 1;
+/// newest version of the protocol
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// optional features of the protocol, as bit flags
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Capabilities(pub u64);
+
+impl Capabilities {
+    /// no optional feature
+    pub const NONE: Capabilities = Capabilities(0);
+    /// messages are acknowledged once they are durable
+    pub const ACKS: Capabilities = Capabilities(1);
+    /// the content of frames may be compressed
+    pub const COMPRESSION: Capabilities = Capabilities(1 << 1);
+    /// interrupted file transfers may be resumed
+    pub const FILE_RESUME: Capabilities = Capabilities(1 << 2);
+    /// the presence of other users is pushed, see `presence`
+    pub const PRESENCE: Capabilities = Capabilities(1 << 3);
+
+    /// check whether all the given capabilities are set
+    pub fn contains(self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitAnd for Capabilities {
+    type Output = Capabilities;
+
+    fn bitand(self, rhs: Capabilities) -> Capabilities {
+        Capabilities(self.0 & rhs.0)
+    }
+}
+
+impl BitOr for Capabilities {
+    type Output = Capabilities;
+
+    fn bitor(self, rhs: Capabilities) -> Capabilities {
+        Capabilities(self.0 | rhs.0)
+    }
+}
+
+/// content of a hello frame
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Hello {
+    pub version: u32,
+    pub capabilities: Capabilities,
+}
+
+impl Hello {
+    /// create a hello of the newest version
+    pub fn new(capabilities: Capabilities) -> Hello {
+        Hello {
+            version: PROTOCOL_VERSION,
+            capabilities,
+        }
+    }
+
+    /// read a hello frame, None if the frame is not a valid hello
+    pub fn from_frame(frame: &ClushFrame) -> Option<Hello> {
+        if frame.msg_type != MessageType::HelloMessage || frame.from_id > u32::MAX as u64 {
+            return None;
+        }
+
+        Some(Hello {
+            version: frame.from_id as u32,
+            capabilities: Capabilities(frame.to_id),
+        })
+    }
+
+    /// convert to a hello frame
+    pub fn to_frame(&self) -> ClushFrame {
+        ClushFrame::new(
+            MessageType::HelloMessage,
+            self.version as u64,
+            self.capabilities.0,
+            0,
+            BytesMut::new(),
+        )
+    }
+
+    /// agree on the version and capabilities of a connection,
+    /// given the hello of a client and what the server supports
+    ///
+    /// a newer client is answered with the newest version of the server,
+    /// a version older than `min_version` is refused
+    pub fn negotiate(&self, min_version: u32, supported: Capabilities) -> Result<Hello, String> {
+        if self.version < min_version.max(LEGACY_VERSION) {
+            return Err(unsupported_version(self.version, min_version));
+        }
+
+        Ok(Hello {
+            version: self.version.min(PROTOCOL_VERSION),
+            capabilities: self.capabilities & supported,
+        })
+    }
+}
+
+/// error sent to a client whose version is too old
+pub fn unsupported_version(version: u32, min_version: u32) -> String {
+    format!(
+        "unsupported protocol version {}, the server supports {} to {}",
+        version,
+        min_version.max(LEGACY_VERSION),
+        PROTOCOL_VERSION
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hello_frame_test() {
+        let hello = Hello::new(Capabilities::ACKS | Capabilities::COMPRESSION);
+        let frame = hello.to_frame();
+        assert_eq!(MessageType::HelloMessage, frame.msg_type);
+        assert_eq!((2, 3), (frame.from_id, frame.to_id));
+        assert_eq!(Some(hello), Hello::from_frame(&frame));
+
+        let login = ClushFrame::new(MessageType::LoginMessage, 2, 3, 0, BytesMut::new());
+        assert_eq!(None, Hello::from_frame(&login));
+    }
+
+    #[test]
+    fn negotiate_test() {
+        let supported = Capabilities::ACKS | Capabilities::COMPRESSION;
+
+        // capabilities are those both sides support
+        let client = Hello::new(Capabilities::ACKS | Capabilities::FILE_RESUME);
+        let agreed = client.negotiate(LEGACY_VERSION, supported).unwrap();
+        assert_eq!(PROTOCOL_VERSION, agreed.version);
+        assert_eq!(Capabilities::ACKS, agreed.capabilities);
+        assert!(agreed.capabilities.contains(Capabilities::ACKS));
+        assert!(!agreed.capabilities.contains(Capabilities::FILE_RESUME));
+
+        // a newer client gets the version of the server
+        let client = Hello {
+            version: PROTOCOL_VERSION + 1,
+            capabilities: Capabilities::NONE,
+        };
+        let agreed = client.negotiate(LEGACY_VERSION, supported).unwrap();
+        assert_eq!(PROTOCOL_VERSION, agreed.version);
+
+        // an older client is refused below the minimum
+        let client = Hello {
+            version: 1,
+            capabilities: Capabilities::NONE,
+        };
+        assert!(client.negotiate(1, supported).is_ok());
+        let e = client.negotiate(2, supported).unwrap_err();
+        assert_eq!(
+            "unsupported protocol version 1, the server supports 2 to 2",
+            e
+        );
+        let client = Hello {
+            version: 0,
+            capabilities: Capabilities::NONE,
+        };
+        assert!(client.negotiate(0, supported).is_err());
+    }
+}
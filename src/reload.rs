@@ -0,0 +1,247 @@
+//! reload of the configuration of a running clush server
+//!
+//! the settings of connections, e.g. the TLS certificate, frame limits, compression
+//! and outbound queues, apply to connections accepted after a reload,
+//! connections already open keep theirs. the token of the admin API applies at once.
+//! the other fields, e.g. the bind address or the storage, need a restart
+
+use crate::codec::FrameLimits;
+use crate::compression::Compression;
+use crate::config::{AdminConfig, ClushConfig, FrameConfig, OutboundConfig, ServerConfig};
+use crate::tls::{self, TlsAcceptor};
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+
+/// settings which can change while the server is running
+#[derive(Clone)]
+pub(crate) struct Settings {
+    pub(crate) acceptor: Option<TlsAcceptor>,
+    pub(crate) shutdown_timeout: Duration,
+    pub(crate) min_protocol_version: u32,
+    pub(crate) compression: Option<Compression>,
+    pub(crate) frame_limits: FrameLimits,
+    pub(crate) max_inflight_bytes: u32,
+    pub(crate) outbound_config: OutboundConfig,
+    /// token of the admin API
+    pub(crate) admin_token: String,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        let shutdown_timeout = Duration::from_secs(ServerConfig::default_shutdown_timeout());
+        let min_protocol_version = ServerConfig::default_min_protocol_version();
+        let compression = Some(Compression::default());
+        let frame_limits = FrameLimits::default();
+        let max_inflight_bytes = FrameConfig::default_max_inflight_bytes();
+        let outbound_config = OutboundConfig::default();
+        let admin_token = AdminConfig::default().token;
+
+        Settings {
+            acceptor: None,
+            shutdown_timeout,
+            min_protocol_version,
+            compression,
+            frame_limits,
+            max_inflight_bytes,
+            outbound_config,
+            admin_token,
+        }
+    }
+}
+
+impl Settings {
+    /// get the settings of a configuration, loading the certificate and key if TLS is enabled
+    pub(crate) fn new(config: &ClushConfig) -> io::Result<Settings> {
+        let server_config = &config.server_config;
+        let acceptor = match server_config.enable_tls {
+            true => {
+                let tls_config =
+                    tls::server_config(&server_config.cert_path, &server_config.key_path)?;
+                Some(tls::acceptor(tls_config))
+            }
+            false => None,
+        };
+        let compression = match config.compression_config.enable {
+            true => Some(Compression::new(&config.compression_config)),
+            false => None,
+        };
+
+        Ok(Settings {
+            acceptor,
+            shutdown_timeout: Duration::from_secs(server_config.shutdown_timeout),
+            min_protocol_version: server_config.min_protocol_version,
+            compression,
+            frame_limits: FrameLimits::new(&config.frame_config),
+            max_inflight_bytes: config.frame_config.max_inflight_bytes,
+            outbound_config: config.outbound_config.clone(),
+            admin_token: config.admin_config.token.clone(),
+        })
+    }
+}
+
+/// handle to reload the configuration of a server, cheap to clone
+///
+/// # Example
+///
+/// ```no_run
+/// # use clush_server::config::ConfigLoader;
+/// # use clush_server::ClushServer;
+/// # async fn run() -> std::io::Result<()> {
+/// let server = ClushServer::builder().build().await?;
+/// let handle = server.reload_handle();
+/// tokio::spawn(async move {
+///     let config = ConfigLoader::new().load().await.unwrap();
+///     for field in handle.reload(config).unwrap() {
+///         tracing::warn!("{} changed, restart to apply it", field);
+///     }
+/// });
+/// server.start().await
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct ReloadHandle {
+    tx: Arc<watch::Sender<Arc<Settings>>>,
+    /// the configuration the server was built with
+    config: Arc<ClushConfig>,
+}
+
+impl ReloadHandle {
+    /// apply the settings of a configuration to the connections accepted from now on,
+    /// return the fields which changed but only apply after a restart.
+    ///
+    /// the running settings are kept if the configuration is invalid,
+    /// or if the certificate or key cannot be loaded
+    pub fn reload(&self, config: ClushConfig) -> io::Result<Vec<&'static str>> {
+        config.validate()?;
+        let settings = Settings::new(&config)?;
+        self.tx
+            .send(Arc::new(settings))
+            .map_err(|_| io::Error::new(io::ErrorKind::NotConnected, "the server is gone"))?;
+
+        Ok(restart_required(&self.config, &config))
+    }
+}
+
+/// create a reload handle of a server built with the given configuration,
+/// and the receiver of its settings
+pub(crate) fn channel(
+    config: ClushConfig,
+    settings: Settings,
+) -> (ReloadHandle, watch::Receiver<Arc<Settings>>) {
+    let (tx, rx) = watch::channel(Arc::new(settings));
+    let handle = ReloadHandle {
+        tx: Arc::new(tx),
+        config: Arc::new(config),
+    };
+
+    (handle, rx)
+}
+
+/// list the fields which differ between two configurations and need a restart
+pub fn restart_required(running: &ClushConfig, config: &ClushConfig) -> Vec<&'static str> {
+    let mut fields = vec![];
+    if running.server_config.url != config.server_config.url {
+        fields.push("serverConfig.url");
+    }
+    if running.storage_config != config.storage_config {
+        fields.push("storageConfig");
+    }
+    if running.rbatis_config != config.rbatis_config {
+        fields.push("rbatisConfig");
+    }
+    if running.pipeline_config != config.pipeline_config {
+        fields.push("pipelineConfig");
+    }
+    if running.wal_config != config.wal_config {
+        fields.push("walConfig");
+    }
+    // the level is up to the application, see `LogHandle::set_level`
+    let logging_config = &config.logging_config;
+    if running.logging_config.format != logging_config.format {
+        fields.push("loggingConfig.format");
+    }
+    if running.logging_config.path != logging_config.path {
+        fields.push("loggingConfig.path");
+    }
+    if running.metrics_config != config.metrics_config {
+        fields.push("metricsConfig");
+    }
+    let admin_config = &config.admin_config;
+    if running.admin_config.enable != admin_config.enable {
+        fields.push("adminConfig.enable");
+    }
+    if running.admin_config.url != admin_config.url {
+        fields.push("adminConfig.url");
+    }
+
+    fields
+}
+
+/// wait for SIGHUP, never returns where there is no such signal
+pub async fn signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut hangup = signal(SignalKind::hangup()).expect("failed to listen to SIGHUP");
+        hangup.recv().await;
+    }
+
+    #[cfg(not(unix))]
+    std::future::pending::<()>().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn restart_required_test() {
+        let running = ClushConfig::default();
+        let mut config = ClushConfig::default();
+        config.server_config.shutdown_timeout = 1;
+        config.logging_config.level = "debug".to_string();
+        config.frame_config.max_message_size = 1024;
+        config.outbound_config.queue_size = 8;
+        config.admin_config.token = "secret".to_string();
+        assert!(restart_required(&running, &config).is_empty());
+
+        config.server_config.url = "127.0.0.1:1".to_string();
+        config.rbatis_config.db_url = "sqlite://clush.db".to_string();
+        config.wal_config.enable = false;
+        config.logging_config.path = "log/clush.log".to_string();
+        assert_eq!(
+            vec![
+                "serverConfig.url",
+                "rbatisConfig",
+                "walConfig",
+                "loggingConfig.path"
+            ],
+            restart_required(&running, &config)
+        );
+    }
+
+    #[tokio::test]
+    async fn reload_test() {
+        let (handle, rx) = channel(ClushConfig::default(), Settings::default());
+
+        let mut config = ClushConfig::default();
+        config.compression_config.enable = false;
+        config.outbound_config.queue_size = 8;
+        assert!(handle.reload(config.clone()).unwrap().is_empty());
+        assert!(rx.borrow().compression.is_none());
+        assert_eq!(8, rx.borrow().outbound_config.queue_size);
+
+        // an invalid configuration is not applied
+        config.outbound_config.queue_size = 0;
+        let e = handle.reload(config).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, e.kind());
+        assert_eq!(8, rx.borrow().outbound_config.queue_size);
+
+        drop(rx);
+        let e = handle.reload(ClushConfig::default()).unwrap_err();
+        assert_eq!(io::ErrorKind::NotConnected, e.kind());
+    }
+}
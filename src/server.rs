@@ -0,0 +1,1536 @@
+use crate::admin::{self, Admin};
+use crate::audit::{self, AuditKind};
+use crate::codec::{self, ClushFrame, Connection, FrameLimits, MessageType};
+use crate::compression::Compression;
+use crate::config::{ClushConfig, FrameConfig, PipelineConfig, ServerConfig, WalConfig};
+use crate::entity::*;
+use crate::http::{self, Endpoints};
+use crate::metrics::Metrics;
+use crate::outbound::{self, Offer, Outbound, OutboundReceiver, OutboundStats};
+use crate::pipeline::{Pipeline, Record};
+use crate::presence::{Presence, PresenceHub, Status};
+use crate::protocol::{self, Capabilities, Hello};
+use crate::reload::{self, ReloadHandle, Settings};
+use crate::shutdown::{self, Drain, DrainGuard, Shutdown, ShutdownHandle};
+use crate::storage::{self, MeteredStorage, Storage};
+use crate::util::*;
+use bytes::BytesMut;
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
+use std::collections::HashSet;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncWriteExt, ReadHalf, Result, WriteHalf};
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, oneshot, watch, OwnedSemaphorePermit, Semaphore};
+use tokio::time;
+use tracing::{field, Instrument, Span};
+
+/// optional features of the protocol the server supports, besides compression
+static CAPABILITIES: Capabilities = Capabilities(Capabilities::ACKS.0 | Capabilities::PRESENCE.0);
+/// longest wait at login for messages accepted just before to be saved
+static SAVE_WAIT: Duration = Duration::from_secs(1);
+
+/// outbound queues of online users
+pub(crate) type OutboundMap = DashMap<u64, Outbound>;
+/// outbound queues of all the connections of online users, the latest last
+pub(crate) type ConnectionMap = DashMap<u64, Vec<Outbound>>;
+
+/// a frame on its way to the message handler
+struct Inbound {
+    frame: ClushFrame,
+    /// the in-flight budget of its connection, released once the frame is routed
+    _permit: OwnedSemaphorePermit,
+    /// told whether a user message reached the queue of its recipient
+    routed: Option<oneshot::Sender<bool>>,
+}
+
+// TODO: add group_map to store online member of a group
+/// a clush server
+///
+/// # Example
+///
+/// ```no_run
+/// # use clush_server::ClushServer;
+/// # async fn run() -> std::io::Result<()> {
+/// let server = ClushServer::builder().build().await?;
+/// server.start().await
+/// # }
+/// ```
+pub struct ClushServer {
+    listener: TcpListener,
+    db: Arc<dyn Storage>,
+    map: Arc<OutboundMap>,
+    connections: Arc<ConnectionMap>,
+    presence: Arc<PresenceHub>,
+    pipeline_config: PipelineConfig,
+    wal_config: WalConfig,
+    /// settings of new connections, changed by reloads
+    settings: watch::Receiver<Arc<Settings>>,
+    reload_handle: ReloadHandle,
+    outbound_stats: Arc<OutboundStats>,
+    metrics: Arc<Metrics>,
+    /// listener of the HTTP endpoints, if enabled
+    metrics_listener: Option<Arc<TcpListener>>,
+    /// listener of the admin API, if enabled
+    admin_listener: Option<Arc<TcpListener>>,
+    /// whether connections are accepted, for the readiness probe
+    accepting: Arc<AtomicBool>,
+    shutdown_handle: ShutdownHandle,
+    shutdown: Shutdown,
+}
+
+impl ClushServer {
+    /// create a clush server with the given TCP listener and storage
+    pub fn new(listener: TcpListener, db: Arc<dyn Storage>) -> ClushServer {
+        // wrap in Arc for using in multi-threading context
+        let map = Arc::new(DashMap::new());
+        let metrics = Arc::new(Metrics::new());
+        let db = Arc::new(MeteredStorage::new(db, metrics.storage_latency.clone()));
+        let pipeline_config = PipelineConfig::default();
+        let wal_config = WalConfig::default();
+        let (reload_handle, settings) =
+            reload::channel(ClushConfig::default(), Settings::default());
+        let outbound_stats = Arc::new(OutboundStats::default());
+        metrics.register_outbound(map.clone(), outbound_stats.clone());
+        let (shutdown_handle, shutdown) = shutdown::channel();
+
+        ClushServer {
+            listener,
+            db,
+            map,
+            connections: Arc::new(ConnectionMap::new()),
+            presence: Arc::new(PresenceHub::new()),
+            pipeline_config,
+            wal_config,
+            settings,
+            reload_handle,
+            outbound_stats,
+            metrics,
+            metrics_listener: None,
+            admin_listener: None,
+            accepting: Arc::new(AtomicBool::new(false)),
+            shutdown_handle,
+            shutdown,
+        }
+    }
+
+    /// create a builder of a clush server
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder::new()
+    }
+
+    /// init a clush server with the given configuration
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use clush_server::{ClushConfig, ClushServer};
+    /// # async fn run() -> std::io::Result<()> {
+    /// let config = ClushConfig::from_json("config/clush.json").await?;
+    /// let server = ClushServer::init_with_config(config).await?;
+    /// server.start().await
+    /// # }
+    /// ```
+    pub async fn init_with_config(config: ClushConfig) -> Result<ClushServer> {
+        ServerBuilder::new().config(config).build().await
+    }
+
+    /// get the address the server is listening on
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// get a handle to shut the server down
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown_handle.clone()
+    }
+
+    /// get a handle to reload the configuration of the server
+    pub fn reload_handle(&self) -> ReloadHandle {
+        self.reload_handle.clone()
+    }
+
+    /// get the address of the HTTP endpoints, if enabled
+    pub fn metrics_addr(&self) -> Option<SocketAddr> {
+        self.metrics_listener
+            .as_ref()
+            .and_then(|listener| listener.local_addr().ok())
+    }
+
+    /// get the address of the admin API, if enabled
+    pub fn admin_addr(&self) -> Option<SocketAddr> {
+        self.admin_listener
+            .as_ref()
+            .and_then(|listener| listener.local_addr().ok())
+    }
+
+    /// get the metrics of the server
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
+
+    /// get the counters of the outbound queues
+    pub fn outbound_stats(&self) -> Arc<OutboundStats> {
+        self.outbound_stats.clone()
+    }
+
+    /// get the user with the most frames waiting to be written, and their number
+    pub fn deepest_queue(&self) -> Option<(u64, usize)> {
+        self.map
+            .iter()
+            .map(|pair| (*pair.key(), pair.value().len()))
+            .max_by_key(|(_, depth)| *depth)
+    }
+
+    /// start the event loop, return once the server is shut down
+    pub async fn start(&self) -> Result<()> {
+        // create a channel to handle message
+        let (tx, rx) = mpsc::channel::<Inbound>(1024);
+        let map = self.map.clone();
+        let db = self.db.clone();
+        let stats = self.outbound_stats.clone();
+        let metrics = self.metrics.clone();
+        // spawn the pipeline persisting messages
+        let pipeline =
+            Pipeline::spawn_with_wal(self.db.clone(), &self.pipeline_config, &self.wal_config)
+                .await?;
+        // user messages dropped from outbound queues wait for the next login
+        let (dropped, dropped_rx) = mpsc::unbounded_channel();
+        tokio::spawn(store_dropped(dropped_rx, pipeline.clone()));
+        // track readers and writers of connections to drain them on shutdown
+        let readers = Drain::new();
+        let writers = Drain::new();
+
+        // spawn a task to read message
+        let handler = tokio::spawn(async move {
+            let mut handler = MessageHandler::new(rx, map, db, stats);
+            // the permit of a frame is released once it is routed
+            while let Some(inbound) = handler.rx.recv().await {
+                metrics.handler_queue.dec();
+                let Inbound { frame, routed, .. } = inbound;
+                match frame.msg_type {
+                    MessageType::UserMessage | MessageType::UserFileMessage => {
+                        let queued = handler.handle_user_msg(frame);
+                        if let Some(routed) = routed {
+                            let _ = routed.send(queued);
+                        }
+                    }
+                    MessageType::GroupMessage | MessageType::GroupFileMessage => {
+                        handler.handle_group_msg(frame).await
+                    }
+                    _ => (),
+                }
+            }
+        });
+
+        // serve the HTTP endpoints next to the clients, until the server has drained
+        let endpoints = self.metrics_listener.as_ref().map(|listener| {
+            let endpoints = Endpoints {
+                metrics: self.metrics.clone(),
+                db: self.db.clone(),
+                accepting: self.accepting.clone(),
+                shutdown: self.shutdown.clone(),
+            };
+            let endpoints = Arc::new(endpoints);
+            tokio::spawn(http::serve(listener.clone(), move |request| {
+                http::handle(endpoints.clone(), request)
+            }))
+        });
+        let admin = self.admin_listener.as_ref().map(|listener| {
+            let admin = Arc::new(Admin {
+                db: self.db.clone(),
+                map: self.map.clone(),
+                connections: self.connections.clone(),
+                settings: self.settings.clone(),
+            });
+            tokio::spawn(http::serve(listener.clone(), move |request| {
+                admin::handle(admin.clone(), request)
+            }))
+        });
+
+        // main event loop, until a shutdown begins
+        let mut shutdown = self.shutdown.clone();
+        self.accepting.store(true, Ordering::Relaxed);
+        let mut session_id = 0u64;
+        loop {
+            // get stream from listener
+            let (stream, addr) = tokio::select! {
+                accepted = self.listener.accept() => accepted?,
+                _ = shutdown.wait() => break,
+            };
+            self.metrics.connections.inc();
+            // clone Arc of db, map to use in new task
+            let db = self.db.clone();
+            let map = self.map.clone();
+            let connections = self.connections.clone();
+            let presence = self.presence.clone();
+            // clone sender and pipeline to use in task
+            let tx = tx.clone();
+            let pipeline = pipeline.clone();
+            let metrics = self.metrics.clone();
+            // the settings at the time of the connection, whatever the later reloads
+            let settings = self.settings.borrow().clone();
+            let (outbound, outbound_rx) = outbound::channel(
+                settings.outbound_config.queue_size,
+                settings.outbound_config.overflow_policy,
+                self.outbound_stats.clone(),
+                Some(dropped.clone()),
+            );
+            let mut shutdown = self.shutdown.clone();
+            let reader_guard = readers.guard();
+            let writer_guard = writers.guard();
+            // everything logged about the connection has its session, and its user once known
+            session_id += 1;
+            let span = tracing::info_span!(
+                "connection",
+                %addr,
+                session = session_id,
+                uid = field::Empty
+            );
+
+            // spawn a new task
+            let connection = async move {
+                let _guard = reader_guard;
+                // do the TLS handshake if enabled
+                let stream: Box<dyn Connection> = match &settings.acceptor {
+                    Some(acceptor) => {
+                        let accepted = tokio::select! {
+                            accepted = acceptor.accept(stream) => accepted,
+                            _ = shutdown.wait() => return,
+                        };
+                        match accepted {
+                            Ok(stream) => Box::new(stream),
+                            Err(e) => {
+                                tracing::warn!("TLS handshake with {} failed: {}", addr, e);
+                                return;
+                            }
+                        }
+                    }
+                    None => Box::new(stream),
+                };
+
+                // split the stream, frames are written by a task of their own
+                let (reader, writer) = tokio::io::split(stream);
+                // compression of outgoing frames is enabled once negotiated
+                let (compressing, compressing_rx) = watch::channel(None);
+                tokio::spawn(
+                    write_frames(
+                        writer,
+                        outbound_rx,
+                        compressing_rx,
+                        metrics.clone(),
+                        shutdown.clone(),
+                        writer_guard,
+                    )
+                    .instrument(Span::current()),
+                );
+
+                // create a new task to deal with the stream
+                let mut task = Task::new(reader, outbound, db, map.clone(), tx, pipeline, metrics);
+                task.min_protocol_version = settings.min_protocol_version;
+                task.compression = settings.compression;
+                task.compressing = compressing;
+                task.limits = settings.frame_limits;
+                task.set_max_inflight_bytes(settings.max_inflight_bytes);
+                task.peer = Some(addr);
+                task.presence = presence;
+
+                // first login to server
+                let login = tokio::select! {
+                    login = task.process_login() => login,
+                    _ = shutdown.wait() => return,
+                };
+                if let Some(uid) = login {
+                    Span::current().record("uid", uid);
+                    tracing::info!("logged in");
+                    task.metrics.sessions.inc();
+                    // record the session of the user
+                    let session = Session {
+                        user_id: Some(uid),
+                        address: Some(addr.to_string()),
+                        login_time: Some(chrono::Utc::now()),
+                    };
+                    // the session is only informative, a failure does not refuse the login
+                    if let Err(e) = task.db.save_session(&session).await {
+                        tracing::error!("failed to record the session of {}: {}", uid, e);
+                    }
+                    // store outbound queue to map if login success
+                    register(&map, &connections, uid, &task.outbound);
+                    let listening = task.capabilities.contains(Capabilities::PRESENCE);
+                    let first = task.presence.login(uid, listening);
+
+                    // write back a success information if login succeed
+                    let mut frame = ClushFrame::new(
+                        MessageType::LoginMessage,
+                        0,
+                        uid,
+                        0,
+                        BytesMut::from("success"),
+                    );
+                    frame.update_size();
+                    if let Err(e) = task.write_frame(frame).await {
+                        tracing::debug!("failed to confirm the login: {}", e);
+                    }
+
+                    // tell the watchers the user is online, and the user who of their peers is
+                    if let Err(e) = task.go_online(first).await {
+                        tracing::error!("failed to publish the presence of {}: {}", uid, e);
+                    }
+
+                    // send what arrived while the user was offline
+                    if let Err(e) = task.deliver_offline().await {
+                        tracing::error!("failed to deliver offline messages to {}: {}", uid, e);
+                    }
+
+                    // then start to process the rest
+                    match task.process(shutdown.clone()).await {
+                        Ok(()) => tracing::info!("logged out"),
+                        Err(e) => tracing::warn!("connection of user {} failed: {}", uid, e),
+                    }
+
+                    // remove outbound queue and session when it is done,
+                    // on shutdown the queue is kept until it is flushed.
+                    // the user stays online while another of their connections is open
+                    let last = task.presence.logout(uid, listening, &task.subscriptions);
+                    task.subscriptions.clear();
+                    if !shutdown.is_shutdown() {
+                        release(&map, &connections, uid, &task.outbound);
+                        if last {
+                            if let Err(e) = task.db.remove_session(uid).await {
+                                tracing::error!("failed to remove the session of {}: {}", uid, e);
+                            }
+                        }
+                    }
+                    if last {
+                        if let Err(e) = task.go_offline().await {
+                            tracing::error!("failed to publish the presence of {}: {}", uid, e);
+                        }
+                    }
+                    task.metrics.sessions.dec();
+                } else {
+                    tracing::info!("login failed");
+                    // write back a failure message if login fail
+                    let mut frame = ClushFrame::new(
+                        MessageType::LoginMessage,
+                        0,
+                        0,
+                        0,
+                        BytesMut::from("failed"),
+                    );
+                    frame.update_size();
+                    let _ = task.write_frame(frame).await;
+                }
+            };
+            tokio::spawn(connection.instrument(span));
+        }
+        self.accepting.store(false, Ordering::Relaxed);
+
+        // drain everything accepted before the shutdown, up to the deadline
+        let shutdown_timeout = self.settings.borrow().shutdown_timeout;
+        let drained = time::timeout(shutdown_timeout, async {
+            // readers finish the frame at hand and stop
+            readers.wait().await;
+            // the handler forwards the frames left in the channel
+            drop(tx);
+            let _ = handler.await;
+            // pending messages are written to the storage
+            pipeline.flush().await;
+            // outbound queues are flushed and connections closed
+            self.map.clear();
+            self.connections.clear();
+            writers.wait().await;
+        })
+        .await;
+
+        if drained.is_err() {
+            tracing::warn!(
+                "shutdown deadline of {:?} exceeded, pending work is dropped",
+                shutdown_timeout
+            );
+        }
+        // the server is stopped anyway, stale sessions are cleared on the next start
+        if let Err(e) = self.db.clear_sessions().await {
+            tracing::error!("failed to clear the sessions: {}", e);
+        }
+        for task in endpoints.into_iter().chain(admin) {
+            task.abort();
+        }
+
+        Ok(())
+    }
+}
+
+/// builder of a clush server
+///
+/// the listener and the storage are created from the configuration unless given,
+/// the configuration defaults to `ClushConfig::default()`
+///
+/// # Example
+///
+/// ```no_run
+/// # use clush_server::{ClushServer, MemoryStorage};
+/// # use std::sync::Arc;
+/// # async fn run() -> std::io::Result<()> {
+/// let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+/// let server = ClushServer::builder()
+///     .listener(listener)
+///     .storage(Arc::new(MemoryStorage::new()))
+///     .build()
+///     .await?;
+/// server.start().await
+/// # }
+/// ```
+#[derive(Default)]
+pub struct ServerBuilder {
+    config: ClushConfig,
+    listener: Option<TcpListener>,
+    storage: Option<Arc<dyn Storage>>,
+}
+
+impl ServerBuilder {
+    /// create a builder with the default configuration
+    pub fn new() -> ServerBuilder {
+        ServerBuilder::default()
+    }
+
+    /// use the given configuration
+    pub fn config(mut self, config: ClushConfig) -> ServerBuilder {
+        self.config = config;
+
+        self
+    }
+
+    /// accept connections on the given listener instead of binding `serverConfig.url`
+    pub fn listener(mut self, listener: TcpListener) -> ServerBuilder {
+        self.listener = Some(listener);
+
+        self
+    }
+
+    /// use the given storage instead of opening the configured one
+    pub fn storage(mut self, storage: Arc<dyn Storage>) -> ServerBuilder {
+        self.storage = Some(storage);
+
+        self
+    }
+
+    /// build the server, binding the listener and opening the storage if needed,
+    /// fail with `InvalidInput` listing every invalid field of the configuration
+    pub async fn build(self) -> Result<ClushServer> {
+        let config = self.config;
+        config.validate()?;
+        // create listener
+        let listener = match self.listener {
+            Some(listener) => listener,
+            None => TcpListener::bind(&config.server_config.url).await?,
+        };
+        // open the storage, e.g. create database connection pool
+        let db = match self.storage {
+            Some(db) => db,
+            None => storage::open(&config).await?,
+        };
+        // sessions recorded before a restart are stale
+        db.clear_sessions().await?;
+
+        let mut server = ClushServer::new(listener, db);
+        // the HTTP endpoints have an address of their own
+        if config.metrics_config.enable {
+            let listener = TcpListener::bind(&config.metrics_config.url).await?;
+            server.metrics_listener = Some(Arc::new(listener));
+        }
+        if config.admin_config.enable {
+            let listener = TcpListener::bind(&config.admin_config.url).await?;
+            server.admin_listener = Some(Arc::new(listener));
+        }
+        // load the certificate and key if TLS is enabled
+        let settings = Settings::new(&config)?;
+        server.pipeline_config = config.pipeline_config.clone();
+        server.wal_config = config.wal_config.clone();
+        let (reload_handle, settings) = reload::channel(config, settings);
+        server.settings = settings;
+        server.reload_handle = reload_handle;
+
+        Ok(server)
+    }
+}
+
+/// write the frames of an outbound queue to a connection until the queue is closed,
+/// then tell the client the server is going away if it is shutting down
+///
+/// frames are compressed with the settings in `compressing`, if any
+async fn write_frames(
+    mut writer: WriteHalf<Box<dyn Connection>>,
+    mut rx: OutboundReceiver,
+    compressing: watch::Receiver<Option<Compression>>,
+    metrics: Arc<Metrics>,
+    shutdown: Shutdown,
+    _guard: DrainGuard,
+) {
+    while let Some(mut frame) = rx.recv().await {
+        // a user message is given back as queued if it cannot be written
+        let message = match frame.msg_type {
+            MessageType::UserMessage => Some(frame.clone()),
+            _ => None,
+        };
+        let compression = *compressing.borrow();
+        if let Some(compression) = compression {
+            if let Err(e) = compression.compress(&mut frame) {
+                tracing::warn!("failed to compress frame: {}", e);
+            }
+        }
+        if let Err(e) = codec::write_frame(&mut writer, &frame).await {
+            tracing::debug!("failed to write frame: {}", e);
+            if let Some(message) = message {
+                rx.give_back(message);
+            }
+            return;
+        }
+        metrics.sent(&frame);
+    }
+
+    if shutdown.is_shutdown() {
+        let frame = ClushFrame::new(MessageType::ShutdownMessage, 0, 0, 0, BytesMut::new());
+        let _ = codec::write_frame(&mut writer, &frame).await;
+    }
+    let _ = writer.shutdown().await;
+}
+
+/// store the user messages dropped from outbound queues as undelivered,
+/// so they are delivered on the next login of their recipient.
+/// they were stored as delivered when they were queued, and are stored again
+async fn store_dropped(mut rx: mpsc::UnboundedReceiver<ClushFrame>, pipeline: Pipeline) {
+    while let Some(frame) = rx.recv().await {
+        // broadcasts of the server are never stored
+        if frame.msg_type != MessageType::UserMessage || frame.from_id == 0 {
+            continue;
+        }
+        let (from, to) = (frame.from_id, frame.to_id);
+        let msg = UserMsg {
+            id: None,
+            from_id: Some(from),
+            to_id: Some(to),
+            date_time: Some(chrono::Utc::now()),
+            content: Some(String::from_utf8_lossy(&frame.content).into_owned()),
+            delivered: Some(false),
+        };
+        // stored concurrently, so they share batches
+        let pipeline = pipeline.clone();
+        tokio::spawn(async move {
+            if let Err(e) = pipeline.persist(Record::User(msg)).await {
+                tracing::error!(
+                    "failed to store a dropped message from {} to {}: {}",
+                    from,
+                    to,
+                    e
+                );
+            }
+        });
+    }
+}
+
+/// add the queue of a new connection of a user, frames to the user go to it
+fn register(map: &OutboundMap, connections: &ConnectionMap, uid: u64, outbound: &Outbound) {
+    let mut queues = connections.entry(uid).or_default();
+    queues.push(outbound.clone());
+    map.insert(uid, outbound.clone());
+}
+
+/// remove the queue of a closed connection of a user,
+/// frames to the user go to the latest of their other connections, if any
+fn release(map: &OutboundMap, connections: &ConnectionMap, uid: u64, outbound: &Outbound) {
+    if let Entry::Occupied(mut queues) = connections.entry(uid) {
+        queues.get_mut().retain(|queue| !queue.same_queue(outbound));
+        match queues.get().last() {
+            Some(latest) => {
+                map.insert(uid, latest.clone());
+            }
+            None => {
+                queues.remove();
+                map.remove(&uid);
+            }
+        }
+    }
+}
+
+/// a task to process the given connection
+struct Task {
+    stream: ReadHalf<Box<dyn Connection>>,
+    outbound: Outbound,
+    db: Arc<dyn Storage>,
+    map: Arc<OutboundMap>,
+    tx: mpsc::Sender<Inbound>,
+    pipeline: Pipeline,
+    uid: u64,
+    min_protocol_version: u32,
+    capabilities: Capabilities,
+    /// compression offered to the client
+    compression: Option<Compression>,
+    /// enables compression of the frames written to the client
+    compressing: watch::Sender<Option<Compression>>,
+    limits: FrameLimits,
+    /// bytes of content the connection may have waiting in the message handler
+    inflight: Arc<Semaphore>,
+    max_inflight_bytes: u32,
+    metrics: Arc<Metrics>,
+    /// address of the client, recorded in the audit log
+    peer: Option<SocketAddr>,
+    presence: Arc<PresenceHub>,
+    /// users the client subscribed to, besides its group peers
+    subscriptions: HashSet<u64>,
+}
+
+impl Task {
+    /// create a task to process the given stream,
+    /// frames to the client go through its outbound queue
+    fn new(
+        stream: ReadHalf<Box<dyn Connection>>,
+        outbound: Outbound,
+        db: Arc<dyn Storage>,
+        map: Arc<OutboundMap>,
+        tx: mpsc::Sender<Inbound>,
+        pipeline: Pipeline,
+        metrics: Arc<Metrics>,
+    ) -> Task {
+        let mut task = Task {
+            stream,
+            outbound,
+            db,
+            map,
+            tx,
+            pipeline,
+            uid: 0,
+            min_protocol_version: ServerConfig::default_min_protocol_version(),
+            capabilities: Capabilities::NONE,
+            compression: None,
+            compressing: watch::channel(None).0,
+            limits: FrameLimits::default(),
+            inflight: Arc::new(Semaphore::new(0)),
+            max_inflight_bytes: 0,
+            metrics,
+            peer: None,
+            presence: Arc::new(PresenceHub::new()),
+            subscriptions: HashSet::new(),
+        };
+        task.set_max_inflight_bytes(FrameConfig::default_max_inflight_bytes());
+
+        task
+    }
+
+    /// set the budget of bytes in flight of the connection
+    fn set_max_inflight_bytes(&mut self, max_inflight_bytes: u32) {
+        let max_inflight_bytes = max_inflight_bytes.max(1);
+        self.inflight = Arc::new(Semaphore::new(max_inflight_bytes as usize));
+        self.max_inflight_bytes = max_inflight_bytes;
+    }
+
+    /// process the stream until it is closed or a shutdown begins,
+    /// a frame being processed is always finished.
+    /// an invalid frame, e.g. one exceeding the limits, is reported before closing.
+    /// the connection is closed if it overflows its outbound queue
+    async fn process(&mut self, mut shutdown: Shutdown) -> Result<()> {
+        let outbound = self.outbound.clone();
+        loop {
+            let frame = tokio::select! {
+                frame = self.read_frame() => frame,
+                _ = shutdown.wait() => return Ok(()),
+                reason = outbound.disconnected() => {
+                    let event = AuditEvent::new(AuditKind::SessionTerminated, 0)
+                        .target(self.uid)
+                        .detail(reason);
+                    audit::record(&*self.db, event).await;
+                    return Err(io::Error::other(reason));
+                }
+            };
+            let frame = match frame {
+                Ok(frame) => frame,
+                Err(e) => {
+                    if e.kind() == io::ErrorKind::InvalidData {
+                        let _ = self.write_error(self.uid, &e.to_string()).await;
+                    }
+                    return Err(e);
+                }
+            };
+            let frame = match frame {
+                Some(frame) => frame,
+                None => return Ok(()),
+            };
+
+            let span = tracing::debug_span!(
+                "frame",
+                msg_type = ?frame.msg_type,
+                to = frame.to_id,
+                size = frame.size
+            );
+            let received = Instant::now();
+            self.process_frame(frame).instrument(span.clone()).await?;
+            span.in_scope(|| {
+                tracing::debug!(
+                    latency_us = received.elapsed().as_micros() as u64,
+                    "frame processed"
+                )
+            });
+        }
+    }
+
+    /// read a frame from the stream, decompressing its content if needed
+    async fn read_frame(&mut self) -> Result<Option<ClushFrame>> {
+        let mut frame = match codec::read_frame_with_limits(&mut self.stream, &self.limits).await? {
+            Some(frame) => frame,
+            None => return Ok(None),
+        };
+        self.metrics.received(&frame);
+
+        if frame.compressed {
+            let negotiated = self.capabilities.contains(Capabilities::COMPRESSION);
+            match self.compression.filter(|_| negotiated) {
+                Some(compression) => compression.decompress(&mut frame)?,
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "compression was not negotiated",
+                    ))
+                }
+            }
+            // the limits hold for the decompressed content too
+            self.limits.check(&frame.msg_type, frame.size)?;
+        }
+
+        Ok(Some(frame))
+    }
+
+    /// queue a frame to be written to the stream
+    async fn write_frame(&mut self, frame: ClushFrame) -> Result<()> {
+        self.outbound
+            .send(frame)
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "connection is closed"))
+    }
+
+    /// negotiate the protocol if the client starts with a hello,
+    /// then process the login message,
+    /// return Some(uid) if login success,
+    /// or None if failed
+    async fn process_login(&mut self) -> Option<u64> {
+        let mut first_frame = match self.read_frame().await {
+            Ok(Some(frame)) => frame,
+            _ => return None,
+        };
+
+        if let Some(hello) = Hello::from_frame(&first_frame) {
+            let supported = match self.compression {
+                Some(_) => CAPABILITIES | Capabilities::COMPRESSION,
+                None => CAPABILITIES,
+            };
+            let agreed = match hello.negotiate(self.min_protocol_version, supported) {
+                Ok(agreed) => agreed,
+                Err(e) => {
+                    self.metrics.login("unsupported_version");
+                    let _ = self.write_error(0, &e).await;
+                    return None;
+                }
+            };
+            self.capabilities = agreed.capabilities;
+            if self.write_frame(agreed.to_frame()).await.is_err() {
+                return None;
+            }
+            if self.capabilities.contains(Capabilities::COMPRESSION) {
+                let _ = self.compressing.send(self.compression);
+            }
+
+            first_frame = match self.read_frame().await {
+                Ok(Some(frame)) => frame,
+                _ => return None,
+            };
+        } else if protocol::LEGACY_VERSION < self.min_protocol_version {
+            let error =
+                protocol::unsupported_version(protocol::LEGACY_VERSION, self.min_protocol_version);
+            self.metrics.login("unsupported_version");
+            let _ = self.write_error(0, &error).await;
+            return None;
+        }
+
+        // the login message follows the hello, if any
+        if first_frame.msg_type != MessageType::LoginMessage {
+            self.metrics.login("login_required");
+            let _ = self.write_error(0, "login required").await;
+            return None;
+        }
+
+        // get uid, password from frame
+        let uid = first_frame.from_id;
+        let password_bytes = first_frame.content.freeze();
+
+        // get user info from database
+        let user = match self.db.fetch_user(uid).await {
+            Ok(user) => user,
+            Err(e) => {
+                tracing::error!("failed to fetch user {}: {}", uid, e);
+                self.metrics.login("error");
+                let _ = self.write_error(uid, "internal error").await;
+                return None;
+            }
+        };
+        let disabled = user.as_ref().and_then(|user| user.disabled) == Some(true);
+        // check password
+        if let Some(password) = user.and_then(|user| user.password) {
+            let hex_to_bytes = hex_string_to_bytes(&password);
+
+            if hex_to_bytes != password_bytes {
+                // if mismatch, send back an error frame
+                self.metrics.login("invalid_password");
+                self.audit_login_failure(uid, "invalid password").await;
+                let _ = self.write_error(uid, "invalid password").await;
+                return None;
+            }
+            // only told to those who know the password
+            if disabled {
+                self.metrics.login("disabled");
+                self.audit_login_failure(uid, "user disabled").await;
+                let _ = self.write_error(uid, "user disabled").await;
+                return None;
+            }
+            self.metrics.login("success");
+            self.audit(AuditEvent::new(AuditKind::Login, uid)).await;
+            self.uid = uid;
+
+            Some(uid)
+        } else {
+            // if mismatch, send back an error frame
+            self.metrics.login("invalid_user");
+            self.audit_login_failure(uid, "invalid user").await;
+            let _ = self.write_error(uid, "invalid user").await;
+
+            None
+        }
+    }
+
+    /// record an event of the client in the audit log, with its address
+    async fn audit(&mut self, event: AuditEvent) {
+        let event = match self.peer {
+            Some(peer) => event.address(peer),
+            None => event,
+        };
+        audit::record(&*self.db, event).await;
+    }
+
+    /// record a refused login as the claimed user in the audit log
+    async fn audit_login_failure(&mut self, uid: u64, reason: &str) {
+        let event = AuditEvent::new(AuditKind::LoginFailed, uid).detail(reason);
+        self.audit(event).await;
+    }
+
+    /// send the messages stored while the user was offline, then mark them delivered
+    async fn deliver_offline(&mut self) -> Result<()> {
+        // a message sent right before the login may not be saved yet
+        let _ = time::timeout(SAVE_WAIT, self.pipeline.wait_saved()).await;
+        let msgs = self.db.fetch_undelivered_user_msgs(self.uid).await?;
+
+        let mut ids = vec![];
+        for msg in msgs {
+            let content = msg.content.unwrap_or_default();
+            let mut frame = ClushFrame::new(
+                MessageType::UserMessage,
+                msg.from_id.unwrap_or(0),
+                self.uid,
+                0,
+                BytesMut::from(content.as_bytes()),
+            );
+            frame.update_size();
+            self.write_frame(frame).await?;
+            ids.extend(msg.id);
+        }
+        self.db.mark_user_msgs_delivered(&ids).await?;
+
+        Ok(())
+    }
+
+    /// process the frame according to the frame type,
+    /// the sender is always the logged in user
+    async fn process_frame(&mut self, mut frame: ClushFrame) -> Result<()> {
+        let is_message = matches!(
+            frame.msg_type,
+            MessageType::UserMessage
+                | MessageType::GroupMessage
+                | MessageType::UserFileMessage
+                | MessageType::GroupFileMessage
+        );
+        if is_message && frame.from_id != self.uid {
+            let event = AuditEvent::new(AuditKind::Spoofing, self.uid)
+                .target(frame.from_id)
+                .detail(format!(
+                    "{:?} sent as user {}",
+                    frame.msg_type, frame.from_id
+                ));
+            self.audit(event).await;
+        }
+        frame.from_id = self.uid;
+
+        match frame.msg_type {
+            MessageType::UserMessage => self.process_user_msg(frame).await,
+            MessageType::GroupMessage => self.process_group_msg(frame).await,
+            MessageType::UserFileMessage => self.process_user_file(frame).await,
+            MessageType::GroupFileMessage => self.process_group_file(frame).await,
+            MessageType::PresenceMessage => self.process_presence(frame).await,
+            // nothing to do with acks or a repeated login
+            _ => Ok(()),
+        }
+    }
+
+    /// process a ClushFrame as user message
+    ///
+    /// the message is forwarded at once, and acknowledged to the sender
+    /// after it is durable in the storage.
+    /// a message to an offline user is delivered on their next login
+    async fn process_user_msg(&mut self, frame: ClushFrame) -> Result<()> {
+        // use auto-generated id
+        let id = None;
+        // get info from frame
+        let from_id = Some(frame.from_id);
+        let to_id = Some(frame.to_id);
+        let date_time = Some(chrono::Utc::now());
+        let content = match String::from_utf8(frame.content.to_vec()) {
+            Ok(content) => Some(content),
+            Err(_) => return self.write_error(frame.from_id, "invalid UTF-8").await,
+        };
+        let (from, to) = (frame.from_id, frame.to_id);
+
+        // forward first, delivery does not wait for the database.
+        // a message which did not reach the queue of its recipient is delivered
+        // on their next login
+        let delivered = Some(self.forward_user_msg(frame).await?);
+
+        let user_msg = UserMsg {
+            id,
+            from_id,
+            to_id,
+            date_time,
+            content,
+            delivered,
+        };
+
+        // store UserMsg through the pipeline, then acknowledge.
+        // a storage failure withholds the ack but keeps the connection,
+        // online delivery goes on while the database is down
+        match self.pipeline.persist(Record::User(user_msg)).await {
+            Ok(()) => self.write_ack(from, to).await,
+            Err(e) => {
+                tracing::error!("failed to store a message from {} to {}: {}", from, to, e);
+                self.write_error(from, "failed to store the message").await
+            }
+        }
+    }
+
+    /// process a ClushFrame as group message
+    ///
+    /// the message is forwarded to the online members of the group,
+    /// and acknowledged to the sender once it is stored
+    async fn process_group_msg(&mut self, frame: ClushFrame) -> Result<()> {
+        if !self.ensure_member(frame.to_id).await? {
+            return Ok(());
+        }
+        let content = match String::from_utf8(frame.content.to_vec()) {
+            Ok(content) => Some(content),
+            Err(_) => return self.write_error(frame.from_id, "invalid UTF-8").await,
+        };
+
+        let group_msg = GroupMsg {
+            id: None,
+            group_id: Some(frame.to_id),
+            user_id: Some(frame.from_id),
+            date_time: Some(chrono::Utc::now()),
+            content,
+        };
+        let (from_id, to_id) = (frame.from_id, frame.to_id);
+
+        self.forward(frame).await?;
+
+        // store GroupMsg through the pipeline like user messages, then acknowledge
+        match self.pipeline.persist(Record::Group(group_msg)).await {
+            Ok(()) => self.write_ack(from_id, to_id).await,
+            Err(e) => {
+                tracing::error!(
+                    "failed to store a message from {} to group {}: {}",
+                    from_id,
+                    to_id,
+                    e
+                );
+                self.write_error(from_id, "failed to store the message")
+                    .await
+            }
+        }
+    }
+
+    /// process a ClushFrame as file sent to a user,
+    /// files are not stored, so the recipient must be online
+    async fn process_user_file(&mut self, frame: ClushFrame) -> Result<()> {
+        if !self.map.contains_key(&frame.to_id) {
+            let error = format!("user {} is offline", frame.to_id);
+            return self.write_error(frame.from_id, &error).await;
+        }
+
+        self.forward(frame).await
+    }
+
+    /// process a ClushFrame as file sent to a group,
+    /// only online members receive it
+    async fn process_group_file(&mut self, frame: ClushFrame) -> Result<()> {
+        if !self.ensure_member(frame.to_id).await? {
+            return Ok(());
+        }
+
+        self.forward(frame).await
+    }
+
+    /// process a ClushFrame as presence,
+    /// either a status of the user or a subscription to the user in `to_id`
+    async fn process_presence(&mut self, frame: ClushFrame) -> Result<()> {
+        let content = String::from_utf8_lossy(&frame.content).into_owned();
+        match content.as_str() {
+            "subscribe" => {
+                if self.db.fetch_user(frame.to_id).await?.is_none() {
+                    let error = format!("unknown user {}", frame.to_id);
+                    return self.write_error(self.uid, &error).await;
+                }
+                if !self.group_peers().await?.contains(&frame.to_id) {
+                    let error = format!("user {} shares no group", frame.to_id);
+                    return self.write_error(self.uid, &error).await;
+                }
+                if self.subscriptions.insert(frame.to_id) {
+                    self.presence.subscribe(self.uid, frame.to_id);
+                }
+
+                let presence = self.presence_of(frame.to_id).await?;
+                self.write_presence(frame.to_id, presence).await
+            }
+            "unsubscribe" => {
+                if self.subscriptions.remove(&frame.to_id) {
+                    self.presence.unsubscribe(self.uid, frame.to_id);
+                }
+
+                Ok(())
+            }
+            status => match status.parse() {
+                Ok(Status::Offline) | Err(_) => {
+                    let error = format!("invalid status `{}`", status);
+                    self.write_error(self.uid, &error).await
+                }
+                Ok(status) => {
+                    self.presence.set_status(self.uid, status);
+                    self.publish(Presence::online(status)).await
+                }
+            },
+        }
+    }
+
+    /// tell the watchers of the user that they are online, on the first connection of the user,
+    /// then tell a client which negotiated presence which of its group peers are online
+    async fn go_online(&mut self, first: bool) -> Result<()> {
+        if first {
+            self.publish(Presence::online(Status::Online)).await?;
+        }
+        if !self.capabilities.contains(Capabilities::PRESENCE) {
+            return Ok(());
+        }
+
+        for user_id in self.group_peers().await? {
+            if let Some(status) = self.presence.status(user_id) {
+                self.write_presence(user_id, Presence::online(status))
+                    .await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// record when the user, gone offline, was last seen and tell their watchers
+    async fn go_offline(&mut self) -> Result<()> {
+        let last_seen = LastSeen {
+            user_id: Some(self.uid),
+            date_time: Some(chrono::Utc::now()),
+        };
+        self.db.save_last_seen(&last_seen).await?;
+        self.publish(Presence::offline(last_seen.date_time)).await
+    }
+
+    /// get the presence of a user, with the time they were last seen if offline
+    async fn presence_of(&mut self, user_id: u64) -> Result<Presence> {
+        if let Some(status) = self.presence.status(user_id) {
+            return Ok(Presence::online(status));
+        }
+        let last_seen = self.db.fetch_last_seen(user_id).await?;
+
+        Ok(Presence::offline(
+            last_seen.and_then(|last_seen| last_seen.date_time),
+        ))
+    }
+
+    /// offer the presence of the user to the online watchers which negotiated presence,
+    /// presence is not worth waiting for a full queue, it is dropped then
+    async fn publish(&mut self, presence: Presence) -> Result<()> {
+        let mut watchers = self.group_peers().await?;
+        watchers.extend(self.presence.subscribers(self.uid));
+
+        let frame = presence_frame(self.uid, 0, presence);
+        for user_id in watchers {
+            if !self.presence.is_listening(user_id) {
+                continue;
+            }
+            if let Some(outbound) = self.map.get(&user_id) {
+                let mut frame = frame.clone();
+                frame.to_id = user_id;
+                if let Offer::Refused(_) = outbound.offer(frame) {
+                    tracing::debug!("outbound queue of user {} is full", user_id);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// get the members of the groups of the user, but the user
+    async fn group_peers(&mut self) -> Result<HashSet<u64>> {
+        let mut peers = HashSet::new();
+        for membership in self.db.fetch_memberships(self.uid).await? {
+            if let Some(group_id) = membership.group_id {
+                let members = self.db.fetch_members(group_id).await?;
+                peers.extend(members.iter().filter_map(|member| member.user_id));
+            }
+        }
+        peers.remove(&self.uid);
+
+        Ok(peers)
+    }
+
+    /// send the presence of a user to the client
+    async fn write_presence(&mut self, user_id: u64, presence: Presence) -> Result<()> {
+        self.write_frame(presence_frame(user_id, self.uid, presence))
+            .await
+    }
+
+    /// check that the user is a member of a group, and tell them if they are not
+    /// or membership cannot be checked, return whether they are
+    async fn ensure_member(&mut self, group_id: u64) -> Result<bool> {
+        let error = match self.is_member(group_id).await {
+            Ok(true) => return Ok(true),
+            Ok(false) => format!("not a member of group {}", group_id),
+            Err(e) => {
+                tracing::error!("failed to fetch the members of group {}: {}", group_id, e);
+                "failed to check the membership".to_string()
+            }
+        };
+        self.write_error(self.uid, &error).await?;
+
+        Ok(false)
+    }
+
+    /// check whether the logged in user is a member of a group
+    async fn is_member(&mut self, group_id: u64) -> Result<bool> {
+        let members = self.db.fetch_members(group_id).await?;
+
+        Ok(members
+            .iter()
+            .any(|member| member.user_id == Some(self.uid)))
+    }
+
+    /// pass a frame to the message handler to route it to its recipients
+    async fn forward(&mut self, frame: ClushFrame) -> Result<()> {
+        self.send_inbound(frame, None).await
+    }
+
+    /// pass a user message to the message handler,
+    /// return whether it reached the queue of its recipient
+    async fn forward_user_msg(&mut self, frame: ClushFrame) -> Result<bool> {
+        let (routed, queued) = oneshot::channel();
+        self.send_inbound(frame, Some(routed)).await?;
+
+        Ok(queued.await.unwrap_or(false))
+    }
+
+    /// send a frame to the message handler,
+    /// waiting while too many bytes of the connection are in flight
+    async fn send_inbound(
+        &mut self,
+        frame: ClushFrame,
+        routed: Option<oneshot::Sender<bool>>,
+    ) -> Result<()> {
+        // a frame larger than the whole budget waits for all of it
+        let cost = (frame.content.len() as u64).clamp(1, self.max_inflight_bytes as u64) as u32;
+        let permit = self
+            .inflight
+            .clone()
+            .acquire_many_owned(cost)
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "connection is closed"))?;
+
+        let inbound = Inbound {
+            frame,
+            _permit: permit,
+            routed,
+        };
+        // counted before it is sent, the handler may take it at once
+        self.metrics.handler_queue.inc();
+        self.tx.send(inbound).await.map_err(|_| {
+            self.metrics.handler_queue.dec();
+            io::Error::new(io::ErrorKind::BrokenPipe, "message handler is closed")
+        })
+    }
+
+    /// acknowledge a durable message if the client negotiated acks
+    async fn write_ack(&mut self, from_id: u64, to_id: u64) -> Result<()> {
+        if !self.capabilities.contains(Capabilities::ACKS) {
+            return Ok(());
+        }
+
+        let ack = ClushFrame::new(MessageType::AckMessage, from_id, to_id, 0, BytesMut::new());
+        self.write_frame(ack).await
+    }
+
+    /// send an error to the client, as a user message from the server
+    async fn write_error(&mut self, to_id: u64, error: &str) -> Result<()> {
+        let mut err_frame =
+            ClushFrame::new(MessageType::UserMessage, 0, to_id, 0, BytesMut::from(error));
+        err_frame.update_size();
+
+        self.write_frame(err_frame).await
+    }
+}
+
+/// create a frame telling the presence of `user_id` to `to_id`
+fn presence_frame(user_id: u64, to_id: u64, presence: Presence) -> ClushFrame {
+    let content = BytesMut::from(presence.to_content().as_str());
+    let mut frame = ClushFrame::new(MessageType::PresenceMessage, user_id, to_id, 0, content);
+    frame.update_size();
+
+    frame
+}
+
+/// message handler, routing frames to the outbound queues of their recipients
+///
+/// routing never waits for a recipient, a full queue is handled by its overflow policy
+struct MessageHandler {
+    rx: mpsc::Receiver<Inbound>,
+    map: Arc<OutboundMap>,
+    db: Arc<dyn Storage>,
+    stats: Arc<OutboundStats>,
+}
+
+impl MessageHandler {
+    /// create a new MessageHandler
+    ///
+    /// # Example
+    ///
+    /// ```ignore
+    /// let (tx, rx) = mpsc::channel(1024);
+    /// let map = Arc::new(DashMap::new());
+    /// let handler = MessageHandler::new(rx, map.clone(), db.clone(), stats.clone());
+    /// ```
+    fn new(
+        rx: mpsc::Receiver<Inbound>,
+        map: Arc<OutboundMap>,
+        db: Arc<dyn Storage>,
+        stats: Arc<OutboundStats>,
+    ) -> MessageHandler {
+        MessageHandler { rx, map, db, stats }
+    }
+
+    /// handle a frame sent to a user, return whether it was queued
+    fn handle_user_msg(&self, frame: ClushFrame) -> bool {
+        self.route(frame.to_id, frame)
+    }
+
+    /// handle a frame sent to a group, every online member but the sender gets it
+    async fn handle_group_msg(&self, frame: ClushFrame) {
+        let members = match self.db.fetch_members(frame.to_id).await {
+            Ok(members) => members,
+            Err(e) => {
+                tracing::error!("failed to fetch members of group {}: {}", frame.to_id, e);
+                return;
+            }
+        };
+
+        for user_id in members.iter().filter_map(|member| member.user_id) {
+            if user_id == frame.from_id {
+                continue;
+            }
+            self.route(user_id, frame.clone());
+        }
+    }
+
+    /// offer a frame to the queue of an online user, return whether it was queued
+    fn route(&self, user_id: u64, frame: ClushFrame) -> bool {
+        let offer = match self.map.get(&user_id) {
+            Some(outbound) => outbound.offer(frame),
+            None => return false,
+        };
+
+        match offer {
+            Offer::Queued => true,
+            Offer::Refused(frame) => {
+                // user messages are stored undelivered by their sender, others are lost
+                if frame.msg_type == MessageType::UserMessage {
+                    self.stats.add_spilled();
+                } else {
+                    self.stats.add_dropped();
+                }
+                tracing::debug!("outbound queue of user {} is full", user_id);
+
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::ClushClient;
+    use crate::storage::{MemoryStorage, MessageRepository, UserRepository};
+    use crate::tls;
+    use std::time::Duration;
+    use tokio::net::TcpStream;
+
+    /// get the default configuration with a write-ahead log of its own
+    fn test_config() -> ClushConfig {
+        let wal_path = std::env::temp_dir().join(format!(
+            "clush-wal-{}",
+            chrono::Utc::now().timestamp_nanos()
+        ));
+        let mut config = ClushConfig::default();
+        config.wal_config.path = wal_path.to_str().unwrap().to_string();
+
+        config
+    }
+
+    /// build a server on an ephemeral port whose only user is 1
+    async fn build_server(config: ClushConfig) -> ClushServer {
+        let db = Arc::new(MemoryStorage::new());
+        let user = User {
+            id: Some(1),
+            username: Some("alice".to_string()),
+            password: Some("1c8a".to_string()),
+            disabled: None,
+        };
+        db.save_user(&user).await.unwrap();
+
+        ClushServer::builder()
+            .config(config)
+            .listener(TcpListener::bind("127.0.0.1:0").await.unwrap())
+            .storage(db)
+            .build()
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn shutdown_test() {
+        let server = build_server(test_config()).await;
+        let addr = server.local_addr().unwrap();
+        let handle = server.shutdown_handle();
+        let server = tokio::spawn(async move { server.start().await });
+
+        // log in, then shut the server down
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let mut login = ClushFrame::new(MessageType::LoginMessage, 1, 0, 0, BytesMut::new());
+        login.append(&[0x1c, 0x8a]).update_size();
+        codec::write_frame(&mut client, &login).await.unwrap();
+        let reply = codec::read_frame(&mut client).await.unwrap().unwrap();
+        assert_eq!(BytesMut::from("success"), reply.content);
+        handle.shutdown();
+
+        // the connection ends with a going-away frame
+        let frame = time::timeout(Duration::from_secs(5), codec::read_frame(&mut client))
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert!(matches!(frame.msg_type, MessageType::ShutdownMessage));
+        assert!(codec::read_frame(&mut client).await.unwrap().is_none());
+
+        time::timeout(Duration::from_secs(5), server)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn store_dropped_test() {
+        let db = Arc::new(MemoryStorage::new());
+        let pipeline = Pipeline::spawn(db.clone(), &PipelineConfig::default());
+        let (dropped, dropped_rx) = mpsc::unbounded_channel();
+        tokio::spawn(store_dropped(dropped_rx, pipeline));
+
+        // only user messages of users are stored again
+        let frames = [
+            (MessageType::UserMessage, 1, "hi bob"),
+            (MessageType::UserMessage, 0, "broadcast"),
+            (MessageType::PresenceMessage, 1, "online"),
+        ];
+        for (msg_type, from_id, content) in frames {
+            let frame = ClushFrame::new(msg_type, from_id, 2, 0, BytesMut::from(content));
+            dropped.send(frame).unwrap();
+        }
+
+        let mut undelivered = vec![];
+        for _ in 0..100 {
+            undelivered = db.fetch_undelivered_user_msgs(2).await.unwrap();
+            if !undelivered.is_empty() {
+                break;
+            }
+            time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(1, undelivered.len());
+        assert_eq!(Some(1), undelivered[0].from_id);
+        assert_eq!(Some("hi bob"), undelivered[0].content.as_deref());
+    }
+
+    #[tokio::test]
+    async fn reload_tls_test() {
+        let (old_cert, old_key) = tls::tests::self_signed();
+        let mut config = test_config();
+        config.server_config.enable_tls = true;
+        config.server_config.cert_path = old_cert.clone();
+        config.server_config.key_path = old_key;
+        let server = build_server(config.clone()).await;
+        let addr = server.local_addr().unwrap();
+        let handle = server.reload_handle();
+        tokio::spawn(async move { server.start().await });
+
+        let connect = |ca_path: String| async move {
+            let client_config = tls::client_config(&ca_path).unwrap();
+            ClushClient::connect_tls(addr, "localhost", client_config).await
+        };
+        let mut old_client = connect(old_cert.clone()).await.unwrap();
+        old_client.login(1, &[0x1c, 0x8a]).await.unwrap();
+
+        // rotate the certificate, the address needs a restart
+        let (new_cert, new_key) = tls::tests::self_signed();
+        config.server_config.cert_path = new_cert.clone();
+        config.server_config.key_path = new_key;
+        config.server_config.url = "127.0.0.1:1".to_string();
+        assert_eq!(vec!["serverConfig.url"], handle.reload(config).unwrap());
+
+        // new handshakes get the new certificate
+        assert!(connect(new_cert).await.is_ok());
+        assert!(connect(old_cert).await.is_err());
+
+        // the session opened before the reload goes on
+        old_client.send_user_msg(1, "still here").await.unwrap();
+        loop {
+            let frame = time::timeout(Duration::from_secs(5), old_client.recv())
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap();
+            // skip the ack
+            if frame.msg_type == MessageType::UserMessage {
+                assert_eq!(BytesMut::from("still here"), frame.content);
+                break;
+            }
+        }
+    }
+}
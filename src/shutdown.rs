@@ -0,0 +1,159 @@
+//! graceful shutdown of a clush server
+
+use std::sync::Arc;
+use tokio::sync::{mpsc, watch};
+
+/// handle to shut a server down, cheap to clone
+///
+/// # Example
+///
+/// ```no_run
+/// # use clush_server::ClushServer;
+/// # async fn run() -> std::io::Result<()> {
+/// let server = ClushServer::builder().build().await?;
+/// let handle = server.shutdown_handle();
+/// tokio::spawn(async move { handle.shutdown() });
+/// server.start().await
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    tx: Arc<watch::Sender<bool>>,
+}
+
+impl ShutdownHandle {
+    /// ask the server to shut down, `start` returns once it has drained
+    pub fn shutdown(&self) {
+        let _ = self.tx.send(true);
+    }
+}
+
+/// listener notified when a shutdown begins, cheap to clone
+#[derive(Clone)]
+pub struct Shutdown {
+    rx: watch::Receiver<bool>,
+}
+
+impl Shutdown {
+    /// check whether a shutdown has begun
+    pub fn is_shutdown(&self) -> bool {
+        *self.rx.borrow()
+    }
+
+    /// wait until a shutdown begins
+    pub async fn wait(&mut self) {
+        while !self.is_shutdown() {
+            // all handles are gone, no one can shut down anymore
+            if self.rx.changed().await.is_err() {
+                std::future::pending::<()>().await;
+            }
+        }
+    }
+}
+
+/// create a pair of shutdown handle and listener
+pub fn channel() -> (ShutdownHandle, Shutdown) {
+    let (tx, rx) = watch::channel(false);
+
+    (ShutdownHandle { tx: Arc::new(tx) }, Shutdown { rx })
+}
+
+/// wait for SIGINT, or SIGTERM on unix
+pub async fn signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut terminate = signal(SignalKind::terminate()).expect("failed to listen to SIGTERM");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => (),
+            _ = terminate.recv() => (),
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
+/// tracks tasks which must finish before the server exits
+pub struct Drain {
+    tx: mpsc::Sender<()>,
+    rx: mpsc::Receiver<()>,
+}
+
+/// held by a tracked task until it finishes
+#[derive(Clone)]
+pub struct DrainGuard {
+    _tx: mpsc::Sender<()>,
+}
+
+impl Drain {
+    /// create a tracker without tasks
+    pub fn new() -> Drain {
+        let (tx, rx) = mpsc::channel(1);
+
+        Drain { tx, rx }
+    }
+
+    /// get a guard for a new task
+    pub fn guard(&self) -> DrainGuard {
+        DrainGuard {
+            _tx: self.tx.clone(),
+        }
+    }
+
+    /// wait until all guards are dropped
+    pub async fn wait(self) {
+        let Drain { tx, mut rx } = self;
+        drop(tx);
+
+        // nothing is ever sent, recv returns None once all senders are gone
+        let _ = rx.recv().await;
+    }
+}
+
+impl Default for Drain {
+    fn default() -> Self {
+        Drain::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::time;
+
+    #[tokio::test]
+    async fn shutdown_test() {
+        let (handle, shutdown) = channel();
+        assert!(!shutdown.is_shutdown());
+
+        let mut waiting = shutdown.clone();
+        let task = tokio::spawn(async move { waiting.wait().await });
+        handle.shutdown();
+
+        time::timeout(Duration::from_secs(1), task)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(shutdown.is_shutdown());
+    }
+
+    #[tokio::test]
+    async fn drain_test() {
+        let drain = Drain::new();
+        let guard = drain.guard();
+        let task = tokio::spawn(async move {
+            time::sleep(Duration::from_millis(20)).await;
+            drop(guard);
+        });
+
+        time::timeout(Duration::from_secs(1), drain.wait())
+            .await
+            .unwrap();
+        assert!(task.await.is_ok());
+    }
+}
@@ -0,0 +1,414 @@
+//! behaviour shared by every storage backend,
+//! each backend runs `check` on an empty storage in its own tests
+
+use super::*;
+use crate::audit::{AuditKind, AuditQuery};
+
+/// check all repositories of the given empty storage
+pub async fn check(storage: &dyn Storage) {
+    check_users(storage).await;
+    check_messages(storage).await;
+    check_groups(storage).await;
+    check_sessions(storage).await;
+    check_last_seen(storage).await;
+    check_audit(storage).await;
+    storage.ping().await.unwrap();
+}
+
+/// a message saved before delivery was tracked, so `delivered` is NULL,
+/// each backend inserts it in its own way before running `check_legacy_msg`
+pub fn legacy_msg() -> UserMsg {
+    UserMsg {
+        id: None,
+        from_id: Some(1),
+        to_id: Some(5),
+        date_time: Some(chrono::Utc::now()),
+        content: Some("legacy".to_string()),
+        delivered: None,
+    }
+}
+
+/// check that the message of `legacy_msg`, inserted after `check`, counts as delivered
+pub async fn check_legacy_msg(storage: &dyn Storage) {
+    assert_eq!(1, storage.fetch_user_msgs(5).await.unwrap().len());
+    assert!(storage
+        .fetch_undelivered_user_msgs(5)
+        .await
+        .unwrap()
+        .is_empty());
+
+    // purged like delivered messages, the message left by `check` waits for its recipient
+    let later = chrono::Utc::now() + chrono::Duration::hours(1);
+    assert_eq!(1, storage.purge_msgs(later).await.unwrap());
+    assert!(storage.fetch_user_msgs(5).await.unwrap().is_empty());
+    assert_eq!(1, storage.fetch_user_msgs(2).await.unwrap().len());
+}
+
+async fn check_users(storage: &dyn Storage) {
+    assert!(storage.fetch_user(1).await.unwrap().is_none());
+
+    let user = User {
+        id: Some(1),
+        username: Some("alice".to_string()),
+        password: Some("1c8a".to_string()),
+        disabled: None,
+    };
+    storage.save_user(&user).await.unwrap();
+
+    let fetched = storage.fetch_user(1).await.unwrap().unwrap();
+    assert_eq!(Some(1), fetched.id);
+    assert_eq!(user.username, fetched.username);
+    assert_eq!(user.password, fetched.password);
+    assert_ne!(Some(true), fetched.disabled);
+    assert!(storage.fetch_user(2).await.unwrap().is_none());
+
+    // a user saved without an id gets one
+    let bob = User {
+        id: None,
+        username: Some("bob".to_string()),
+        ..user.clone()
+    };
+    let bob_id = storage.save_user(&bob).await.unwrap();
+    let ids: Vec<u64> = storage
+        .fetch_users()
+        .await
+        .unwrap()
+        .iter()
+        .map(|user| user.id.unwrap())
+        .collect();
+    assert_eq!(vec![1, bob_id], ids);
+
+    // only the fields which are not None are updated
+    let disabled = User {
+        id: Some(bob_id),
+        username: None,
+        password: None,
+        disabled: Some(true),
+    };
+    assert!(storage.update_user(&disabled).await.unwrap());
+    let fetched = storage.fetch_user(bob_id).await.unwrap().unwrap();
+    assert_eq!(Some(true), fetched.disabled);
+    assert_eq!(bob.username, fetched.username);
+    assert_eq!(bob.password, fetched.password);
+    let missing = User {
+        id: Some(bob_id + 1),
+        ..disabled
+    };
+    assert!(!storage.update_user(&missing).await.unwrap());
+
+    assert!(storage.remove_user(bob_id).await.unwrap());
+    assert!(!storage.remove_user(bob_id).await.unwrap());
+    assert!(storage.fetch_user(bob_id).await.unwrap().is_none());
+    assert_eq!(1, storage.fetch_users().await.unwrap().len());
+}
+
+async fn check_messages(storage: &dyn Storage) {
+    let user_msg = UserMsg {
+        id: None,
+        from_id: Some(1),
+        to_id: Some(2),
+        date_time: Some(chrono::Utc::now()),
+        content: Some("hello".to_string()),
+        delivered: None,
+    };
+    storage.save_user_msg(&user_msg).await.unwrap();
+    storage.save_user_msgs(&[]).await.unwrap();
+    storage
+        .save_user_msgs(&[user_msg.clone(), user_msg.clone()])
+        .await
+        .unwrap();
+
+    let msgs = storage.fetch_user_msgs(2).await.unwrap();
+    assert_eq!(3, msgs.len());
+    assert!(msgs.iter().all(|msg| msg.id.is_some()));
+    assert_eq!(user_msg.content, msgs[0].content);
+    assert!(storage.fetch_user_msgs(1).await.unwrap().is_empty());
+
+    // messages to an offline user wait until they are marked delivered
+    assert!(storage
+        .fetch_undelivered_user_msgs(2)
+        .await
+        .unwrap()
+        .is_empty());
+    for content in &["first", "second"] {
+        let offline_msg = UserMsg {
+            content: Some(content.to_string()),
+            delivered: Some(false),
+            ..user_msg.clone()
+        };
+        storage.save_user_msgs(&[offline_msg]).await.unwrap();
+    }
+    let undelivered = storage.fetch_undelivered_user_msgs(2).await.unwrap();
+    let contents: Vec<_> = undelivered
+        .iter()
+        .map(|msg| msg.content.as_deref().unwrap())
+        .collect();
+    assert_eq!(vec!["first", "second"], contents);
+    assert!(storage
+        .fetch_undelivered_user_msgs(1)
+        .await
+        .unwrap()
+        .is_empty());
+
+    assert_eq!(2, storage.count_undelivered_user_msgs().await.unwrap());
+
+    let ids: Vec<u64> = undelivered.iter().map(|msg| msg.id.unwrap()).collect();
+    storage.mark_user_msgs_delivered(&ids[..1]).await.unwrap();
+    storage.mark_user_msgs_delivered(&[]).await.unwrap();
+    let undelivered = storage.fetch_undelivered_user_msgs(2).await.unwrap();
+    assert_eq!(1, undelivered.len());
+    assert_eq!(1, storage.count_undelivered_user_msgs().await.unwrap());
+    assert_eq!(Some(ids[1]), undelivered[0].id);
+    assert_eq!(5, storage.fetch_user_msgs(2).await.unwrap().len());
+
+    let group_msg = GroupMsg {
+        id: None,
+        group_id: Some(1),
+        user_id: Some(1),
+        date_time: Some(chrono::Utc::now()),
+        content: Some("hello".to_string()),
+    };
+    storage.save_group_msg(&group_msg).await.unwrap();
+    storage
+        .save_group_msgs(&[group_msg.clone(), group_msg.clone()])
+        .await
+        .unwrap();
+
+    // old messages are purged, unless they still wait for their recipient
+    let hour = chrono::Duration::hours(1);
+    let now = chrono::Utc::now();
+    assert_eq!(0, storage.purge_msgs(now - hour).await.unwrap());
+    assert_eq!(7, storage.purge_msgs(now + hour).await.unwrap());
+    let left = storage.fetch_user_msgs(2).await.unwrap();
+    assert_eq!(1, left.len());
+    assert_eq!(Some(ids[1]), left[0].id);
+}
+
+async fn check_groups(storage: &dyn Storage) {
+    assert!(storage.fetch_group(1).await.unwrap().is_none());
+    assert!(storage.fetch_role(1).await.unwrap().is_none());
+
+    let group = Group {
+        id: Some(1),
+        group_name: Some("clush".to_string()),
+    };
+    storage.save_group(&group).await.unwrap();
+    let role = Role {
+        id: Some(1),
+        role_name: Some("owner".to_string()),
+    };
+    storage.save_role(&role).await.unwrap();
+
+    let fetched = storage.fetch_group(1).await.unwrap().unwrap();
+    assert_eq!(group.group_name, fetched.group_name);
+    let fetched = storage.fetch_role(1).await.unwrap().unwrap();
+    assert_eq!(role.role_name, fetched.role_name);
+
+    for (group_id, user_id) in &[(1, 1), (1, 2), (2, 1)] {
+        let member = GroupMember {
+            id: None,
+            group_id: Some(*group_id),
+            user_id: Some(*user_id),
+            role_id: Some(1),
+        };
+        storage.save_member(&member).await.unwrap();
+    }
+
+    let mut members: Vec<u64> = storage
+        .fetch_members(1)
+        .await
+        .unwrap()
+        .iter()
+        .map(|member| member.user_id.unwrap())
+        .collect();
+    members.sort_unstable();
+    assert_eq!(vec![1, 2], members);
+    assert!(storage.fetch_members(3).await.unwrap().is_empty());
+
+    let mut groups: Vec<u64> = storage
+        .fetch_memberships(1)
+        .await
+        .unwrap()
+        .iter()
+        .map(|member| member.group_id.unwrap())
+        .collect();
+    groups.sort_unstable();
+    assert_eq!(vec![1, 2], groups);
+    assert!(storage.fetch_memberships(3).await.unwrap().is_empty());
+
+    assert!(storage.remove_member(1, 2).await.unwrap());
+    assert!(!storage.remove_member(1, 2).await.unwrap());
+    assert_eq!(1, storage.fetch_members(1).await.unwrap().len());
+
+    // a group saved without an id gets one, and takes its members along when removed
+    let lounge = Group {
+        id: None,
+        group_name: Some("lounge".to_string()),
+    };
+    let lounge_id = storage.save_group(&lounge).await.unwrap();
+    let member = GroupMember {
+        id: None,
+        group_id: Some(lounge_id),
+        user_id: Some(1),
+        role_id: None,
+    };
+    storage.save_member(&member).await.unwrap();
+    let ids: Vec<u64> = storage
+        .fetch_groups()
+        .await
+        .unwrap()
+        .iter()
+        .map(|group| group.id.unwrap())
+        .collect();
+    assert_eq!(vec![1, lounge_id], ids);
+    assert!(storage.remove_group(lounge_id).await.unwrap());
+    assert!(!storage.remove_group(lounge_id).await.unwrap());
+    assert!(storage.fetch_members(lounge_id).await.unwrap().is_empty());
+    assert_eq!(1, storage.fetch_groups().await.unwrap().len());
+
+    // a removed user is no member anymore
+    assert!(storage.remove_user(1).await.unwrap());
+    assert!(storage.fetch_members(1).await.unwrap().is_empty());
+    assert!(storage.fetch_members(2).await.unwrap().is_empty());
+}
+
+async fn check_sessions(storage: &dyn Storage) {
+    assert!(storage.fetch_session(1).await.unwrap().is_none());
+
+    for (user_id, address) in &[(1, "127.0.0.1:1"), (2, "127.0.0.1:2"), (1, "127.0.0.1:3")] {
+        let session = Session {
+            user_id: Some(*user_id),
+            address: Some(address.to_string()),
+            login_time: Some(chrono::Utc::now()),
+        };
+        storage.save_session(&session).await.unwrap();
+    }
+
+    // a later session replaces the former one
+    let fetched = storage.fetch_session(1).await.unwrap().unwrap();
+    assert_eq!(Some("127.0.0.1:3".to_string()), fetched.address);
+    assert_eq!(2, storage.fetch_sessions().await.unwrap().len());
+
+    storage.remove_session(1).await.unwrap();
+    assert!(storage.fetch_session(1).await.unwrap().is_none());
+    assert_eq!(1, storage.fetch_sessions().await.unwrap().len());
+
+    storage.clear_sessions().await.unwrap();
+    assert!(storage.fetch_sessions().await.unwrap().is_empty());
+}
+
+async fn check_last_seen(storage: &dyn Storage) {
+    assert!(storage.fetch_last_seen(1).await.unwrap().is_none());
+
+    let now = chrono::Utc::now();
+    for date_time in &[now - chrono::Duration::hours(1), now] {
+        let last_seen = LastSeen {
+            user_id: Some(1),
+            date_time: Some(*date_time),
+        };
+        storage.save_last_seen(&last_seen).await.unwrap();
+    }
+
+    // a later record replaces the former one
+    let fetched = storage.fetch_last_seen(1).await.unwrap().unwrap();
+    assert_eq!(
+        Some(now.timestamp()),
+        fetched.date_time.map(|at| at.timestamp())
+    );
+    assert!(storage.fetch_last_seen(2).await.unwrap().is_none());
+}
+
+async fn check_audit(storage: &dyn Storage) {
+    let all = AuditQuery::default();
+    assert!(storage.fetch_audit(&all).await.unwrap().is_empty());
+
+    let now = chrono::Utc::now();
+    let hour = chrono::Duration::hours(1);
+    let events = [
+        AuditEvent::new(AuditKind::LoginFailed, 1)
+            .address("127.0.0.1:1")
+            .detail("invalid password"),
+        AuditEvent::new(AuditKind::Login, 1).address("127.0.0.1:1"),
+        AuditEvent::new(AuditKind::PasswordChanged, 0).target(2),
+    ];
+    for (event, hours_ago) in events.iter().zip(&[2, 1, 0]) {
+        let event = AuditEvent {
+            date_time: Some(now - hour * *hours_ago),
+            ..event.clone()
+        };
+        storage.append_audit(&event).await.unwrap();
+    }
+
+    // events come back in the order they were appended
+    let fetched = storage.fetch_audit(&all).await.unwrap();
+    let kinds: Vec<_> = fetched
+        .iter()
+        .map(|event| event.kind.as_deref().unwrap())
+        .collect();
+    assert_eq!(vec!["loginFailed", "login", "passwordChanged"], kinds);
+    assert!(fetched.iter().all(|event| event.id.is_some()));
+    assert_eq!(Some("127.0.0.1:1"), fetched[0].address.as_deref());
+    assert_eq!(Some("invalid password"), fetched[0].detail.as_deref());
+    assert_eq!(Some(2), fetched[2].target_id);
+
+    let count = |query: AuditQuery| async move { storage.fetch_audit(&query).await.unwrap() };
+    let by_actor = count(AuditQuery {
+        actor_id: Some(1),
+        ..AuditQuery::default()
+    })
+    .await;
+    assert_eq!(2, by_actor.len());
+    let by_kind = count(AuditQuery {
+        kind: Some(AuditKind::Login),
+        ..AuditQuery::default()
+    })
+    .await;
+    assert_eq!(1, by_kind.len());
+    assert_eq!(Some(1), by_kind[0].actor_id);
+    let half = hour / 2;
+    let in_range = count(AuditQuery {
+        from: Some(now - hour - half),
+        to: Some(now - half),
+        ..AuditQuery::default()
+    })
+    .await;
+    assert_eq!(1, in_range.len());
+    assert_eq!(Some("login"), in_range[0].kind.as_deref());
+    let limited = count(AuditQuery {
+        from: Some(now - hour - half),
+        limit: Some(1),
+        ..AuditQuery::default()
+    })
+    .await;
+    assert_eq!(1, limited.len());
+    assert_eq!(Some("login"), limited[0].kind.as_deref());
+
+    // times within a second compare by their fraction, whole seconds included
+    let second = chrono::DateTime::parse_from_rfc3339("2021-06-01T12:00:00Z")
+        .unwrap()
+        .with_timezone(&chrono::Utc);
+    let ms = chrono::Duration::milliseconds;
+    for (actor_id, offset) in &[(10, 0), (11, 250), (12, 500), (13, 1000)] {
+        let event = AuditEvent {
+            date_time: Some(second + ms(*offset)),
+            ..AuditEvent::new(AuditKind::Login, *actor_id)
+        };
+        storage.append_audit(&event).await.unwrap();
+    }
+    let actors = |from: i64, to: i64| async move {
+        let query = AuditQuery {
+            from: Some(second + ms(from)),
+            to: Some(second + ms(to)),
+            ..AuditQuery::default()
+        };
+        let events = storage.fetch_audit(&query).await.unwrap();
+        events
+            .iter()
+            .map(|event| event.actor_id.unwrap())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(vec![10], actors(0, 1).await);
+    assert_eq!(vec![11], actors(250, 500).await);
+    assert_eq!(vec![11, 12], actors(1, 1000).await);
+    assert_eq!(vec![12, 13], actors(500, 1001).await);
+}
@@ -0,0 +1,397 @@
+use super::*;
+use dashmap::DashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// storage keeping everything in memory, all data is lost when dropped
+///
+/// entities saved without an id get an auto-generated one
+#[derive(Default)]
+pub struct MemoryStorage {
+    users: DashMap<u64, User>,
+    user_msgs: Mutex<Vec<UserMsg>>,
+    groups: DashMap<u64, Group>,
+    members: Mutex<Vec<GroupMember>>,
+    group_msgs: Mutex<Vec<GroupMsg>>,
+    roles: DashMap<u64, Role>,
+    sessions: DashMap<u64, Session>,
+    last_seen: DashMap<u64, LastSeen>,
+    audit: Mutex<Vec<AuditEvent>>,
+    last_id: AtomicU64,
+}
+
+impl MemoryStorage {
+    /// create an empty storage
+    pub fn new() -> MemoryStorage {
+        MemoryStorage::default()
+    }
+
+    /// return the given id, or generate a new one if None
+    fn id_or_next(&self, id: Option<u64>) -> u64 {
+        match id {
+            Some(id) => {
+                self.last_id.fetch_max(id, Ordering::SeqCst);
+                id
+            }
+            None => self.last_id.fetch_add(1, Ordering::SeqCst) + 1,
+        }
+    }
+}
+
+#[async_trait]
+impl UserRepository for MemoryStorage {
+    async fn fetch_user(&self, id: u64) -> Result<Option<User>> {
+        Ok(self.users.get(&id).map(|user| user.clone()))
+    }
+
+    async fn fetch_users(&self) -> Result<Vec<User>> {
+        let mut users: Vec<User> = self.users.iter().map(|user| user.clone()).collect();
+        users.sort_by_key(|user| user.id);
+
+        Ok(users)
+    }
+
+    async fn save_user(&self, user: &User) -> Result<u64> {
+        let id = self.id_or_next(user.id);
+        let user = User {
+            id: Some(id),
+            ..user.clone()
+        };
+        self.users.insert(id, user);
+
+        Ok(id)
+    }
+
+    async fn update_user(&self, user: &User) -> Result<bool> {
+        let id = match user.id {
+            Some(id) => id,
+            None => return Ok(false),
+        };
+        let mut saved = match self.users.get_mut(&id) {
+            Some(saved) => saved,
+            None => return Ok(false),
+        };
+        if user.username.is_some() {
+            saved.username = user.username.clone();
+        }
+        if user.password.is_some() {
+            saved.password = user.password.clone();
+        }
+        if user.disabled.is_some() {
+            saved.disabled = user.disabled;
+        }
+
+        Ok(true)
+    }
+
+    async fn remove_user(&self, id: u64) -> Result<bool> {
+        self.members
+            .lock()
+            .unwrap()
+            .retain(|member| member.user_id != Some(id));
+
+        Ok(self.users.remove(&id).is_some())
+    }
+}
+
+#[async_trait]
+impl MessageRepository for MemoryStorage {
+    async fn save_user_msg(&self, msg: &UserMsg) -> Result<()> {
+        let msg = UserMsg {
+            id: Some(self.id_or_next(msg.id)),
+            ..msg.clone()
+        };
+        self.user_msgs.lock().unwrap().push(msg);
+
+        Ok(())
+    }
+
+    async fn save_user_msgs(&self, msgs: &[UserMsg]) -> Result<()> {
+        for msg in msgs {
+            self.save_user_msg(msg).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn fetch_user_msgs(&self, to_id: u64) -> Result<Vec<UserMsg>> {
+        let msgs = self.user_msgs.lock().unwrap();
+
+        Ok(msgs
+            .iter()
+            .filter(|msg| msg.to_id == Some(to_id))
+            .cloned()
+            .collect())
+    }
+
+    async fn fetch_undelivered_user_msgs(&self, to_id: u64) -> Result<Vec<UserMsg>> {
+        let msgs = self.user_msgs.lock().unwrap();
+
+        Ok(msgs
+            .iter()
+            .filter(|msg| msg.to_id == Some(to_id) && msg.delivered == Some(false))
+            .cloned()
+            .collect())
+    }
+
+    async fn count_undelivered_user_msgs(&self) -> Result<u64> {
+        let msgs = self.user_msgs.lock().unwrap();
+
+        Ok(msgs
+            .iter()
+            .filter(|msg| msg.delivered == Some(false))
+            .count() as u64)
+    }
+
+    async fn mark_user_msgs_delivered(&self, ids: &[u64]) -> Result<()> {
+        let mut msgs = self.user_msgs.lock().unwrap();
+        for msg in msgs.iter_mut() {
+            if msg.id.is_some_and(|id| ids.contains(&id)) {
+                msg.delivered = Some(true);
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn save_group_msg(&self, msg: &GroupMsg) -> Result<()> {
+        let msg = GroupMsg {
+            id: Some(self.id_or_next(msg.id)),
+            ..msg.clone()
+        };
+        self.group_msgs.lock().unwrap().push(msg);
+
+        Ok(())
+    }
+
+    async fn save_group_msgs(&self, msgs: &[GroupMsg]) -> Result<()> {
+        for msg in msgs {
+            self.save_group_msg(msg).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn purge_msgs(&self, before: DateTime<Utc>) -> Result<u64> {
+        let is_old = |date_time: Option<DateTime<Utc>>| date_time.is_some_and(|at| at < before);
+
+        let mut user_msgs = self.user_msgs.lock().unwrap();
+        let count = user_msgs.len();
+        user_msgs.retain(|msg| msg.delivered == Some(false) || !is_old(msg.date_time));
+        let mut removed = count - user_msgs.len();
+
+        let mut group_msgs = self.group_msgs.lock().unwrap();
+        let count = group_msgs.len();
+        group_msgs.retain(|msg| !is_old(msg.date_time));
+        removed += count - group_msgs.len();
+
+        Ok(removed as u64)
+    }
+}
+
+#[async_trait]
+impl GroupRepository for MemoryStorage {
+    async fn fetch_group(&self, id: u64) -> Result<Option<Group>> {
+        Ok(self.groups.get(&id).map(|group| group.clone()))
+    }
+
+    async fn fetch_groups(&self) -> Result<Vec<Group>> {
+        let mut groups: Vec<Group> = self.groups.iter().map(|group| group.clone()).collect();
+        groups.sort_by_key(|group| group.id);
+
+        Ok(groups)
+    }
+
+    async fn save_group(&self, group: &Group) -> Result<u64> {
+        let id = self.id_or_next(group.id);
+        let group = Group {
+            id: Some(id),
+            ..group.clone()
+        };
+        self.groups.insert(id, group);
+
+        Ok(id)
+    }
+
+    async fn remove_group(&self, id: u64) -> Result<bool> {
+        self.members
+            .lock()
+            .unwrap()
+            .retain(|member| member.group_id != Some(id));
+
+        Ok(self.groups.remove(&id).is_some())
+    }
+
+    async fn fetch_members(&self, group_id: u64) -> Result<Vec<GroupMember>> {
+        let members = self.members.lock().unwrap();
+
+        Ok(members
+            .iter()
+            .filter(|member| member.group_id == Some(group_id))
+            .cloned()
+            .collect())
+    }
+
+    async fn fetch_memberships(&self, user_id: u64) -> Result<Vec<GroupMember>> {
+        let members = self.members.lock().unwrap();
+
+        Ok(members
+            .iter()
+            .filter(|member| member.user_id == Some(user_id))
+            .cloned()
+            .collect())
+    }
+
+    async fn save_member(&self, member: &GroupMember) -> Result<()> {
+        let member = GroupMember {
+            id: Some(self.id_or_next(member.id)),
+            ..member.clone()
+        };
+        self.members.lock().unwrap().push(member);
+
+        Ok(())
+    }
+
+    async fn remove_member(&self, group_id: u64, user_id: u64) -> Result<bool> {
+        let mut members = self.members.lock().unwrap();
+        let before = members.len();
+        members
+            .retain(|member| member.group_id != Some(group_id) || member.user_id != Some(user_id));
+
+        Ok(members.len() < before)
+    }
+
+    async fn fetch_role(&self, id: u64) -> Result<Option<Role>> {
+        Ok(self.roles.get(&id).map(|role| role.clone()))
+    }
+
+    async fn save_role(&self, role: &Role) -> Result<u64> {
+        let id = self.id_or_next(role.id);
+        let role = Role {
+            id: Some(id),
+            ..role.clone()
+        };
+        self.roles.insert(id, role);
+
+        Ok(id)
+    }
+}
+
+#[async_trait]
+impl AuditRepository for MemoryStorage {
+    async fn append_audit(&self, event: &AuditEvent) -> Result<()> {
+        let event = AuditEvent {
+            id: Some(self.id_or_next(event.id)),
+            ..event.clone()
+        };
+        self.audit.lock().unwrap().push(event);
+
+        Ok(())
+    }
+
+    async fn fetch_audit(&self, query: &AuditQuery) -> Result<Vec<AuditEvent>> {
+        let events = self.audit.lock().unwrap();
+        let limit = query.limit.map_or(usize::MAX, |limit| limit as usize);
+
+        Ok(events
+            .iter()
+            .filter(|event| query.matches(event))
+            .take(limit)
+            .cloned()
+            .collect())
+    }
+}
+
+#[async_trait]
+impl HealthCheck for MemoryStorage {
+    async fn ping(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl SessionRepository for MemoryStorage {
+    async fn save_session(&self, session: &Session) -> Result<()> {
+        if let Some(user_id) = session.user_id {
+            self.sessions.insert(user_id, session.clone());
+        }
+
+        Ok(())
+    }
+
+    async fn fetch_session(&self, user_id: u64) -> Result<Option<Session>> {
+        Ok(self.sessions.get(&user_id).map(|session| session.clone()))
+    }
+
+    async fn fetch_sessions(&self) -> Result<Vec<Session>> {
+        Ok(self
+            .sessions
+            .iter()
+            .map(|session| session.value().clone())
+            .collect())
+    }
+
+    async fn remove_session(&self, user_id: u64) -> Result<()> {
+        self.sessions.remove(&user_id);
+
+        Ok(())
+    }
+
+    async fn clear_sessions(&self) -> Result<()> {
+        self.sessions.clear();
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl PresenceRepository for MemoryStorage {
+    async fn save_last_seen(&self, last_seen: &LastSeen) -> Result<()> {
+        if let Some(user_id) = last_seen.user_id {
+            self.last_seen.insert(user_id, last_seen.clone());
+        }
+
+        Ok(())
+    }
+
+    async fn fetch_last_seen(&self, user_id: u64) -> Result<Option<LastSeen>> {
+        Ok(self
+            .last_seen
+            .get(&user_id)
+            .map(|last_seen| last_seen.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::behaviour;
+
+    #[tokio::test]
+    async fn memory_behaviour_test() {
+        let storage = MemoryStorage::new();
+        behaviour::check(&storage).await;
+
+        storage
+            .save_user_msg(&behaviour::legacy_msg())
+            .await
+            .unwrap();
+        behaviour::check_legacy_msg(&storage).await;
+    }
+
+    #[tokio::test]
+    async fn generated_id_test() {
+        let storage = MemoryStorage::new();
+        let user = User {
+            id: Some(5),
+            username: Some("alice".to_string()),
+            password: Some("1c8a".to_string()),
+            disabled: None,
+        };
+        assert_eq!(5, storage.save_user(&user).await.unwrap());
+        let user = User { id: None, ..user };
+        assert_eq!(6, storage.save_user(&user).await.unwrap());
+
+        assert!(storage.fetch_user(6).await.unwrap().is_some());
+    }
+}
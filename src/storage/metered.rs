@@ -0,0 +1,235 @@
+use super::*;
+use prometheus::HistogramVec;
+use std::future::Future;
+
+/// storage recording the latency of every operation of another storage,
+/// labelled by the name of the operation
+pub struct MeteredStorage {
+    inner: Arc<dyn Storage>,
+    latency: HistogramVec,
+}
+
+impl MeteredStorage {
+    /// record the operations of `inner` in the given histogram,
+    /// which has an `operation` label
+    pub fn new(inner: Arc<dyn Storage>, latency: HistogramVec) -> MeteredStorage {
+        MeteredStorage { inner, latency }
+    }
+
+    /// run an operation, observing how long it takes
+    async fn timed<T, F>(&self, operation: &str, future: F) -> T
+    where
+        F: Future<Output = T>,
+    {
+        let _timer = self.latency.with_label_values(&[operation]).start_timer();
+
+        future.await
+    }
+}
+
+#[async_trait]
+impl UserRepository for MeteredStorage {
+    async fn fetch_user(&self, id: u64) -> Result<Option<User>> {
+        self.timed("fetch_user", self.inner.fetch_user(id)).await
+    }
+
+    async fn fetch_users(&self) -> Result<Vec<User>> {
+        self.timed("fetch_users", self.inner.fetch_users()).await
+    }
+
+    async fn save_user(&self, user: &User) -> Result<u64> {
+        self.timed("save_user", self.inner.save_user(user)).await
+    }
+
+    async fn update_user(&self, user: &User) -> Result<bool> {
+        self.timed("update_user", self.inner.update_user(user))
+            .await
+    }
+
+    async fn remove_user(&self, id: u64) -> Result<bool> {
+        self.timed("remove_user", self.inner.remove_user(id)).await
+    }
+}
+
+#[async_trait]
+impl MessageRepository for MeteredStorage {
+    async fn save_user_msg(&self, msg: &UserMsg) -> Result<()> {
+        self.timed("save_user_msg", self.inner.save_user_msg(msg))
+            .await
+    }
+
+    async fn save_user_msgs(&self, msgs: &[UserMsg]) -> Result<()> {
+        self.timed("save_user_msgs", self.inner.save_user_msgs(msgs))
+            .await
+    }
+
+    async fn fetch_user_msgs(&self, to_id: u64) -> Result<Vec<UserMsg>> {
+        self.timed("fetch_user_msgs", self.inner.fetch_user_msgs(to_id))
+            .await
+    }
+
+    async fn fetch_undelivered_user_msgs(&self, to_id: u64) -> Result<Vec<UserMsg>> {
+        let future = self.inner.fetch_undelivered_user_msgs(to_id);
+
+        self.timed("fetch_undelivered_user_msgs", future).await
+    }
+
+    async fn count_undelivered_user_msgs(&self) -> Result<u64> {
+        let future = self.inner.count_undelivered_user_msgs();
+
+        self.timed("count_undelivered_user_msgs", future).await
+    }
+
+    async fn mark_user_msgs_delivered(&self, ids: &[u64]) -> Result<()> {
+        let future = self.inner.mark_user_msgs_delivered(ids);
+
+        self.timed("mark_user_msgs_delivered", future).await
+    }
+
+    async fn save_group_msg(&self, msg: &GroupMsg) -> Result<()> {
+        self.timed("save_group_msg", self.inner.save_group_msg(msg))
+            .await
+    }
+
+    async fn save_group_msgs(&self, msgs: &[GroupMsg]) -> Result<()> {
+        self.timed("save_group_msgs", self.inner.save_group_msgs(msgs))
+            .await
+    }
+
+    async fn purge_msgs(&self, before: DateTime<Utc>) -> Result<u64> {
+        self.timed("purge_msgs", self.inner.purge_msgs(before))
+            .await
+    }
+}
+
+#[async_trait]
+impl GroupRepository for MeteredStorage {
+    async fn fetch_group(&self, id: u64) -> Result<Option<Group>> {
+        self.timed("fetch_group", self.inner.fetch_group(id)).await
+    }
+
+    async fn fetch_groups(&self) -> Result<Vec<Group>> {
+        self.timed("fetch_groups", self.inner.fetch_groups()).await
+    }
+
+    async fn save_group(&self, group: &Group) -> Result<u64> {
+        self.timed("save_group", self.inner.save_group(group)).await
+    }
+
+    async fn remove_group(&self, id: u64) -> Result<bool> {
+        self.timed("remove_group", self.inner.remove_group(id))
+            .await
+    }
+
+    async fn fetch_members(&self, group_id: u64) -> Result<Vec<GroupMember>> {
+        self.timed("fetch_members", self.inner.fetch_members(group_id))
+            .await
+    }
+
+    async fn fetch_memberships(&self, user_id: u64) -> Result<Vec<GroupMember>> {
+        self.timed("fetch_memberships", self.inner.fetch_memberships(user_id))
+            .await
+    }
+
+    async fn save_member(&self, member: &GroupMember) -> Result<()> {
+        self.timed("save_member", self.inner.save_member(member))
+            .await
+    }
+
+    async fn remove_member(&self, group_id: u64, user_id: u64) -> Result<bool> {
+        let future = self.inner.remove_member(group_id, user_id);
+
+        self.timed("remove_member", future).await
+    }
+
+    async fn fetch_role(&self, id: u64) -> Result<Option<Role>> {
+        self.timed("fetch_role", self.inner.fetch_role(id)).await
+    }
+
+    async fn save_role(&self, role: &Role) -> Result<u64> {
+        self.timed("save_role", self.inner.save_role(role)).await
+    }
+}
+
+#[async_trait]
+impl SessionRepository for MeteredStorage {
+    async fn save_session(&self, session: &Session) -> Result<()> {
+        self.timed("save_session", self.inner.save_session(session))
+            .await
+    }
+
+    async fn fetch_session(&self, user_id: u64) -> Result<Option<Session>> {
+        self.timed("fetch_session", self.inner.fetch_session(user_id))
+            .await
+    }
+
+    async fn fetch_sessions(&self) -> Result<Vec<Session>> {
+        self.timed("fetch_sessions", self.inner.fetch_sessions())
+            .await
+    }
+
+    async fn remove_session(&self, user_id: u64) -> Result<()> {
+        self.timed("remove_session", self.inner.remove_session(user_id))
+            .await
+    }
+
+    async fn clear_sessions(&self) -> Result<()> {
+        self.timed("clear_sessions", self.inner.clear_sessions())
+            .await
+    }
+}
+
+#[async_trait]
+impl PresenceRepository for MeteredStorage {
+    async fn save_last_seen(&self, last_seen: &LastSeen) -> Result<()> {
+        self.timed("save_last_seen", self.inner.save_last_seen(last_seen))
+            .await
+    }
+
+    async fn fetch_last_seen(&self, user_id: u64) -> Result<Option<LastSeen>> {
+        self.timed("fetch_last_seen", self.inner.fetch_last_seen(user_id))
+            .await
+    }
+}
+
+#[async_trait]
+impl AuditRepository for MeteredStorage {
+    async fn append_audit(&self, event: &AuditEvent) -> Result<()> {
+        self.timed("append_audit", self.inner.append_audit(event))
+            .await
+    }
+
+    async fn fetch_audit(&self, query: &AuditQuery) -> Result<Vec<AuditEvent>> {
+        self.timed("fetch_audit", self.inner.fetch_audit(query))
+            .await
+    }
+}
+
+#[async_trait]
+impl HealthCheck for MeteredStorage {
+    async fn ping(&self) -> Result<()> {
+        self.timed("ping", self.inner.ping()).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::behaviour;
+    use prometheus::HistogramOpts;
+
+    #[tokio::test]
+    async fn metered_storage_test() {
+        let latency =
+            HistogramVec::new(HistogramOpts::new("latency", "latency"), &["operation"]).unwrap();
+        let storage = MeteredStorage::new(Arc::new(MemoryStorage::new()), latency.clone());
+        behaviour::check(&storage).await;
+
+        assert_eq!(
+            5,
+            latency
+                .with_label_values(&["fetch_user"])
+                .get_sample_count()
+        );
+    }
+}
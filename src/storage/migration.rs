@@ -0,0 +1,209 @@
+//! versioned schema migrations embedded in the binary
+//!
+//! applied migrations are recorded in the `clush_migration` table,
+//! a database migrated by a newer binary is refused
+
+use super::*;
+use chrono::{DateTime, Utc};
+use rbatis::core::db::DriverType;
+use rbatis::crud::CRUD;
+use rbatis::rbatis::Rbatis;
+
+/// a versioned change of the database schema
+pub struct Migration {
+    pub version: u64,
+    pub name: &'static str,
+    sql: &'static str,
+}
+
+/// migrations of a SQLite database, in order
+static SQLITE_MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "init",
+        sql: include_str!("../../migrations/sqlite/0001_init.sql"),
+    },
+    Migration {
+        version: 2,
+        name: "delivered",
+        sql: include_str!("../../migrations/sqlite/0002_delivered.sql"),
+    },
+    Migration {
+        version: 3,
+        name: "disabled",
+        sql: include_str!("../../migrations/sqlite/0003_disabled.sql"),
+    },
+    Migration {
+        version: 4,
+        name: "audit",
+        sql: include_str!("../../migrations/sqlite/0004_audit.sql"),
+    },
+    Migration {
+        version: 5,
+        name: "last_seen",
+        sql: include_str!("../../migrations/sqlite/0005_last_seen.sql"),
+    },
+    Migration {
+        version: 6,
+        name: "audit_time",
+        sql: include_str!("../../migrations/sqlite/0006_audit_time.sql"),
+    },
+];
+
+/// migrations of a PostgreSQL database, in order
+static POSTGRES_MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "init",
+        sql: include_str!("../../migrations/postgres/0001_init.sql"),
+    },
+    Migration {
+        version: 2,
+        name: "delivered",
+        sql: include_str!("../../migrations/postgres/0002_delivered.sql"),
+    },
+    Migration {
+        version: 3,
+        name: "disabled",
+        sql: include_str!("../../migrations/postgres/0003_disabled.sql"),
+    },
+    Migration {
+        version: 4,
+        name: "audit",
+        sql: include_str!("../../migrations/postgres/0004_audit.sql"),
+    },
+    Migration {
+        version: 5,
+        name: "last_seen",
+        sql: include_str!("../../migrations/postgres/0005_last_seen.sql"),
+    },
+];
+
+static SQLITE_MIGRATION_TABLE: &str = "CREATE TABLE IF NOT EXISTS clush_migration (
+    version INTEGER PRIMARY KEY,
+    name TEXT NOT NULL,
+    applied_at TEXT NOT NULL
+)";
+
+static POSTGRES_MIGRATION_TABLE: &str = "CREATE TABLE IF NOT EXISTS clush_migration (
+    version BIGINT PRIMARY KEY,
+    name TEXT NOT NULL,
+    applied_at TIMESTAMPTZ NOT NULL
+)";
+
+/// record of an applied migration
+#[crud_enable(id_name: version | formats_pg: "applied_at:{}::timestamptz")]
+#[derive(Clone, Debug)]
+struct ClushMigration {
+    version: Option<u64>,
+    name: Option<String>,
+    applied_at: Option<DateTime<Utc>>,
+}
+
+/// get the migrations of the database driver
+fn migrations_of(driver: DriverType) -> Result<(&'static str, &'static [Migration])> {
+    match driver {
+        DriverType::Sqlite => Ok((SQLITE_MIGRATION_TABLE, SQLITE_MIGRATIONS)),
+        DriverType::Postgres => Ok((POSTGRES_MIGRATION_TABLE, POSTGRES_MIGRATIONS)),
+        driver => {
+            let msg = format!("no migrations for database driver {:?}", driver);
+            Err(StorageError::Schema(msg))
+        }
+    }
+}
+
+/// the latest schema version known by this binary
+pub fn latest_version(db: &Rbatis) -> Result<u64> {
+    let (_, migrations) = migrations_of(db.driver_type()?)?;
+
+    Ok(migrations.last().map_or(0, |migration| migration.version))
+}
+
+/// get the schema version of the database, 0 if nothing is applied
+pub async fn schema_version(db: &Rbatis) -> Result<u64> {
+    let (table, _) = migrations_of(db.driver_type()?)?;
+    db.exec("", table).await?;
+
+    let applied = db.fetch_list::<ClushMigration>("").await?;
+
+    Ok(applied
+        .iter()
+        .filter_map(|migration| migration.version)
+        .max()
+        .unwrap_or(0))
+}
+
+/// check that the schema of the database is neither newer nor older than this binary
+pub async fn check(db: &Rbatis) -> Result<()> {
+    let current = schema_version(db).await?;
+    let latest = latest_version(db)?;
+
+    if current > latest {
+        let msg = format!(
+            "schema version {} is newer than version {} supported by this server",
+            current, latest
+        );
+        return Err(StorageError::Schema(msg));
+    }
+    if current < latest {
+        let msg = format!(
+            "schema version {} is older than version {}, run `clush-server migrate` first",
+            current, latest
+        );
+        return Err(StorageError::Schema(msg));
+    }
+
+    Ok(())
+}
+
+/// apply all pending migrations, each in its own transaction,
+/// return the versions applied
+pub async fn migrate(db: &Rbatis) -> Result<Vec<u64>> {
+    let (_, migrations) = migrations_of(db.driver_type()?)?;
+    let current = schema_version(db).await?;
+    let latest = latest_version(db)?;
+
+    if current > latest {
+        let msg = format!(
+            "schema version {} is newer than version {} supported by this server",
+            current, latest
+        );
+        return Err(StorageError::Schema(msg));
+    }
+
+    let mut applied = vec![];
+    for migration in migrations.iter().filter(|m| m.version > current) {
+        let tx_id = db.begin_tx().await?;
+        if let Err(e) = apply(db, &tx_id, migration).await {
+            db.rollback(&tx_id).await?;
+            let msg = format!(
+                "failed to apply migration {} ({}): {}",
+                migration.version, migration.name, e
+            );
+            return Err(StorageError::Schema(msg));
+        }
+        db.commit(&tx_id).await?;
+
+        applied.push(migration.version);
+    }
+
+    Ok(applied)
+}
+
+/// apply a migration within the given transaction and record it
+async fn apply(db: &Rbatis, tx_id: &str, migration: &Migration) -> Result<()> {
+    for statement in migration.sql.split(';').map(str::trim) {
+        if !statement.is_empty() {
+            db.exec(tx_id, statement).await?;
+        }
+    }
+
+    let record = ClushMigration {
+        version: Some(migration.version),
+        name: Some(migration.name.to_string()),
+        applied_at: Some(Utc::now()),
+    };
+    db.save::<ClushMigration>(tx_id, &record).await?;
+
+    Ok(())
+}
@@ -0,0 +1,232 @@
+//! persistence layer of clush server
+//!
+//! the server only talks to the repository traits defined here,
+//! so any backend implementing them can be plugged into `ClushServer`
+
+mod memory;
+mod metered;
+mod migration;
+mod sql;
+
+#[cfg(test)]
+mod behaviour;
+
+pub use self::memory::MemoryStorage;
+pub use self::metered::MeteredStorage;
+pub use self::sql::RbatisStorage;
+
+use crate::audit::AuditQuery;
+use crate::config::{ClushConfig, StorageBackend};
+use crate::entity::*;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+
+/// result of a storage operation
+pub type Result<T> = std::result::Result<T, StorageError>;
+
+/// error occurred in a storage backend
+#[derive(Debug)]
+pub enum StorageError {
+    /// the backend failed to execute the operation
+    Backend(String),
+    /// the database schema does not match the server
+    Schema(String),
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageError::Backend(msg) => write!(f, "storage backend error: {}", msg),
+            StorageError::Schema(msg) => write!(f, "database schema error: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+impl From<rbatis::core::Error> for StorageError {
+    fn from(e: rbatis::core::Error) -> Self {
+        StorageError::Backend(e.to_string())
+    }
+}
+
+impl From<StorageError> for io::Error {
+    fn from(e: StorageError) -> Self {
+        io::Error::other(e)
+    }
+}
+
+/// persistence of users
+#[async_trait]
+pub trait UserRepository: Send + Sync {
+    /// fetch a user by id, None if not exists
+    async fn fetch_user(&self, id: u64) -> Result<Option<User>>;
+
+    /// fetch all users, ordered by id
+    async fn fetch_users(&self) -> Result<Vec<User>>;
+
+    /// save a new user, return its id, generated if None
+    async fn save_user(&self, user: &User) -> Result<u64>;
+
+    /// update the fields of a user which are not None, return whether the user exists
+    async fn update_user(&self, user: &User) -> Result<bool>;
+
+    /// remove a user and their memberships, return whether the user existed
+    async fn remove_user(&self, id: u64) -> Result<bool>;
+}
+
+/// persistence of user and group messages
+#[async_trait]
+pub trait MessageRepository: Send + Sync {
+    /// save a message sent to a user
+    async fn save_user_msg(&self, msg: &UserMsg) -> Result<()>;
+
+    /// save messages sent to users at once
+    async fn save_user_msgs(&self, msgs: &[UserMsg]) -> Result<()>;
+
+    /// fetch all messages sent to a user, in the order they were saved
+    async fn fetch_user_msgs(&self, to_id: u64) -> Result<Vec<UserMsg>>;
+
+    /// fetch the messages not delivered to a user yet, in the order they were saved
+    async fn fetch_undelivered_user_msgs(&self, to_id: u64) -> Result<Vec<UserMsg>>;
+
+    /// count the messages not delivered to any user yet
+    async fn count_undelivered_user_msgs(&self) -> Result<u64>;
+
+    /// mark messages as delivered to their recipient
+    async fn mark_user_msgs_delivered(&self, ids: &[u64]) -> Result<()>;
+
+    /// save a message sent to a group
+    async fn save_group_msg(&self, msg: &GroupMsg) -> Result<()>;
+
+    /// save messages sent to groups at once
+    async fn save_group_msgs(&self, msgs: &[GroupMsg]) -> Result<()>;
+
+    /// remove the group messages and the delivered user messages sent before the given time,
+    /// return how many were removed
+    async fn purge_msgs(&self, before: DateTime<Utc>) -> Result<u64>;
+}
+
+/// persistence of groups, their members and the roles of members
+#[async_trait]
+pub trait GroupRepository: Send + Sync {
+    /// fetch a group by id, None if not exists
+    async fn fetch_group(&self, id: u64) -> Result<Option<Group>>;
+
+    /// fetch all groups, ordered by id
+    async fn fetch_groups(&self) -> Result<Vec<Group>>;
+
+    /// save a new group, return its id, generated if None
+    async fn save_group(&self, group: &Group) -> Result<u64>;
+
+    /// remove a group and its members, return whether the group existed
+    async fn remove_group(&self, id: u64) -> Result<bool>;
+
+    /// fetch all members of a group
+    async fn fetch_members(&self, group_id: u64) -> Result<Vec<GroupMember>>;
+
+    /// fetch the memberships of a user in all groups
+    async fn fetch_memberships(&self, user_id: u64) -> Result<Vec<GroupMember>>;
+
+    /// save a new member of a group
+    async fn save_member(&self, member: &GroupMember) -> Result<()>;
+
+    /// remove a user from a group, return whether they were a member
+    async fn remove_member(&self, group_id: u64, user_id: u64) -> Result<bool>;
+
+    /// fetch a role by id, None if not exists
+    async fn fetch_role(&self, id: u64) -> Result<Option<Role>>;
+
+    /// save a new role, return its id, generated if None
+    async fn save_role(&self, role: &Role) -> Result<u64>;
+}
+
+/// registry of online sessions, keyed by user id
+#[async_trait]
+pub trait SessionRepository: Send + Sync {
+    /// record a session, replacing the former one of the same user
+    async fn save_session(&self, session: &Session) -> Result<()>;
+
+    /// fetch the session of a user, None if offline
+    async fn fetch_session(&self, user_id: u64) -> Result<Option<Session>>;
+
+    /// fetch all recorded sessions
+    async fn fetch_sessions(&self) -> Result<Vec<Session>>;
+
+    /// remove the session of a user
+    async fn remove_session(&self, user_id: u64) -> Result<()>;
+
+    /// remove all sessions, used to drop stale records on startup
+    async fn clear_sessions(&self) -> Result<()>;
+}
+
+/// when users were last seen online, the status of online users is only kept in memory
+#[async_trait]
+pub trait PresenceRepository: Send + Sync {
+    /// record when a user was last seen, replacing the former record of the user
+    async fn save_last_seen(&self, last_seen: &LastSeen) -> Result<()>;
+
+    /// fetch when a user was last seen, None if never
+    async fn fetch_last_seen(&self, user_id: u64) -> Result<Option<LastSeen>>;
+}
+
+/// the audit log, events are appended and never changed or removed
+#[async_trait]
+pub trait AuditRepository: Send + Sync {
+    /// append an event to the audit log
+    async fn append_audit(&self, event: &AuditEvent) -> Result<()>;
+
+    /// fetch the events asked for, in the order they were appended
+    async fn fetch_audit(&self, query: &AuditQuery) -> Result<Vec<AuditEvent>>;
+}
+
+/// reachability of a backend
+#[async_trait]
+pub trait HealthCheck: Send + Sync {
+    /// check that the backend answers, e.g. that the database can be queried
+    async fn ping(&self) -> Result<()>;
+}
+
+/// all persistence needed by a clush server
+pub trait Storage:
+    UserRepository
+    + MessageRepository
+    + GroupRepository
+    + SessionRepository
+    + PresenceRepository
+    + AuditRepository
+    + HealthCheck
+{
+}
+
+impl<T> Storage for T where
+    T: UserRepository
+        + MessageRepository
+        + GroupRepository
+        + SessionRepository
+        + PresenceRepository
+        + AuditRepository
+        + HealthCheck
+{
+}
+
+/// open the storage selected by the given configuration
+pub async fn open(config: &ClushConfig) -> Result<Arc<dyn Storage>> {
+    match config.storage_config.backend {
+        StorageBackend::Rbatis => {
+            let db = RbatisStorage::connect(&config.rbatis_config).await?;
+            // bring the schema up to date, or make sure it already is
+            if config.rbatis_config.auto_migrate {
+                db.migrate().await?;
+            } else {
+                db.check_schema().await?;
+            }
+
+            Ok(Arc::new(db))
+        }
+        StorageBackend::Memory => Ok(Arc::new(MemoryStorage::new())),
+    }
+}
@@ -0,0 +1,111 @@
+//! TLS configuration of servers and clients
+
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::sync::Arc;
+use tokio_rustls::rustls::internal::pemfile;
+use tokio_rustls::rustls::{
+    Certificate, ClientConfig, NoClientAuth, PrivateKey, RootCertStore, ServerConfig,
+};
+
+pub use tokio_rustls::{rustls, webpki, TlsAcceptor, TlsConnector};
+
+/// load the PEM certificate chain and private key of a server
+pub fn server_config(cert_path: &str, key_path: &str) -> io::Result<ServerConfig> {
+    let certs = load_certs(cert_path)?;
+    let key = load_key(key_path)?;
+
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config
+        .set_single_cert(certs, key)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+    Ok(config)
+}
+
+/// create a client configuration trusting the PEM certificates in the given file
+pub fn client_config(ca_path: &str) -> io::Result<ClientConfig> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(ca_path)? {
+        roots
+            .add(&cert)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    }
+
+    let mut config = ClientConfig::new();
+    config.root_store = roots;
+
+    Ok(config)
+}
+
+/// create a TLS acceptor of the given server configuration
+pub fn acceptor(config: ServerConfig) -> TlsAcceptor {
+    TlsAcceptor::from(Arc::new(config))
+}
+
+/// load all certificates of a PEM file
+fn load_certs(path: &str) -> io::Result<Vec<Certificate>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs = pemfile::certs(&mut reader).map_err(|_| invalid_pem(path))?;
+    if certs.is_empty() {
+        return Err(invalid_pem(path));
+    }
+
+    Ok(certs)
+}
+
+/// load the first PKCS#8 or RSA private key of a PEM file
+fn load_key(path: &str) -> io::Result<PrivateKey> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut keys = pemfile::pkcs8_private_keys(&mut reader).map_err(|_| invalid_pem(path))?;
+    if keys.is_empty() {
+        let mut reader = BufReader::new(File::open(path)?);
+        keys = pemfile::rsa_private_keys(&mut reader).map_err(|_| invalid_pem(path))?;
+    }
+
+    keys.into_iter().next().ok_or_else(|| invalid_pem(path))
+}
+
+fn invalid_pem(path: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("no valid PEM data in {}", path),
+    )
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// write a self-signed certificate for `localhost` and its key to a temporary directory,
+    /// return the paths of both
+    pub(crate) fn self_signed() -> (String, String) {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let dir = std::env::temp_dir().join(format!(
+            "clush-tls-{}",
+            chrono::Utc::now().timestamp_nanos()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let cert_path = dir.join("cert.pem");
+        let key_path = dir.join("key.pem");
+        std::fs::write(&cert_path, cert.serialize_pem().unwrap()).unwrap();
+        std::fs::write(&key_path, cert.serialize_private_key_pem()).unwrap();
+
+        (
+            cert_path.to_str().unwrap().to_string(),
+            key_path.to_str().unwrap().to_string(),
+        )
+    }
+
+    #[test]
+    fn load_config_test() {
+        let (cert_path, key_path) = self_signed();
+        assert!(server_config(&cert_path, &key_path).is_ok());
+        assert!(client_config(&cert_path).is_ok());
+
+        // a key is not a certificate
+        let e = server_config(&key_path, &key_path).err().unwrap();
+        assert_eq!(io::ErrorKind::InvalidData, e.kind());
+    }
+}
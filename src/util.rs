@@ -1,7 +1,9 @@
+use sha2::{Digest, Sha256};
+
 const BITS_OF_BYTE: usize = 8;
 
 /// clush message type
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum MessageType {
     Undefined,
     LoginMessage,     // 0
@@ -9,6 +11,10 @@ pub enum MessageType {
     GroupMessage,     // 2
     UserFileMessage,  // 3
     GroupFileMessage, // 4
+    AckMessage,       // 5
+    ShutdownMessage,  // 6
+    HelloMessage,     // 7
+    PresenceMessage,  // 8
 }
 
 /// convert a given slice to u32
@@ -17,9 +23,9 @@ pub fn u32_from_bytes(bytes: &[u8]) -> Result<u32, &str> {
         return Err("insufficient data!");
     }
     let mut number: u32 = 0;
-    for i in 0..4 {
+    for byte in &bytes[..4] {
         number <<= BITS_OF_BYTE;
-        number |= bytes[i] as u32;
+        number |= *byte as u32;
     }
 
     Ok(number)
@@ -31,9 +37,9 @@ pub fn u64_from_bytes(bytes: &[u8]) -> Result<u64, &str> {
         return Err("insufficient data!");
     }
     let mut number: u64 = 0;
-    for i in 0..8 {
+    for byte in &bytes[..8] {
         number <<= BITS_OF_BYTE;
-        number |= bytes[i] as u64;
+        number |= *byte as u64;
     }
 
     Ok(number)
@@ -77,6 +83,15 @@ pub fn hex_string_to_bytes(str: &str) -> Vec<u8> {
     hex
 }
 
+/// hash a password the way clients do before logging in,
+/// return it in hex, the way it is stored
+pub fn hash_password(password: &str) -> String {
+    Sha256::digest(password.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -107,4 +122,12 @@ mod tests {
     fn hex_string_to_bytes_test() {
         assert_eq!(vec![0x1c_u8, 0x8a_u8], hex_string_to_bytes("1c8a"));
     }
+
+    #[test]
+    fn hash_password_test() {
+        assert_eq!(
+            "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824",
+            hash_password("hello")
+        );
+    }
 }
@@ -0,0 +1,638 @@
+//! durable local write-ahead log of accepted messages
+//!
+//! user and group messages are appended to segment files as JSON lines,
+//! e.g. `{"group":{...}}`, and synced to disk
+//! before they are acknowledged, a batch of messages with a single sync. a background replayer reads them back in
+//! order and saves them to the database, retrying until the database is
+//! available, then records how far it got in a checkpoint file.
+//! messages an available database refuses, e.g. for a violated constraint,
+//! are set aside in a dead-letter file so the messages after them get through.
+//!
+//! a message is replayed at least once, a crash between saving a batch and
+//! recording the checkpoint replays that batch again.
+//! logs written before group messages were logged hold bare user messages,
+//! they are still read.
+
+use crate::config::WalConfig;
+use crate::entity::UserMsg;
+use crate::pipeline::{self, Record};
+use crate::storage::Storage;
+use serde::{Deserialize, Serialize};
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::{AsyncBufReadExt, AsyncSeekExt, AsyncWriteExt, BufReader, Result};
+use tokio::sync::{watch, Mutex, Notify};
+use tokio::time;
+
+/// name of the file holding the checkpoint
+static CHECKPOINT: &str = "checkpoint";
+/// name of the file of the messages the database refused, as JSON lines
+static DEAD_LETTER: &str = "dead-letter";
+/// suffix of segment files
+static SEGMENT_SUFFIX: &str = ".log";
+/// first wait before trying again to save to an unavailable database
+static RETRY_DELAY: Duration = Duration::from_millis(100);
+/// longest wait between two attempts to save to an unavailable database
+static MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// the segment being appended to
+struct ActiveSegment {
+    index: u64,
+    file: File,
+    size: u64,
+    /// whether a failed append may have left a partial record after `size`
+    torn: bool,
+}
+
+/// an append-only log of messages, split into segment files
+pub struct WriteAheadLog {
+    dir: PathBuf,
+    segment_size: u64,
+    active: Mutex<ActiveSegment>,
+    active_index: AtomicU64,
+    appended: Notify,
+    /// checkpoint of the replayer, None until it started
+    replayed: watch::Sender<Option<Checkpoint>>,
+    replayed_rx: watch::Receiver<Option<Checkpoint>>,
+}
+
+/// position in the log after the last replayed message
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, PartialOrd)]
+struct Checkpoint {
+    segment: u64,
+    offset: u64,
+}
+
+impl WriteAheadLog {
+    /// open the log in the configured directory
+    ///
+    /// appending always starts in a new segment,
+    /// so a record torn by a crash is never followed by others
+    pub async fn open(config: &WalConfig) -> Result<WriteAheadLog> {
+        let dir = PathBuf::from(&config.path);
+        fs::create_dir_all(&dir).await?;
+
+        let index = segments(&dir).await?.last().map_or(0, |last| last + 1);
+        let file = create_segment(&dir, index).await?;
+        let active = ActiveSegment {
+            index,
+            file,
+            size: 0,
+            torn: false,
+        };
+        let (replayed, replayed_rx) = watch::channel(None);
+
+        Ok(WriteAheadLog {
+            dir,
+            segment_size: config.segment_size,
+            active: Mutex::new(active),
+            active_index: AtomicU64::new(index),
+            appended: Notify::new(),
+            replayed,
+            replayed_rx,
+        })
+    }
+
+    /// append a message, return once it is synced to disk
+    pub async fn append(&self, record: &Record) -> Result<()> {
+        self.append_all(std::slice::from_ref(record)).await
+    }
+
+    /// append messages with a single write, return once they are synced to disk
+    ///
+    /// after a failed append the next records go to a new segment,
+    /// where a record torn by the failure is the end of its segment, like after a crash.
+    /// records written but not synced may still be replayed
+    pub async fn append_all(&self, records: &[Record]) -> Result<()> {
+        let mut lines = vec![];
+        for record in records {
+            serde_json::to_writer(&mut lines, record)?;
+            lines.push(b'\n');
+        }
+        if lines.is_empty() {
+            return Ok(());
+        }
+
+        let mut active = self.active.lock().await;
+        // start a new segment when the active one is full or torn
+        if active.torn || active.size > 0 && active.size + lines.len() as u64 > self.segment_size {
+            self.roll(&mut active).await?;
+        }
+
+        if let Err(e) = write_synced(&mut active.file, &lines).await {
+            active.torn = true;
+            if let Err(e) = self.roll(&mut active).await {
+                tracing::error!("failed to start a new write-ahead log segment: {}", e);
+            }
+            return Err(e);
+        }
+        active.size += lines.len() as u64;
+        drop(active);
+
+        self.appended.notify_one();
+
+        Ok(())
+    }
+
+    /// start appending to a new segment
+    async fn roll(&self, active: &mut ActiveSegment) -> Result<()> {
+        let index = active.index + 1;
+        let file = create_segment(&self.dir, index).await?;
+        *active = ActiveSegment {
+            index,
+            file,
+            size: 0,
+            torn: false,
+        };
+        self.active_index.store(index, Ordering::SeqCst);
+
+        Ok(())
+    }
+
+    /// return once every message appended before is replayed into the database
+    pub async fn wait_replayed(&self) {
+        let appended = {
+            let active = self.active.lock().await;
+            Checkpoint {
+                segment: active.index,
+                offset: active.size,
+            }
+        };
+
+        let mut replayed = self.replayed_rx.clone();
+        while replayed
+            .borrow()
+            .is_none_or(|checkpoint| checkpoint < appended)
+        {
+            if replayed.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+
+    /// replay the log into the database forever, run in a background task
+    pub async fn replay(self: Arc<Self>, db: Arc<dyn Storage>, batch_size: usize) {
+        let mut delay = RETRY_DELAY;
+        let mut checkpoint = loop {
+            match self.load_checkpoint().await {
+                Ok(checkpoint) => break checkpoint,
+                Err(e) => {
+                    tracing::error!(
+                        "failed to load write-ahead log checkpoint, retry in {:?}: {}",
+                        delay,
+                        e
+                    );
+                    time::sleep(delay).await;
+                    delay = (delay * 2).min(MAX_RETRY_DELAY);
+                }
+            }
+        };
+        let _ = self.replayed.send(Some(checkpoint));
+
+        loop {
+            let active = self.active_index.load(Ordering::SeqCst);
+            let (records, next) = match read_batch(&self.dir, checkpoint, batch_size, active).await
+            {
+                Ok(batch) => batch,
+                Err(e) => {
+                    tracing::error!("failed to read write-ahead log: {}", e);
+                    time::sleep(MAX_RETRY_DELAY).await;
+                    continue;
+                }
+            };
+
+            // wait for new messages if nothing is left
+            if records.is_empty() && next == checkpoint {
+                let _ = time::timeout(Duration::from_secs(1), self.appended.notified()).await;
+                continue;
+            }
+
+            // save until the database accepts the batch, or refuses some of its messages
+            let mut delay = RETRY_DELAY;
+            while let Err(e) = pipeline::save_records(db.as_ref(), &records).await {
+                // a database which answers refuses the messages themselves
+                if db.ping().await.is_ok() {
+                    tracing::warn!(
+                        "database refused a batch of {} messages, saving them one by one: {}",
+                        records.len(),
+                        e
+                    );
+                    match self.save_each(db.as_ref(), &records).await {
+                        Ok(()) => break,
+                        Err(e) => tracing::error!("failed to set aside a message: {}", e),
+                    }
+                } else {
+                    tracing::warn!(
+                        "failed to replay {} messages, retry in {:?}: {}",
+                        records.len(),
+                        delay,
+                        e
+                    );
+                }
+                time::sleep(delay).await;
+                delay = (delay * 2).min(MAX_RETRY_DELAY);
+            }
+
+            checkpoint = next;
+            if let Err(e) = self.save_checkpoint(checkpoint).await {
+                tracing::error!("failed to save write-ahead log checkpoint: {}", e);
+            }
+            let _ = self.replayed.send(Some(checkpoint));
+        }
+    }
+
+    /// save messages one at a time, setting aside those the database refuses while available
+    async fn save_each(&self, db: &dyn Storage, records: &[Record]) -> Result<()> {
+        for record in records {
+            let mut delay = RETRY_DELAY;
+            while let Err(e) = record.save(db).await {
+                if db.ping().await.is_ok() {
+                    tracing::error!("set aside a {} refused by the database: {}", record, e);
+                    self.set_aside(record).await?;
+                    break;
+                }
+                time::sleep(delay).await;
+                delay = (delay * 2).min(MAX_RETRY_DELAY);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// append a message to the dead-letter file, return once it is synced to disk
+    async fn set_aside(&self, record: &Record) -> Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.dir.join(DEAD_LETTER))
+            .await?;
+        write_synced(&mut file, &line).await
+    }
+
+    /// load the checkpoint, or start from the first segment if there is none
+    async fn load_checkpoint(&self) -> Result<Checkpoint> {
+        match fs::read(self.dir.join(CHECKPOINT)).await {
+            Ok(content) => Ok(serde_json::from_slice(&content)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let segment = segments(&self.dir).await?.first().copied().unwrap_or(0);
+                Ok(Checkpoint { segment, offset: 0 })
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// save the checkpoint atomically, and remove segments replayed completely
+    async fn save_checkpoint(&self, checkpoint: Checkpoint) -> Result<()> {
+        let tmp = self.dir.join(format!("{}.tmp", CHECKPOINT));
+        let mut file = File::create(&tmp).await?;
+        write_synced(&mut file, &serde_json::to_vec(&checkpoint)?).await?;
+        fs::rename(&tmp, self.dir.join(CHECKPOINT)).await?;
+
+        for index in segments(&self.dir).await? {
+            if index < checkpoint.segment {
+                fs::remove_file(segment_path(&self.dir, index)).await?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// write to a file and sync it to disk,
+/// flushing first since syncing does not report a failed write of a tokio file
+async fn write_synced(file: &mut File, buf: &[u8]) -> Result<()> {
+    file.write_all(buf).await?;
+    file.flush().await?;
+    file.sync_data().await
+}
+
+/// get the path of a segment
+fn segment_path(dir: &Path, index: u64) -> PathBuf {
+    dir.join(format!("{:020}{}", index, SEGMENT_SUFFIX))
+}
+
+/// create an empty segment
+async fn create_segment(dir: &Path, index: u64) -> Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(segment_path(dir, index))
+        .await
+}
+
+/// list the indexes of all segments in order
+async fn segments(dir: &Path) -> Result<Vec<u64>> {
+    let mut indexes = vec![];
+    let mut entries = fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name();
+        let index = name
+            .to_str()
+            .and_then(|name| name.strip_suffix(SEGMENT_SUFFIX))
+            .and_then(|index| index.parse::<u64>().ok());
+        if let Some(index) = index {
+            indexes.push(index);
+        }
+    }
+    indexes.sort_unstable();
+
+    Ok(indexes)
+}
+
+/// read up to `max` messages after the given position,
+/// return them with the position after the last one
+///
+/// an incomplete line ends a segment: in the active segment it is still
+/// being written, in an older one it was torn by a crash and never acknowledged
+async fn read_batch(
+    dir: &Path,
+    from: Checkpoint,
+    max: usize,
+    active: u64,
+) -> Result<(Vec<Record>, Checkpoint)> {
+    let mut records = vec![];
+    let mut pos = from;
+
+    loop {
+        let path = segment_path(dir, pos.segment);
+        if fs::metadata(&path).await.is_ok() {
+            let mut file = File::open(&path).await?;
+            file.seek(SeekFrom::Start(pos.offset)).await?;
+            let mut reader = BufReader::new(file);
+            let mut line = vec![];
+
+            while records.len() < max {
+                line.clear();
+                let n = reader.read_until(b'\n', &mut line).await?;
+                if n == 0 || line.last() != Some(&b'\n') {
+                    break;
+                }
+                pos.offset += n as u64;
+
+                match parse_record(&line) {
+                    Ok(record) => records.push(record),
+                    Err(e) => tracing::warn!("skip malformed write-ahead log record: {}", e),
+                }
+            }
+        }
+
+        // move to the next segment once an older one is read through
+        if records.len() >= max || pos.segment >= active {
+            break;
+        }
+        pos = Checkpoint {
+            segment: pos.segment + 1,
+            offset: 0,
+        };
+    }
+
+    Ok((records, pos))
+}
+
+/// read a record, or a bare user message of an older log
+fn parse_record(line: &[u8]) -> serde_json::Result<Record> {
+    serde_json::from_slice(line).or_else(|e| {
+        serde_json::from_slice::<UserMsg>(line)
+            .map(Record::User)
+            .map_err(|_| e)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::RbatisConfig;
+    use crate::entity::GroupMsg;
+    use crate::storage::{MemoryStorage, MessageRepository, RbatisStorage};
+
+    fn wal_config(segment_size: u64) -> WalConfig {
+        let path = std::env::temp_dir().join(format!(
+            "clush-wal-{}",
+            chrono::Utc::now().timestamp_nanos()
+        ));
+
+        WalConfig {
+            enable: true,
+            path: path.to_str().unwrap().to_string(),
+            segment_size,
+        }
+    }
+
+    fn user_msg(content: &str) -> UserMsg {
+        UserMsg {
+            id: None,
+            from_id: Some(1),
+            to_id: Some(2),
+            date_time: Some(chrono::Utc::now()),
+            content: Some(content.to_string()),
+            delivered: Some(true),
+        }
+    }
+
+    fn group_msg(content: &str) -> GroupMsg {
+        GroupMsg {
+            id: None,
+            group_id: Some(1),
+            user_id: Some(1),
+            date_time: Some(chrono::Utc::now()),
+            content: Some(content.to_string()),
+        }
+    }
+
+    #[tokio::test]
+    async fn read_batch_test() {
+        let config = wal_config(1);
+        let wal = WriteAheadLog::open(&config).await.unwrap();
+        // every message fills a segment
+        let records = [
+            Record::User(user_msg("a")),
+            Record::Group(group_msg("b")),
+            Record::User(user_msg("c")),
+        ];
+        for record in &records {
+            wal.append(record).await.unwrap();
+        }
+        // a record torn by a crash
+        let mut file = create_segment(&wal.dir, 0).await.unwrap();
+        file.write_all(b"{\"id\":").await.unwrap();
+
+        let start = Checkpoint {
+            segment: 0,
+            offset: 0,
+        };
+        let (records, next) = read_batch(&wal.dir, start, 2, 2).await.unwrap();
+        assert_eq!(vec!["a", "b"], record_contents(&records));
+        assert!(matches!(records[1], Record::Group(_)));
+        assert_eq!(1, next.segment);
+
+        let (records, next) = read_batch(&wal.dir, next, 2, 2).await.unwrap();
+        assert_eq!(vec!["c"], record_contents(&records));
+        assert_eq!(2, next.segment);
+
+        let (records, last) = read_batch(&wal.dir, next, 2, 2).await.unwrap();
+        assert!(records.is_empty());
+        assert_eq!(next, last);
+
+        fs::remove_dir_all(&config.path).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn failed_append_test() {
+        let config = wal_config(1024);
+        let wal = WriteAheadLog::open(&config).await.unwrap();
+        wal.append(&Record::User(user_msg("a"))).await.unwrap();
+
+        // a write which stops halfway, and then fails
+        {
+            let mut active = wal.active.lock().await;
+            let path = segment_path(&wal.dir, active.index);
+            let mut file = create_segment(&wal.dir, active.index).await.unwrap();
+            file.write_all(b"{\"user\":{\"id\":").await.unwrap();
+            active.file = File::open(path).await.unwrap();
+        }
+        assert!(wal.append(&Record::User(user_msg("b"))).await.is_err());
+        wal.append(&Record::User(user_msg("c"))).await.unwrap();
+
+        // the torn record ends its segment, the next one is read whole
+        let start = Checkpoint {
+            segment: 0,
+            offset: 0,
+        };
+        let (records, next) = read_batch(&wal.dir, start, 16, 1).await.unwrap();
+        assert_eq!(vec!["a", "c"], record_contents(&records));
+        assert_eq!(1, next.segment);
+
+        fs::remove_dir_all(&config.path).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn checkpoint_retry_test() {
+        let config = wal_config(1024);
+        let db = Arc::new(MemoryStorage::new());
+
+        // a checkpoint which cannot be read, for now
+        fs::create_dir_all(Path::new(&config.path).join(CHECKPOINT))
+            .await
+            .unwrap();
+        let wal = Arc::new(WriteAheadLog::open(&config).await.unwrap());
+        wal.append(&Record::User(user_msg("a"))).await.unwrap();
+        tokio::spawn(wal.clone().replay(db.clone(), 16));
+
+        time::sleep(Duration::from_millis(50)).await;
+        assert!(db.fetch_user_msgs(2).await.unwrap().is_empty());
+        fs::remove_dir(wal.dir.join(CHECKPOINT)).await.unwrap();
+
+        // the replayer keeps trying
+        time::timeout(Duration::from_secs(5), wal.wait_replayed())
+            .await
+            .unwrap();
+        assert_eq!(vec!["a"], contents(&db.fetch_user_msgs(2).await.unwrap()));
+
+        fs::remove_dir_all(&config.path).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn replay_test() {
+        let config = wal_config(1024);
+        let db = Arc::new(MemoryStorage::new());
+
+        // a bare user message logged by a former run, before group messages were logged
+        fs::create_dir_all(&config.path).await.unwrap();
+        let mut line = serde_json::to_vec(&user_msg("before")).unwrap();
+        line.push(b'\n');
+        let mut file = create_segment(Path::new(&config.path), 0).await.unwrap();
+        file.write_all(&line).await.unwrap();
+        drop(file);
+
+        let wal = Arc::new(WriteAheadLog::open(&config).await.unwrap());
+        tokio::spawn(wal.clone().replay(db.clone(), 16));
+        wal.append(&Record::User(user_msg("after"))).await.unwrap();
+        wal.append(&Record::Group(group_msg("group")))
+            .await
+            .unwrap();
+
+        time::timeout(Duration::from_secs(5), wal.wait_replayed())
+            .await
+            .unwrap();
+        let msgs = db.fetch_user_msgs(2).await.unwrap();
+        assert_eq!(vec!["before", "after"], contents(&msgs));
+        // the group message is saved too, and purged with the user messages
+        let later = chrono::Utc::now() + chrono::Duration::hours(1);
+        assert_eq!(3, db.purge_msgs(later).await.unwrap());
+
+        // the replayed segment of the former run is removed
+        time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(vec![1], segments(&wal.dir).await.unwrap());
+
+        fs::remove_dir_all(&config.path).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn dead_letter_test() {
+        let config = wal_config(1024);
+        let db_path = std::env::temp_dir().join(format!(
+            "clush-wal-{}.db",
+            chrono::Utc::now().timestamp_nanos()
+        ));
+        let rbatis_config = RbatisConfig {
+            db_url: format!("sqlite://{}", db_path.display()),
+            ..RbatisConfig::default()
+        };
+        let db = Arc::new(RbatisStorage::connect(&rbatis_config).await.unwrap());
+        db.migrate().await.unwrap();
+
+        // a message without recipient violates a constraint, it must not hold back the others
+        let wal = Arc::new(WriteAheadLog::open(&config).await.unwrap());
+        let refused = UserMsg {
+            to_id: None,
+            ..user_msg("refused")
+        };
+        for msg in [user_msg("before"), refused, user_msg("after")] {
+            wal.append(&Record::User(msg)).await.unwrap();
+        }
+        tokio::spawn(wal.clone().replay(db.clone(), 16));
+
+        for _ in 0..100 {
+            if db.fetch_user_msgs(2).await.unwrap().len() == 2 {
+                break;
+            }
+            time::sleep(Duration::from_millis(10)).await;
+        }
+        let msgs = db.fetch_user_msgs(2).await.unwrap();
+        assert_eq!(vec!["before", "after"], contents(&msgs));
+
+        let dead_letters = fs::read(wal.dir.join(DEAD_LETTER)).await.unwrap();
+        let dead_letters: Vec<Record> = dead_letters
+            .split(|b| *b == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(|line| serde_json::from_slice(line).unwrap())
+            .collect();
+        assert_eq!(vec!["refused"], record_contents(&dead_letters));
+
+        fs::remove_dir_all(&config.path).await.unwrap();
+        fs::remove_file(db_path).await.unwrap();
+    }
+
+    fn contents(msgs: &[UserMsg]) -> Vec<&str> {
+        msgs.iter()
+            .map(|msg| msg.content.as_deref().unwrap())
+            .collect()
+    }
+
+    fn record_contents(records: &[Record]) -> Vec<&str> {
+        records
+            .iter()
+            .map(|record| match record {
+                Record::User(msg) => msg.content.as_deref().unwrap(),
+                Record::Group(msg) => msg.content.as_deref().unwrap(),
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,232 @@
+//! harness booting a full server with in-memory storage on an ephemeral port
+
+use clush_server::entity::{Group, GroupMember, Role, User};
+use clush_server::{
+    ClushClient, ClushConfig, ClushFrame, ClushServer, MemoryStorage, ShutdownHandle, Storage,
+};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+use tokio::time;
+
+/// password of every seeded user, stored as `1c8a`
+pub const PASSWORD: &[u8] = &[0x1c, 0x8a];
+/// group whose members are users 1, 2 and 3, user 4 is not a member
+pub const GROUP: u64 = 1;
+/// token of the admin API of `admin_config`
+pub const ADMIN_TOKEN: &str = "secret";
+
+/// how long to wait for a frame which should arrive
+static TIMEOUT: Duration = Duration::from_secs(5);
+/// how long to wait before deciding no frame arrives
+static SILENCE: Duration = Duration::from_millis(200);
+
+/// a running server seeded with users 1 to 4 and one group
+pub struct TestServer {
+    pub addr: SocketAddr,
+    /// address of the HTTP endpoints, if enabled
+    pub metrics_addr: Option<SocketAddr>,
+    /// address of the admin API, if enabled
+    pub admin_addr: Option<SocketAddr>,
+    pub db: Arc<MemoryStorage>,
+    wal_path: String,
+    handle: ShutdownHandle,
+    task: JoinHandle<io::Result<()>>,
+}
+
+impl TestServer {
+    /// start a seeded server on `127.0.0.1:0`
+    pub async fn start() -> TestServer {
+        TestServer::start_with(ClushConfig::default()).await
+    }
+
+    /// start a seeded server on `127.0.0.1:0` with the given configuration
+    pub async fn start_with(mut config: ClushConfig) -> TestServer {
+        let wal_path = wal_path();
+        config.wal_config.path = wal_path.clone();
+        let db = Arc::new(MemoryStorage::new());
+        seed(db.as_ref()).await;
+
+        let server = ClushServer::builder()
+            .config(config)
+            .listener(TcpListener::bind("127.0.0.1:0").await.unwrap())
+            .storage(db.clone())
+            .build()
+            .await
+            .unwrap();
+        let addr = server.local_addr().unwrap();
+        let metrics_addr = server.metrics_addr();
+        let admin_addr = server.admin_addr();
+        let handle = server.shutdown_handle();
+        let task = tokio::spawn(async move { server.start().await });
+
+        TestServer {
+            addr,
+            metrics_addr,
+            admin_addr,
+            db,
+            wal_path,
+            handle,
+            task,
+        }
+    }
+
+    /// connect a client without logging in
+    pub async fn connect(&self) -> ClushClient {
+        ClushClient::connect(self.addr).await.unwrap()
+    }
+
+    /// connect a client logged in as the given user
+    pub async fn login(&self, uid: u64) -> ClushClient {
+        let mut client = self.connect().await;
+        client.login(uid, PASSWORD).await.unwrap();
+
+        client
+    }
+
+    /// send a GET request to the HTTP endpoints, return the status and the body
+    pub async fn http_get(&self, path: &str) -> (u16, String) {
+        http_request(self.metrics_addr.unwrap(), "GET", path, None, "").await
+    }
+
+    /// send a request to the admin API with `ADMIN_TOKEN`, return the status and the body
+    pub async fn admin(&self, method: &str, path: &str, body: &str) -> (u16, String) {
+        let addr = self.admin_addr.unwrap();
+
+        http_request(addr, method, path, Some(ADMIN_TOKEN), body).await
+    }
+
+    /// shut the server down and wait until it has stopped
+    pub async fn stop(self) {
+        self.handle.shutdown();
+        time::timeout(TIMEOUT, self.task)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        let _ = std::fs::remove_dir_all(&self.wal_path);
+    }
+}
+
+/// get a fresh directory for the write-ahead log of a server
+pub fn wal_path() -> String {
+    let path = std::env::temp_dir().join(format!(
+        "clush-test-wal-{}",
+        chrono::Utc::now().timestamp_nanos()
+    ));
+
+    path.to_str().unwrap().to_string()
+}
+
+/// get a configuration serving the admin API on `127.0.0.1:0` with `ADMIN_TOKEN`
+pub fn admin_config() -> ClushConfig {
+    let mut config = ClushConfig::default();
+    config.admin_config.enable = true;
+    config.admin_config.url = "127.0.0.1:0".to_string();
+    config.admin_config.token = ADMIN_TOKEN.to_string();
+
+    config
+}
+
+/// send an HTTP/1.1 request with a bearer token, if any, return the status and the body
+pub async fn http_request(
+    addr: SocketAddr,
+    method: &str,
+    path: &str,
+    token: Option<&str>,
+    body: &str,
+) -> (u16, String) {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let mut request = format!(
+        "{} {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nContent-Length: {}\r\n",
+        method,
+        path,
+        body.len()
+    );
+    if let Some(token) = token {
+        request.push_str(&format!("Authorization: Bearer {}\r\n", token));
+    }
+    request.push_str("\r\n");
+    request.push_str(body);
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = String::new();
+    time::timeout(TIMEOUT, stream.read_to_string(&mut response))
+        .await
+        .unwrap()
+        .unwrap();
+
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    let status = head.split(' ').nth(1).unwrap().parse().unwrap();
+
+    (status, body.to_string())
+}
+
+/// create users 1 to 4, and the group with members 1 to 3
+pub async fn seed(db: &dyn Storage) {
+    for (id, name) in (1..).zip(&["alice", "bob", "carol", "dave"]) {
+        let user = User {
+            id: Some(id),
+            username: Some(name.to_string()),
+            password: Some("1c8a".to_string()),
+            disabled: None,
+        };
+        db.save_user(&user).await.unwrap();
+    }
+
+    let group = Group {
+        id: Some(GROUP),
+        group_name: Some("clush".to_string()),
+    };
+    db.save_group(&group).await.unwrap();
+    let role = Role {
+        id: Some(1),
+        role_name: Some("member".to_string()),
+    };
+    db.save_role(&role).await.unwrap();
+    for user_id in 1..=3 {
+        let member = GroupMember {
+            id: None,
+            group_id: Some(GROUP),
+            user_id: Some(user_id),
+            role_id: Some(1),
+        };
+        db.save_member(&member).await.unwrap();
+    }
+}
+
+/// receive the next frame, fail if none arrives in time
+pub async fn recv(client: &mut ClushClient) -> ClushFrame {
+    time::timeout(TIMEOUT, client.recv())
+        .await
+        .expect("no frame arrived in time")
+        .unwrap()
+        .expect("the connection is closed")
+}
+
+/// fail unless the connection is closed in time
+pub async fn assert_closed(client: &mut ClushClient) {
+    let frame = time::timeout(TIMEOUT, client.recv())
+        .await
+        .expect("the connection is still open");
+    assert!(
+        matches!(frame, Ok(None) | Err(_)),
+        "unexpected frame {:?}",
+        frame
+    );
+}
+
+/// fail if a frame arrives soon
+pub async fn assert_silent(client: &mut ClushClient) {
+    if let Ok(frame) = time::timeout(SILENCE, client.recv()).await {
+        panic!("unexpected frame {:?}", frame);
+    }
+}
+
+/// get the content of a frame as text
+pub fn text(frame: &ClushFrame) -> &str {
+    std::str::from_utf8(&frame.content).unwrap()
+}
@@ -0,0 +1,1064 @@
+//! end-to-end behaviour of a server driven by scripted clients
+
+mod common;
+
+use bytes::BytesMut;
+use clush_server::compression::Compression;
+use clush_server::config::OverflowPolicy;
+use clush_server::presence::{Presence, Status};
+use clush_server::protocol::Capabilities;
+use clush_server::storage::{MessageRepository, SessionRepository};
+use clush_server::util::{hash_password, hex_string_to_bytes};
+use clush_server::{
+    codec, ClushClient, ClushConfig, ClushFrame, ClushServer, MessageType, RbatisStorage,
+};
+use common::*;
+use rbatis::rbatis::Rbatis;
+use std::io;
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time;
+
+#[tokio::test]
+async fn login_test() {
+    let server = TestServer::start().await;
+
+    let alice = server.login(1).await;
+    assert_eq!(1, alice.uid());
+
+    let mut client = server.connect().await;
+    let e = client.login(1, b"wrong").await.unwrap_err();
+    assert_eq!(io::ErrorKind::PermissionDenied, e.kind());
+    assert_eq!("invalid password", e.to_string());
+
+    let mut client = server.connect().await;
+    let e = client.login(9, PASSWORD).await.unwrap_err();
+    assert_eq!("invalid user", e.to_string());
+
+    // any other frame before login is refused
+    let mut client = server.connect().await;
+    client.send_user_msg(2, "hello").await.unwrap();
+    let frame = recv(&mut client).await;
+    assert_eq!("login required", text(&frame));
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn user_msg_test() {
+    let server = TestServer::start().await;
+    let mut alice = server.login(1).await;
+    let mut bob = server.login(2).await;
+    let mut carol = server.login(3).await;
+
+    alice.send_user_msg(2, "hello bob").await.unwrap();
+
+    let frame = recv(&mut bob).await;
+    assert_eq!(MessageType::UserMessage, frame.msg_type);
+    assert_eq!((1, 2), (frame.from_id, frame.to_id));
+    assert_eq!("hello bob", text(&frame));
+    let ack = recv(&mut alice).await;
+    assert_eq!(MessageType::AckMessage, ack.msg_type);
+    assert_eq!((1, 2), (ack.from_id, ack.to_id));
+    assert_silent(&mut carol).await;
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn spoofed_sender_test() {
+    let server = TestServer::start().await;
+    let mut alice = server.login(1).await;
+    let mut bob = server.login(2).await;
+
+    // the sender is always the logged in user
+    let mut frame = ClushFrame::new(MessageType::UserMessage, 3, 2, 0, BytesMut::new());
+    frame.append(b"from carol").update_size();
+    alice.send_frame(&frame).await.unwrap();
+
+    let frame = recv(&mut bob).await;
+    assert_eq!(1, frame.from_id);
+    assert_eq!(MessageType::AckMessage, recv(&mut alice).await.msg_type);
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn group_msg_test() {
+    let server = TestServer::start().await;
+    let mut alice = server.login(1).await;
+    let mut bob = server.login(2).await;
+    let mut carol = server.login(3).await;
+    let mut dave = server.login(4).await;
+
+    alice.send_group_msg(GROUP, "hello group").await.unwrap();
+
+    for member in [&mut bob, &mut carol].iter_mut() {
+        let frame = recv(member).await;
+        assert_eq!(MessageType::GroupMessage, frame.msg_type);
+        assert_eq!((1, GROUP), (frame.from_id, frame.to_id));
+        assert_eq!("hello group", text(&frame));
+    }
+    assert_eq!(MessageType::AckMessage, recv(&mut alice).await.msg_type);
+    assert_silent(&mut dave).await;
+
+    // only members may send to a group
+    dave.send_group_msg(GROUP, "let me in").await.unwrap();
+    let frame = recv(&mut dave).await;
+    assert_eq!(0, frame.from_id);
+    assert_eq!("not a member of group 1", text(&frame));
+    assert_silent(&mut alice).await;
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn offline_delivery_test() {
+    let server = TestServer::start().await;
+    let mut alice = server.login(1).await;
+
+    // dave is offline, the message is stored
+    alice.send_user_msg(4, "first").await.unwrap();
+    alice.send_user_msg(4, "second").await.unwrap();
+    for _ in 0..2 {
+        assert_eq!(MessageType::AckMessage, recv(&mut alice).await.msg_type);
+    }
+
+    // and delivered in order once he logs in
+    let mut dave = server.login(4).await;
+    for content in &["first", "second"] {
+        let frame = recv(&mut dave).await;
+        assert_eq!((1, 4), (frame.from_id, frame.to_id));
+        assert_eq!(*content, text(&frame));
+    }
+    drop(dave);
+    assert_eq!(2, server.db.fetch_user_msgs(4).await.unwrap().len());
+    assert!(server
+        .db
+        .fetch_undelivered_user_msgs(4)
+        .await
+        .unwrap()
+        .is_empty());
+
+    // but only once
+    let mut dave = server.login(4).await;
+    assert_silent(&mut dave).await;
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn storage_outage_test() {
+    let path = std::env::temp_dir().join(format!(
+        "clush-outage-test-{}.db",
+        chrono::Utc::now().timestamp_nanos()
+    ));
+    let mut config = ClushConfig::default();
+    config.rbatis_config.db_url = format!("sqlite://{}", path.display());
+    config.wal_config.enable = false;
+    let db = RbatisStorage::connect(&config.rbatis_config).await.unwrap();
+    db.migrate().await.unwrap();
+    seed(&db).await;
+
+    let server = ClushServer::builder()
+        .config(config.clone())
+        .listener(TcpListener::bind("127.0.0.1:0").await.unwrap())
+        .storage(Arc::new(db))
+        .build()
+        .await
+        .unwrap();
+    let addr = server.local_addr().unwrap();
+    let handle = server.shutdown_handle();
+    let task = tokio::spawn(async move { server.start().await });
+    let mut alice = ClushClient::connect(addr).await.unwrap();
+    alice.login(1, PASSWORD).await.unwrap();
+    let mut bob = ClushClient::connect(addr).await.unwrap();
+    bob.login(2, PASSWORD).await.unwrap();
+
+    // messages can no longer be stored
+    let broken = Rbatis::new();
+    broken.link(&config.rbatis_config.db_url).await.unwrap();
+    broken.exec("", "DROP TABLE user_msg").await.unwrap();
+    broken.exec("", "DROP TABLE group_msg").await.unwrap();
+
+    // online delivery goes on, the sender is told instead of acked and stays connected
+    for content in &["first", "second"] {
+        alice.send_user_msg(2, content).await.unwrap();
+        assert_eq!(*content, text(&recv(&mut bob).await));
+        let frame = recv(&mut alice).await;
+        assert_eq!(MessageType::UserMessage, frame.msg_type);
+        assert_eq!(0, frame.from_id);
+        assert_eq!("failed to store the message", text(&frame));
+    }
+    alice.send_group_msg(GROUP, "group").await.unwrap();
+    assert_eq!("group", text(&recv(&mut bob).await));
+    assert_eq!("failed to store the message", text(&recv(&mut alice).await));
+
+    // nor does a failed membership check close the connection
+    broken.exec("", "DROP TABLE group_member").await.unwrap();
+    alice.send_group_msg(GROUP, "lost").await.unwrap();
+    let frame = recv(&mut alice).await;
+    assert_eq!("failed to check the membership", text(&frame));
+    alice.send_user_msg(2, "still here").await.unwrap();
+    assert_eq!("still here", text(&recv(&mut bob).await));
+
+    handle.shutdown();
+    task.await.unwrap().unwrap();
+    std::fs::remove_file(path).unwrap();
+}
+
+#[tokio::test]
+async fn file_test() {
+    let server = TestServer::start().await;
+    let mut alice = server.login(1).await;
+    let mut bob = server.login(2).await;
+    let mut carol = server.login(3).await;
+    let file = vec![0u8, 1, 2, 255];
+
+    alice.send_user_file(2, &file).await.unwrap();
+    let frame = recv(&mut bob).await;
+    assert_eq!(MessageType::UserFileMessage, frame.msg_type);
+    assert_eq!(&file[..], &frame.content[..]);
+
+    alice.send_group_file(GROUP, &file).await.unwrap();
+    for member in [&mut bob, &mut carol].iter_mut() {
+        let frame = recv(member).await;
+        assert_eq!(MessageType::GroupFileMessage, frame.msg_type);
+        assert_eq!(&file[..], &frame.content[..]);
+    }
+
+    // files are not stored, an offline recipient is an error
+    alice.send_user_file(4, &file).await.unwrap();
+    let frame = recv(&mut alice).await;
+    assert_eq!("user 4 is offline", text(&frame));
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn compression_test() {
+    let server = TestServer::start().await;
+    let mut alice = server.login(1).await;
+    let mut bob = server.login(2).await;
+    assert!(alice.capabilities().contains(Capabilities::COMPRESSION));
+    let mut carol = server.connect().await;
+    carol.set_capabilities(Capabilities::ACKS);
+    carol.login(3, PASSWORD).await.unwrap();
+    let file = b"a compressible file ".repeat(1000);
+
+    // compressed on the way, whatever the recipient negotiated
+    alice.send_group_file(GROUP, &file).await.unwrap();
+    for member in [&mut bob, &mut carol].iter_mut() {
+        let frame = recv(member).await;
+        assert!(!frame.compressed);
+        assert_eq!(&file[..], &frame.content[..]);
+    }
+
+    // a compressed frame without negotiation closes the connection
+    let mut frame = ClushFrame::new(MessageType::UserMessage, 3, 1, 0, BytesMut::new());
+    frame.append(&file).update_size();
+    Compression::default().compress(&mut frame).unwrap();
+    assert!(frame.compressed);
+    carol.send_frame(&frame).await.unwrap();
+    assert_eq!(
+        "compression was not negotiated",
+        text(&recv(&mut carol).await)
+    );
+    assert!(recv_closed(&mut carol).await);
+    assert_silent(&mut alice).await;
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn frame_limits_test() {
+    let server = TestServer::start().await;
+    let mut bob = server.login(2).await;
+
+    // a header announcing a huge frame is refused before its content
+    let mut alice = server.login(1).await;
+    let mut frame = ClushFrame::new(MessageType::UserMessage, 1, 2, 0, BytesMut::new());
+    frame.size = u64::MAX;
+    alice.send_frame(&frame).await.unwrap();
+    assert_eq!(
+        format!(
+            "frame of {} bytes exceeds the limit of 65536 bytes",
+            u64::MAX
+        ),
+        text(&recv(&mut alice).await)
+    );
+    assert!(recv_closed(&mut alice).await);
+
+    // so is a message inflating past the limit
+    let mut alice = server.login(1).await;
+    let mut frame = ClushFrame::new(MessageType::UserMessage, 1, 2, 0, BytesMut::new());
+    frame.append(&vec![b'a'; 1024 * 1024]).update_size();
+    Compression::default().compress(&mut frame).unwrap();
+    alice.send_frame(&frame).await.unwrap();
+    assert!(text(&recv(&mut alice).await).starts_with("frame of 1048576 bytes"));
+    assert!(recv_closed(&mut alice).await);
+    assert_silent(&mut bob).await;
+
+    // files may be larger than messages
+    let mut alice = server.login(1).await;
+    alice
+        .send_user_file(2, &vec![1u8; 1024 * 1024])
+        .await
+        .unwrap();
+    assert_eq!(1024 * 1024, recv(&mut bob).await.content.len());
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn slow_consumer_test() {
+    let mut config = ClushConfig::default();
+    config.outbound_config.queue_size = 4;
+    let server = TestServer::start_with(config).await;
+    let mut alice = login_uncompressed(&server, 1).await;
+    let mut carol = server.login(3).await;
+    // bob does not read, his socket buffers fill up first, then his queue
+    let mut bob = login_uncompressed(&server, 2).await;
+    let message = "x".repeat(60 * 1024);
+    let count = 200;
+
+    for _ in 0..count {
+        alice.send_user_msg(2, &message).await.unwrap();
+    }
+    for _ in 0..count {
+        assert_eq!(MessageType::AckMessage, recv(&mut alice).await.msg_type);
+    }
+    // others are still served
+    alice.send_user_msg(3, "hi carol").await.unwrap();
+    assert_eq!("hi carol", text(&recv(&mut carol).await));
+
+    // what did not fit is delivered on the next login
+    let mut received = 0;
+    while let Ok(Ok(Some(_))) = time::timeout(Duration::from_millis(500), bob.recv()).await {
+        received += 1;
+    }
+    assert!(received < count);
+    drop(bob);
+    time::sleep(Duration::from_millis(100)).await;
+    let mut bob = server.login(2).await;
+    for _ in received..count {
+        assert_eq!(message.len(), recv(&mut bob).await.content.len());
+    }
+    assert_silent(&mut bob).await;
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn disconnect_slow_consumer_test() {
+    let mut config = ClushConfig::default();
+    config.outbound_config.queue_size = 4;
+    config.outbound_config.overflow_policy = OverflowPolicy::Disconnect;
+    let server = TestServer::start_with(config).await;
+    let mut alice = login_uncompressed(&server, 1).await;
+    let mut bob = login_uncompressed(&server, 2).await;
+    let message = "x".repeat(60 * 1024);
+
+    for _ in 0..200 {
+        alice.send_user_msg(2, &message).await.unwrap();
+    }
+    for _ in 0..200 {
+        assert_eq!(MessageType::AckMessage, recv(&mut alice).await.msg_type);
+    }
+    assert!(recv_closed(&mut bob).await);
+
+    server.stop().await;
+}
+
+/// connect a client which does not negotiate compression, so large frames fill its buffers
+async fn login_uncompressed(server: &TestServer, uid: u64) -> ClushClient {
+    let mut client = server.connect().await;
+    client.set_capabilities(Capabilities::ACKS);
+    client.login(uid, PASSWORD).await.unwrap();
+
+    client
+}
+
+/// wait until the server closes the connection, skipping frames
+async fn recv_closed(client: &mut ClushClient) -> bool {
+    time::timeout(Duration::from_secs(5), async {
+        while let Ok(Some(_)) = client.recv().await {}
+    })
+    .await
+    .is_ok()
+}
+
+#[tokio::test]
+async fn invalid_frame_test() {
+    let server = TestServer::start().await;
+    let mut alice = server.login(1).await;
+    let mut bob = server.login(2).await;
+
+    // content which is not UTF-8 is refused
+    let mut frame = ClushFrame::new(MessageType::UserMessage, 1, 2, 0, BytesMut::new());
+    frame.append(&[0xff, 0xfe]).update_size();
+    alice.send_frame(&frame).await.unwrap();
+    assert_eq!("invalid UTF-8", text(&recv(&mut alice).await));
+    assert_silent(&mut bob).await;
+
+    // an unknown message type closes the connection
+    let mut raw = TcpStream::connect(server.addr).await.unwrap();
+    let mut login = ClushFrame::new(MessageType::LoginMessage, 3, 0, 0, BytesMut::new());
+    login.append(PASSWORD).update_size();
+    codec::write_frame(&mut raw, &login).await.unwrap();
+    let reply = codec::read_frame(&mut raw).await.unwrap().unwrap();
+    assert_eq!("success", text(&reply));
+    let mut bytes = frame.to_bytes().to_vec();
+    bytes[3] = 0x7f;
+    raw.write_all(&bytes).await.unwrap();
+    let mut rest = vec![];
+    let closed = time::timeout(Duration::from_secs(5), raw.read_to_end(&mut rest)).await;
+    assert!(closed.is_ok());
+
+    // others are not affected
+    bob.send_user_msg(1, "still there").await.unwrap();
+    assert_eq!(MessageType::AckMessage, recv(&mut bob).await.msg_type);
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn legacy_client_test() {
+    let server = TestServer::start().await;
+    let mut bob = server.login(2).await;
+
+    // a client logging in without a hello gets no acks
+    let mut raw = TcpStream::connect(server.addr).await.unwrap();
+    let mut login = ClushFrame::new(MessageType::LoginMessage, 1, 0, 0, BytesMut::new());
+    login.append(PASSWORD).update_size();
+    codec::write_frame(&mut raw, &login).await.unwrap();
+    let reply = codec::read_frame(&mut raw).await.unwrap().unwrap();
+    assert_eq!("success", text(&reply));
+
+    let mut frame = ClushFrame::new(MessageType::UserMessage, 1, 2, 0, BytesMut::new());
+    frame.append(b"hi bob").update_size();
+    codec::write_frame(&mut raw, &frame).await.unwrap();
+    assert_eq!("hi bob", text(&recv(&mut bob).await));
+    let reply = time::timeout(Duration::from_millis(200), codec::read_frame(&mut raw)).await;
+    assert!(reply.is_err());
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn presence_test() {
+    let server = TestServer::start().await;
+    let mut alice = login_with_presence(&server, 1).await;
+
+    // group peers tell each other, a new peer learns who is online
+    let mut bob = login_with_presence(&server, 2).await;
+    assert_eq!((2, Status::Online), recv_presence(&mut alice).await);
+    assert_eq!((1, Status::Online), recv_presence(&mut bob).await);
+    bob.set_status(Status::Away).await.unwrap();
+    assert_eq!((2, Status::Away), recv_presence(&mut alice).await);
+
+    // clients which did not negotiate presence are told nothing, but their presence is
+    let mut carol = server.login(3).await;
+    assert_eq!((3, Status::Online), recv_presence(&mut alice).await);
+    assert_eq!((3, Status::Online), recv_presence(&mut bob).await);
+    bob.set_status(Status::Online).await.unwrap();
+    assert_eq!((2, Status::Online), recv_presence(&mut alice).await);
+    assert_silent(&mut carol).await;
+
+    // users who share no group cannot be watched, nor watch
+    let mut dave = login_with_presence(&server, 4).await;
+    assert_silent(&mut alice).await;
+    alice.subscribe(4).await.unwrap();
+    assert_eq!("user 4 shares no group", text(&recv(&mut alice).await));
+    dave.set_status(Status::DoNotDisturb).await.unwrap();
+    assert_silent(&mut alice).await;
+    dave.subscribe(1).await.unwrap();
+    assert_eq!("user 1 shares no group", text(&recv(&mut dave).await));
+    drop(dave);
+    assert_silent(&mut alice).await;
+
+    // subscribing answers the presence of a peer
+    alice.subscribe(2).await.unwrap();
+    assert_eq!((2, Status::Online), recv_presence(&mut alice).await);
+
+    // a user logging out is offline since then
+    drop(carol);
+    let frame = recv(&mut alice).await;
+    assert_eq!(MessageType::PresenceMessage, frame.msg_type);
+    assert_eq!((3, 1), (frame.from_id, frame.to_id));
+    let offline = Presence::from_content(text(&frame)).unwrap();
+    assert_eq!(Status::Offline, offline.status);
+    assert!(offline.last_seen.is_some());
+    assert_eq!((3, Status::Offline), recv_presence(&mut bob).await);
+    alice.subscribe(3).await.unwrap();
+    let frame = recv(&mut alice).await;
+    assert_eq!(Ok(offline), Presence::from_content(text(&frame)));
+
+    // offline is no status to set, and only known users are watched
+    alice.unsubscribe(3).await.unwrap();
+    alice.set_status(Status::Offline).await.unwrap();
+    assert_eq!("invalid status `offline`", text(&recv(&mut alice).await));
+    alice.subscribe(9).await.unwrap();
+    assert_eq!("unknown user 9", text(&recv(&mut alice).await));
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn presence_connections_test() {
+    let server = TestServer::start().await;
+    let mut alice = login_with_presence(&server, 1).await;
+    let older = login_with_presence(&server, 2).await;
+    assert_eq!((2, Status::Online), recv_presence(&mut alice).await);
+
+    // a second connection keeps the user online, and is told who is
+    let mut newer = login_with_presence(&server, 2).await;
+    assert_eq!((1, Status::Online), recv_presence(&mut newer).await);
+    assert_silent(&mut alice).await;
+
+    // the user stays online, and reachable, when the older connection closes
+    drop(older);
+    assert_silent(&mut alice).await;
+    assert!(server.db.fetch_session(2).await.unwrap().is_some());
+    alice.send_user_msg(2, "still there?").await.unwrap();
+    assert_eq!(MessageType::AckMessage, recv(&mut alice).await.msg_type);
+    assert_eq!("still there?", text(&recv(&mut newer).await));
+
+    // and the other way round, the older connection gets what the newer one would
+    let mut older = login_with_presence(&server, 2).await;
+    assert_eq!((1, Status::Online), recv_presence(&mut older).await);
+    drop(newer);
+    assert_silent(&mut alice).await;
+    alice.send_user_msg(2, "and now?").await.unwrap();
+    assert_eq!(MessageType::AckMessage, recv(&mut alice).await.msg_type);
+    assert_eq!("and now?", text(&recv(&mut older).await));
+
+    // offline with the last connection
+    drop(older);
+    let frame = recv(&mut alice).await;
+    assert_eq!(MessageType::PresenceMessage, frame.msg_type);
+    assert_eq!(2, frame.from_id);
+    let offline = Presence::from_content(text(&frame)).unwrap();
+    assert_eq!(Status::Offline, offline.status);
+
+    server.stop().await;
+}
+
+/// connect a client which negotiates presence besides the default capabilities
+async fn login_with_presence(server: &TestServer, uid: u64) -> ClushClient {
+    let mut client = server.connect().await;
+    client.set_capabilities(Capabilities::ACKS | Capabilities::PRESENCE);
+    client.login(uid, PASSWORD).await.unwrap();
+    assert!(client.capabilities().contains(Capabilities::PRESENCE));
+
+    client
+}
+
+/// receive a presence frame, return who it is about and their status
+async fn recv_presence(client: &mut ClushClient) -> (u64, Status) {
+    let frame = recv(client).await;
+    assert_eq!(MessageType::PresenceMessage, frame.msg_type, "{:?}", frame);
+    assert_eq!(client.uid(), frame.to_id);
+    let presence = Presence::from_content(text(&frame)).unwrap();
+
+    (frame.from_id, presence.status)
+}
+
+#[tokio::test]
+async fn metrics_test() {
+    let mut config = ClushConfig::default();
+    config.metrics_config.enable = true;
+    config.metrics_config.url = "127.0.0.1:0".to_string();
+    // an ack means the message is saved, not only logged, so it counts in the backlog
+    config.wal_config.enable = false;
+    let server = TestServer::start_with(config).await;
+
+    let mut alice = server.login(1).await;
+    let mut mallory = server.connect().await;
+    assert!(mallory.login(1, &[0x00]).await.is_err());
+    // carol is offline, so the message waits in the backlog once acked
+    alice.send_user_msg(3, "hi carol").await.unwrap();
+    assert_eq!(MessageType::AckMessage, recv(&mut alice).await.msg_type);
+
+    let (status, body) = server.http_get("/metrics").await;
+    assert_eq!(200, status);
+    for line in &[
+        "clush_connections_accepted_total 2",
+        "clush_sessions_active 1",
+        "clush_logins_total{outcome=\"success\"} 1",
+        "clush_logins_total{outcome=\"invalid_password\"} 1",
+        "clush_frames_received_total{msg_type=\"user\"} 1",
+        "clush_frames_sent_total{msg_type=\"ack\"} 1",
+        "clush_offline_backlog 1",
+        "clush_storage_duration_seconds_count{operation=\"fetch_user\"} 2",
+        "clush_outbound_queue_depth_max",
+    ] {
+        assert!(body.contains(line), "{} in\n{}", line, body);
+    }
+
+    let (status, _) = server.http_get("/missing").await;
+    assert_eq!(404, status);
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn probes_test() {
+    let mut config = ClushConfig::default();
+    config.metrics_config.enable = true;
+    config.metrics_config.url = "127.0.0.1:0".to_string();
+    let server = TestServer::start_with(config).await;
+
+    assert_eq!((200, "ok".to_string()), server.http_get("/healthz").await);
+    assert_eq!((200, "ready".to_string()), server.http_get("/readyz").await);
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn admin_auth_test() {
+    let server = TestServer::start_with(admin_config()).await;
+    let addr = server.admin_addr.unwrap();
+
+    for token in &[None, Some("wrong")] {
+        let (status, _) = http_request(addr, "GET", "/users", *token, "").await;
+        assert_eq!(401, status);
+    }
+    let (status, body) = server.admin("GET", "/users", "").await;
+    assert_eq!(200, status);
+    let users: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(4, users.as_array().unwrap().len());
+    assert_eq!(
+        serde_json::json!({"id": 1, "username": "alice", "disabled": false, "online": false}),
+        users[0]
+    );
+
+    assert_eq!(404, server.admin("GET", "/missing", "").await.0);
+    assert_eq!(400, server.admin("GET", "/users/alice", "").await.0);
+    assert_eq!(400, server.admin("POST", "/users", "{").await.0);
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn admin_users_test() {
+    let server = TestServer::start_with(admin_config()).await;
+
+    // create a user, the id is generated unless given
+    let (status, body) = server
+        .admin(
+            "POST",
+            "/users",
+            r#"{"username": "erin", "password": "1c8a"}"#,
+        )
+        .await;
+    assert_eq!(201, status);
+    let erin: serde_json::Value = serde_json::from_str(&body).unwrap();
+    let erin_id = erin["id"].as_u64().unwrap();
+    server.login(erin_id).await;
+    let (status, _) = server
+        .admin(
+            "POST",
+            "/users",
+            r#"{"id": 1, "username": "x", "password": "1c8a"}"#,
+        )
+        .await;
+    assert_eq!(409, status);
+    let (status, _) = server
+        .admin("POST", "/users", r#"{"username": "x", "password": "xyz"}"#)
+        .await;
+    assert_eq!(400, status);
+
+    // a disabled user is disconnected and refused
+    let mut bob = server.login(2).await;
+    assert_eq!(204, server.admin("POST", "/users/2/disable", "").await.0);
+    assert_eq!("user disabled", text(&recv(&mut bob).await));
+    assert_closed(&mut bob).await;
+    let e = server.connect().await.login(2, PASSWORD).await.unwrap_err();
+    assert_eq!("user disabled", e.to_string());
+    assert_eq!(204, server.admin("POST", "/users/2/enable", "").await.0);
+    server.login(2).await;
+
+    // a new password applies to the next login
+    let (status, _) = server
+        .admin("PUT", "/users/3/password", r#"{"password": "beef"}"#)
+        .await;
+    assert_eq!(204, status);
+    assert!(server.connect().await.login(3, PASSWORD).await.is_err());
+    server
+        .connect()
+        .await
+        .login(3, &[0xbe, 0xef])
+        .await
+        .unwrap();
+
+    assert_eq!(204, server.admin("DELETE", "/users/4", "").await.0);
+    assert_eq!(404, server.admin("DELETE", "/users/4", "").await.0);
+    assert_eq!(404, server.admin("GET", "/users/4", "").await.0);
+    assert_eq!(404, server.admin("POST", "/users/4/disable", "").await.0);
+    assert!(server.connect().await.login(4, PASSWORD).await.is_err());
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn admin_disable_connections_test() {
+    let server = TestServer::start_with(admin_config()).await;
+    let mut older = server.login(2).await;
+    let mut newer = server.login(2).await;
+
+    // every connection of a disabled user is told and closed
+    assert_eq!(204, server.admin("POST", "/users/2/disable", "").await.0);
+    assert_eq!("user disabled", text(&recv(&mut newer).await));
+    assert_closed(&mut newer).await;
+    assert_eq!("user disabled", text(&recv(&mut older).await));
+    assert_closed(&mut older).await;
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn admin_groups_test() {
+    let server = TestServer::start_with(admin_config()).await;
+
+    let (status, body) = server
+        .admin("POST", "/groups", r#"{"groupName": "lounge"}"#)
+        .await;
+    assert_eq!(201, status);
+    let lounge: serde_json::Value = serde_json::from_str(&body).unwrap();
+    let lounge_id = lounge["id"].as_u64().unwrap();
+    assert_eq!("lounge", lounge["groupName"]);
+
+    let members = format!("/groups/{}/members", lounge_id);
+    for user_id in &[1, 4] {
+        let body = format!(r#"{{"userId": {}}}"#, user_id);
+        assert_eq!(201, server.admin("POST", &members, &body).await.0);
+    }
+    assert_eq!(
+        409,
+        server.admin("POST", &members, r#"{"userId": 4}"#).await.0
+    );
+    assert_eq!(
+        404,
+        server.admin("POST", &members, r#"{"userId": 9}"#).await.0
+    );
+    let (status, body) = server.admin("GET", &members, "").await;
+    assert_eq!(200, status);
+    assert_eq!(
+        serde_json::json!([{"userId": 1, "roleId": null}, {"userId": 4, "roleId": null}]),
+        serde_json::from_str::<serde_json::Value>(&body).unwrap()
+    );
+
+    // members are allowed to send to the group
+    let mut dave = server.login(4).await;
+    let mut alice = server.login(1).await;
+    dave.send_group_msg(lounge_id, "hi lounge").await.unwrap();
+    assert_eq!("hi lounge", text(&recv(&mut alice).await));
+
+    let member = format!("/groups/{}/members/4", lounge_id);
+    assert_eq!(204, server.admin("DELETE", &member, "").await.0);
+    assert_eq!(404, server.admin("DELETE", &member, "").await.0);
+    let group = format!("/groups/{}", lounge_id);
+    assert_eq!(204, server.admin("DELETE", &group, "").await.0);
+    assert_eq!(404, server.admin("GET", &group, "").await.0);
+    assert_eq!(404, server.admin("GET", &members, "").await.0);
+    let (_, body) = server.admin("GET", "/groups", "").await;
+    assert_eq!(
+        1,
+        serde_json::from_str::<Vec<serde_json::Value>>(&body)
+            .unwrap()
+            .len()
+    );
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn admin_sessions_test() {
+    let server = TestServer::start_with(admin_config()).await;
+    let mut alice = server.login(1).await;
+    let mut bob = server.login(2).await;
+    let mut dave = server.login(4).await;
+
+    let (status, body) = server.admin("GET", "/sessions", "").await;
+    assert_eq!(200, status);
+    let sessions: Vec<serde_json::Value> = serde_json::from_str(&body).unwrap();
+    let user_ids: Vec<_> = sessions
+        .iter()
+        .map(|session| session["userId"].as_u64().unwrap())
+        .collect();
+    assert_eq!(vec![1, 2, 4], user_ids);
+
+    // a broadcast reaches every online user, or the online members of a group
+    let (status, body) = server
+        .admin(
+            "POST",
+            "/broadcasts",
+            r#"{"content": "maintenance at noon"}"#,
+        )
+        .await;
+    assert_eq!((200, r#"{"recipients":3}"#), (status, body.as_str()));
+    for client in &mut [&mut alice, &mut bob, &mut dave] {
+        let frame = recv(client).await;
+        assert_eq!((0, "maintenance at noon"), (frame.from_id, text(&frame)));
+    }
+    let (status, body) = server
+        .admin(
+            "POST",
+            "/broadcasts",
+            r#"{"content": "hi group", "groupId": 1}"#,
+        )
+        .await;
+    assert_eq!((200, r#"{"recipients":2}"#), (status, body.as_str()));
+    assert_eq!("hi group", text(&recv(&mut alice).await));
+    assert_eq!("hi group", text(&recv(&mut bob).await));
+    assert_silent(&mut dave).await;
+
+    // a disconnected user is told why
+    assert_eq!(204, server.admin("DELETE", "/sessions/2", "").await.0);
+    assert_eq!(
+        "disconnected by an administrator",
+        text(&recv(&mut bob).await)
+    );
+    assert_closed(&mut bob).await;
+    assert_eq!(404, server.admin("DELETE", "/sessions/3", "").await.0);
+    // the session is removed once the connection is done
+    let mut sessions = 3;
+    for _ in 0..50 {
+        let (_, body) = server.admin("GET", "/sessions", "").await;
+        sessions = serde_json::from_str::<Vec<serde_json::Value>>(&body)
+            .unwrap()
+            .len();
+        if sessions == 2 {
+            break;
+        }
+        time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(2, sessions);
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn audit_test() {
+    let server = TestServer::start_with(admin_config()).await;
+    let admin_addr = server.admin_addr.unwrap();
+
+    let mut client = server.connect().await;
+    assert!(client.login(1, b"wrong").await.is_err());
+    let mut alice = server.login(1).await;
+    let mut bob = server.login(2).await;
+
+    // a frame sent in the name of carol
+    let mut frame = ClushFrame::new(MessageType::UserMessage, 3, 2, 0, BytesMut::new());
+    frame.append(b"from carol").update_size();
+    alice.send_frame(&frame).await.unwrap();
+    assert_eq!(1, recv(&mut bob).await.from_id);
+
+    let (status, _) = http_request(admin_addr, "GET", "/users", Some("wrong"), "").await;
+    assert_eq!(401, status);
+    let password = r#"{"password": "8a1c"}"#;
+    assert_eq!(
+        204,
+        server.admin("PUT", "/users/2/password", password).await.0
+    );
+    let member = r#"{"userId": 4, "roleId": 1}"#;
+    assert_eq!(
+        201,
+        server.admin("POST", "/groups/1/members", member).await.0
+    );
+    assert_eq!(204, server.admin("DELETE", "/sessions/2", "").await.0);
+    recv(&mut bob).await;
+    assert_closed(&mut bob).await;
+
+    let events = |query: &'static str| {
+        let server = &server;
+        async move {
+            let (status, body) = server.admin("GET", &format!("/audit?{}", query), "").await;
+            assert_eq!(200, status, "{}", body);
+            serde_json::from_str::<Vec<serde_json::Value>>(&body).unwrap()
+        }
+    };
+
+    // what alice did, with where she came from
+    let by_alice = events("actorId=1").await;
+    let kinds: Vec<_> = by_alice.iter().map(|event| &event["kind"]).collect();
+    assert_eq!(vec!["loginFailed", "login", "spoofing"], kinds);
+    assert_eq!("invalid password", by_alice[0]["detail"]);
+    let address = by_alice[1]["address"].as_str().unwrap();
+    assert!(address.starts_with("127.0.0.1:"), "{}", address);
+    assert_eq!(3, by_alice[2]["targetId"]);
+
+    let refused = events("kind=adminUnauthorized").await;
+    assert_eq!(1, refused.len());
+    assert!(refused[0]["actorId"].is_null());
+    assert_eq!("GET /users 401", refused[0]["detail"]);
+    let actions: Vec<_> = events("kind=adminAction")
+        .await
+        .iter()
+        .map(|event| event["detail"].as_str().unwrap().to_string())
+        .collect();
+    assert_eq!(
+        vec![
+            "PUT /users/2/password 204",
+            "POST /groups/1/members 201",
+            "DELETE /sessions/2 204"
+        ],
+        actions
+    );
+    let changed = events("kind=passwordChanged").await;
+    assert_eq!(0, changed[0]["actorId"]);
+    assert_eq!(2, changed[0]["targetId"]);
+    let added = events("kind=memberAdded").await;
+    assert_eq!("user 4 with role 1", added[0]["detail"]);
+
+    // the connection notices the termination on its own
+    let mut terminated = vec![];
+    for _ in 0..50 {
+        terminated = events("kind=sessionTerminated").await;
+        if !terminated.is_empty() {
+            break;
+        }
+        time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(2, terminated[0]["targetId"]);
+    assert_eq!("disconnected by an administrator", terminated[0]["detail"]);
+
+    let from = chrono::Utc::now() + chrono::Duration::hours(1);
+    let query = format!("/audit?from={}", from.format("%Y-%m-%dT%H:%M:%SZ"));
+    let (_, body) = server.admin("GET", &query, "").await;
+    assert_eq!("[]", body);
+    assert_eq!(400, server.admin("GET", "/audit?kind=logout", "").await.0);
+
+    server.stop().await;
+}
+
+/// run `clush-admin` on the given database, return whether it succeeded and its output
+async fn clush_admin(db_url: &str, args: &[&str], stdin: &str) -> (bool, String) {
+    let mut child = tokio::process::Command::new(env!("CARGO_BIN_EXE_clush-admin"))
+        .arg("--set")
+        .arg(format!("rbatisConfig.dbUrl={}", db_url))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut input = child.stdin.take().unwrap();
+    input.write_all(stdin.as_bytes()).await.unwrap();
+    drop(input);
+    let output = child.wait_with_output().await.unwrap();
+
+    (
+        output.status.success(),
+        String::from_utf8_lossy(&output.stdout).trim().to_string(),
+    )
+}
+
+#[tokio::test]
+async fn admin_cli_test() {
+    let path = std::env::temp_dir().join(format!(
+        "clush-admin-test-{}.db",
+        chrono::Utc::now().timestamp_nanos()
+    ));
+    let db_url = format!("sqlite://{}", path.display());
+
+    let (ok, output) = clush_admin(&db_url, &["migrate"], "").await;
+    assert!(ok);
+    assert!(output.starts_with("applied migration 1"), "{}", output);
+
+    // the first user can be created, with the password on stdin
+    let (ok, alice_id) = clush_admin(&db_url, &["create-user", "alice"], "wonderland\n").await;
+    assert!(ok);
+    let args = ["--id", "7", "-p", "builder", "create-user", "bob"];
+    assert_eq!(
+        (true, "7".to_string()),
+        clush_admin(&db_url, &args, "").await
+    );
+    assert!(!clush_admin(&db_url, &args, "").await.0);
+    let args = ["-p", "", "create-user", "carol"];
+    assert!(!clush_admin(&db_url, &args, "").await.0);
+
+    let (ok, group) = clush_admin(&db_url, &["create-group", "clush"], "").await;
+    assert!(ok);
+    let (ok, role) = clush_admin(&db_url, &["create-role", "owner"], "").await;
+    assert!(ok);
+    for user in &[alice_id.as_str(), "7"] {
+        let args = ["add-member", &group, user, "--role", &role];
+        assert!(clush_admin(&db_url, &args, "").await.0);
+    }
+    assert!(
+        !clush_admin(&db_url, &["add-member", &group, "7"], "")
+            .await
+            .0
+    );
+    assert!(
+        !clush_admin(&db_url, &["add-member", &group, "8"], "")
+            .await
+            .0
+    );
+
+    let args = ["-p", "rebuilt", "reset-password", "7"];
+    assert!(clush_admin(&db_url, &args, "").await.0);
+    let args = ["-p", "x", "reset-password", "8"];
+    assert!(!clush_admin(&db_url, &args, "").await.0);
+    let args = ["--kind", "passwordChanged", "audit"];
+    let (ok, output) = clush_admin(&db_url, &args, "").await;
+    assert!(ok);
+    let fields: Vec<&str> = output.split('\t').collect();
+    assert_eq!(["passwordChanged", "0", "7"], fields[1..4]);
+
+    // users log in with the hash of their password
+    let mut config = ClushConfig::default();
+    config.rbatis_config.db_url = db_url.clone();
+    let wal_path = wal_path();
+    config.wal_config.path = wal_path.clone();
+    let server = ClushServer::builder()
+        .config(config)
+        .listener(TcpListener::bind("127.0.0.1:0").await.unwrap())
+        .build()
+        .await
+        .unwrap();
+    let addr = server.local_addr().unwrap();
+    let handle = server.shutdown_handle();
+    let task = tokio::spawn(async move { server.start().await });
+
+    let hash = |password| hex_string_to_bytes(&hash_password(password));
+    let mut client = ClushClient::connect(addr).await.unwrap();
+    assert!(client.login(7, &hash("builder")).await.is_err());
+    let mut bob = ClushClient::connect(addr).await.unwrap();
+    bob.login(7, &hash("rebuilt")).await.unwrap();
+    let mut alice = ClushClient::connect(addr).await.unwrap();
+    alice
+        .login(alice_id.parse().unwrap(), &hash("wonderland"))
+        .await
+        .unwrap();
+    alice
+        .send_group_msg(group.parse().unwrap(), "hi")
+        .await
+        .unwrap();
+    assert_eq!("hi", text(&recv(&mut bob).await));
+
+    handle.shutdown();
+    task.await.unwrap().unwrap();
+
+    // the logins of bob are audited in the same database
+    let (ok, output) = clush_admin(&db_url, &["--actor", "7", "audit"], "").await;
+    assert!(ok);
+    let kinds: Vec<&str> = output
+        .lines()
+        .map(|line| line.split('\t').nth(1).unwrap())
+        .collect();
+    assert_eq!(vec!["loginFailed", "login"], kinds);
+
+    // nothing is old enough to be purged yet
+    let (ok, output) = clush_admin(&db_url, &["purge-messages", "1"], "").await;
+    assert!(ok);
+    assert!(output.starts_with("removed 0 messages"), "{}", output);
+
+    std::fs::remove_file(path).unwrap();
+    std::fs::remove_dir_all(wal_path).unwrap();
+}